@@ -1,5 +1,6 @@
-pub mod health;
-pub mod queues;
 pub mod channels;
-pub mod events;
 pub mod connections;
+pub mod events;
+pub mod health;
+pub mod queues;
+pub mod v2;
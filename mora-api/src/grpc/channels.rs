@@ -1,21 +1,53 @@
-use crate::{ChannelManagerState, QueuePoolState};
+use crate::{ChannelManagerState, EventNotifierState, QueuePoolState, StorageBackend};
+use base64::{prelude::BASE64_STANDARD, Engine};
 use log::{debug, info};
-use mora_core::{clock::Clock, result::MoraError};
+use mora_core::{
+    clock::Clock, models::events::EventKey, result::MoraError, traits::storage::StorageFuture,
+};
 use mora_proto::channels::{
-    channel_service_server::ChannelService, BufferOptions, Channel, CreateChannelRequest,
-    CreateChannelResponse, DeleteChannelRequest, DeleteChannelResponse, Event,
-    GetChannelEventsRequest, GetChannelEventsResponse, GetChannelRequest, GetChannelResponse,
-    ListChannelsRequest, ListChannelsResponse,
+    channel_service_server::ChannelService, AckEventsRequest, AckEventsResponse, BufferOptions,
+    Channel, CreateChannelRequest, CreateChannelResponse, DeleteChannelRequest,
+    DeleteChannelResponse, Event, GetChannelEventsRequest, GetChannelEventsResponse,
+    GetChannelRequest, GetChannelResponse, LeasedEvent, ListChannelsRequest, ListChannelsResponse,
+    NackEventsRequest, NackEventsResponse, SubscribeChannelRequest, SubscribeChannelResponse,
+};
+use mora_queue::{
+    channel_manager::{Channel as QueueChannel, ChannelCursor},
+    event::ScheduledEvent,
+    pool::QueuePool,
+};
+use std::time::Duration;
+use tokio::{
+    sync::mpsc,
+    time::{sleep_until, Instant},
 };
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
-pub struct ChannelServiceImpl {
+use super::events::{durable, parse_u128};
+
+const NANOS_PER_MSEC: u128 = 1_000_000;
+/// Batches a subscription can buffer before waiting for the client to catch up.
+const SUBSCRIPTION_BUFFER: usize = 16;
+
+pub struct ChannelServiceImpl<S: StorageBackend> {
     pub channel_manager: ChannelManagerState,
-    pub queue_pool: QueuePoolState,
+    pub queue_pool: QueuePoolState<S>,
+    pub event_notifier: EventNotifierState,
+}
+
+impl<S: StorageBackend> Clone for ChannelServiceImpl<S> {
+    fn clone(&self) -> Self {
+        Self {
+            channel_manager: self.channel_manager.clone(),
+            queue_pool: self.queue_pool.clone(),
+            event_notifier: self.event_notifier.clone(),
+        }
+    }
 }
 
 #[tonic::async_trait]
-impl ChannelService for ChannelServiceImpl {
+impl<S: StorageBackend> ChannelService for ChannelServiceImpl<S> {
     async fn list_channels(
         &self,
         _request: Request<ListChannelsRequest>,
@@ -37,6 +69,9 @@ impl ChannelService for ChannelServiceImpl {
                     time: channel.buffer_time() as u64,
                 }),
                 msec_from_last_op: channel.msec_from_last_op() as u64,
+                lease_ms: channel
+                    .lease_time()
+                    .map(|lease_time| (lease_time / NANOS_PER_MSEC) as u64),
             })
             .collect();
 
@@ -68,6 +103,9 @@ impl ChannelService for ChannelServiceImpl {
                     time: channel.buffer_time() as u64,
                 }),
                 msec_from_last_op: channel.msec_from_last_op() as u64,
+                lease_ms: channel
+                    .lease_time()
+                    .map(|lease_time| (lease_time / NANOS_PER_MSEC) as u64),
             })),
         }
     }
@@ -78,20 +116,24 @@ impl ChannelService for ChannelServiceImpl {
     ) -> Result<Response<CreateChannelResponse>, Status> {
         debug!("gRPC Received create_channel request");
         let req = request.into_inner();
-        let pool = self.queue_pool.lock().await;
-        let buffer_options = req.buffer_options.ok_or(Status::invalid_argument(
-            "buffer_options is required",
-        ))?;
+        let buffer_options = req
+            .buffer_options
+            .ok_or(Status::invalid_argument("buffer_options is required"))?;
+        let lease_time = match req.lease_ms {
+            Some(0) => return Err(Status::invalid_argument("lease_ms must be positive")),
+            lease_ms => lease_ms.map(|lease_ms| lease_ms as u128 * NANOS_PER_MSEC),
+        };
 
-        let channel = self
-            .channel_manager
-            .lock()
-            .await
+        // Locked in the order every fetch takes them: the channel manager, then the pool.
+        let mut channel_manager = self.channel_manager.lock().await;
+        let pool = self.queue_pool.lock().await;
+        let channel = channel_manager
             .create_channel(
                 &pool,
                 req.queues,
                 buffer_options.size as usize,
                 buffer_options.time as u128,
+                lease_time,
             )
             .map_err(|e| match e {
                 MoraError::QueueNotFound(queue) => {
@@ -125,51 +167,592 @@ impl ChannelService for ChannelServiceImpl {
     ) -> Result<Response<GetChannelEventsResponse>, Status> {
         info!("gRPC Received get_channel_events request");
         let req = request.into_inner();
-        let channel_id = req.channel_id;
-        let delete = req.delete;
+        let fetched = self
+            .get_events(&req.channel_id, req.delete, req.cursor, req.max_wait_ms)
+            .await?;
 
-        let mut channel_manager = self.channel_manager.lock().await;
-        let mut queue_pool = self.queue_pool.lock().await;
-        let channel_opt = channel_manager
-            .get_mut_channel(&channel_id)
-            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(GetChannelEventsResponse {
+            has_more: fetched.has_more,
+            cursor: fetched.cursor_bytes()?,
+            events: fetched.events.iter().map(to_event).collect(),
+        }))
+    }
 
-        match channel_opt {
-            Some(channel) => {
-                channel.reset_msec_from_last_op();
-                let timestamp = Clock::now();
-                let delta = channel.buffer_time();
-                let mut events: Vec<Event> = vec![];
-                let queues = channel.queues();
-                info!("Found {:?}", &queues);
-
-                for queue_name in queues {
-                    let data = queue_pool
-                        .dequeue_until(queue_name, timestamp + delta, delete)
-                        .map_err(|e| Status::internal(e.to_string()))?;
-                    info!("Data Found {:?}", &data);
-
-                    let dequeued_events = data
-                        .iter()
-                        .map(|data| {
-                            Ok(Event {
-                                timestamp: data.0.to_le_bytes().to_vec(),
-                                queue_name: queue_name.to_owned(),
-                                data: std::str::from_utf8(&data.1)
-                                    .map_err(|e| Status::internal(e.to_string()))?
-                                    .to_owned(),
-                            })
-                        })
-                        .collect::<Result<Vec<_>, Status>>()?;
-                    events.extend(dequeued_events)
+    type SubscribeChannelStream = ReceiverStream<Result<SubscribeChannelResponse, Status>>;
+
+    async fn subscribe_channel(
+        &self,
+        request: Request<SubscribeChannelRequest>,
+    ) -> Result<Response<Self::SubscribeChannelStream>, Status> {
+        let channel_id = request.into_inner().channel_id;
+        info!("gRPC Received subscribe_channel request: {}", &channel_id);
+
+        let stream = self
+            .subscribe(channel_id, |events| SubscribeChannelResponse {
+                events: events.iter().map(to_event).collect(),
+            })
+            .await?;
+        Ok(Response::new(stream))
+    }
+
+    async fn ack_events(
+        &self,
+        request: Request<AckEventsRequest>,
+    ) -> Result<Response<AckEventsResponse>, Status> {
+        let req = request.into_inner();
+        debug!("gRPC Received ack_events request: {}", &req.channel_id);
+
+        let expired_events = self
+            .release_leases(&req.channel_id, req.events, true)
+            .await?;
+        // Acked recurring events may have their next occurrence already due.
+        self.event_notifier.notify_waiters();
+        Ok(Response::new(AckEventsResponse { expired_events }))
+    }
+
+    async fn nack_events(
+        &self,
+        request: Request<NackEventsRequest>,
+    ) -> Result<Response<NackEventsResponse>, Status> {
+        let req = request.into_inner();
+        debug!("gRPC Received nack_events request: {}", &req.channel_id);
+
+        let expired_events = self
+            .release_leases(&req.channel_id, req.events, false)
+            .await?;
+        self.event_notifier.notify_waiters();
+        Ok(Response::new(NackEventsResponse { expired_events }))
+    }
+}
+
+impl<S: StorageBackend> ChannelServiceImpl<S> {
+    /// Fetches the events of a channel, long-polling for up to `max_wait_ms` when
+    /// none is due. Shared by all the versions of the API.
+    pub(crate) async fn get_events(
+        &self,
+        channel_id: &String,
+        delete: bool,
+        cursor: Option<Vec<u8>>,
+        max_wait_ms: Option<u64>,
+    ) -> Result<FetchedEvents, Status> {
+        let cursor = cursor
+            .map(|cursor| ChannelCursor::from_bytes(&cursor))
+            .transpose()
+            .map_err(|_| Status::invalid_argument("Invalid cursor"))?;
+        let max_wait = Duration::from_millis(max_wait_ms.unwrap_or(0));
+
+        // Waiting channels must not time out.
+        let _subscription = if max_wait.is_zero() {
+            None
+        } else {
+            Some(ChannelSubscription::new(&self.channel_manager, channel_id).await?)
+        };
+        wait_for_events(
+            &self.channel_manager,
+            &self.queue_pool,
+            &self.event_notifier,
+            channel_id,
+            delete,
+            cursor,
+            Some(Instant::now() + max_wait),
+        )
+        .await
+    }
+
+    /// Streams the events of a channel as they become due, one `to_response` batch
+    /// at a time. Shared by all the versions of the API.
+    pub(crate) async fn subscribe<R: Send + 'static>(
+        &self,
+        channel_id: String,
+        to_response: fn(Vec<DeliveredEvent>) -> R,
+    ) -> Result<ReceiverStream<Result<R, Status>>, Status> {
+        let subscription = ChannelSubscription::new(&self.channel_manager, &channel_id).await?;
+
+        let (tx, rx) = mpsc::channel(SUBSCRIPTION_BUFFER);
+        let channel_manager = self.channel_manager.clone();
+        let queue_pool = self.queue_pool.clone();
+        let event_notifier = self.event_notifier.clone();
+        tokio::spawn(async move {
+            loop {
+                let fetched = tokio::select! {
+                    _ = tx.closed() => break,
+                    fetched = wait_for_events(
+                        &channel_manager,
+                        &queue_pool,
+                        &event_notifier,
+                        &channel_id,
+                        true,
+                        None,
+                        None,
+                    ) => fetched,
+                };
+
+                match fetched {
+                    Ok(fetched) => {
+                        if tx.send(Ok(to_response(fetched.events))).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
                 }
+            }
+
+            debug!("subscription to channel {} closed", &channel_id);
+            drop(subscription);
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// Acks or nacks the events leased by a channel, returning the ones whose lease
+    /// was unknown or already expired once the changes are durable.
+    async fn release_leases(
+        &self,
+        channel_id: &String,
+        events: Vec<LeasedEvent>,
+        ack: bool,
+    ) -> Result<Vec<LeasedEvent>, Status> {
+        let event_ids = events
+            .iter()
+            .map(|event| parse_u128(&event.event_id, "event_id"))
+            .collect::<Result<Vec<_>, Status>>()?;
+
+        let (releases, ticket) = {
+            let mut channel_manager = self.channel_manager.lock().await;
+            let mut queue_pool = self.queue_pool.lock().await;
+            let channel = channel_manager
+                .get_mut_channel(channel_id)
+                .map_err(|e| Status::internal(e.to_string()))?
+                .ok_or(Status::not_found(format!(
+                    "{} channel does not exist",
+                    channel_id
+                )))?;
+            channel.reset_msec_from_last_op();
 
-                Ok(Response::new(GetChannelEventsResponse { events }))
+            let now = Clock::now();
+            let mut releases = Vec::with_capacity(events.len());
+            for (event, event_id) in events.into_iter().zip(event_ids) {
+                let release = match channel.take_lease(&event.queue_name, event_id, now) {
+                    None => None,
+                    Some(lease) => {
+                        let result = if ack {
+                            queue_pool.ack(&lease.queue, lease.key)
+                        } else {
+                            queue_pool.nack(&lease.queue, lease.key)
+                        };
+                        match result {
+                            Ok(release) => Some(release),
+                            // The queue was deleted along with the event.
+                            Err(MoraError::QueueNotFound(_)) => None,
+                            Err(e) => return Err(Status::internal(e.to_string())),
+                        }
+                    }
+                };
+                releases.push((event, release));
             }
-            None => Err(Status::not_found(format!(
+            (releases, queue_pool.durability_ticket())
+        };
+
+        let mut expired_events = vec![];
+        for (event, release) in releases {
+            let released = match release {
+                None => false,
+                Some(release) => release
+                    .written()
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?,
+            };
+            if !released {
+                expired_events.push(event);
+            }
+        }
+        durable(ticket).await?;
+
+        Ok(expired_events)
+    }
+}
+
+/// Keeps a channel from timing out while a consumer is waiting on it.
+struct ChannelSubscription {
+    channel_manager: ChannelManagerState,
+    channel_id: String,
+}
+
+impl ChannelSubscription {
+    async fn new(
+        channel_manager: &ChannelManagerState,
+        channel_id: &String,
+    ) -> Result<Self, Status> {
+        channel_manager
+            .lock()
+            .await
+            .get_mut_channel(channel_id)
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::not_found(format!(
                 "{} channel does not exist",
-                &channel_id
-            ))),
+                channel_id
+            )))?
+            .subscribe();
+
+        Ok(Self {
+            channel_manager: channel_manager.clone(),
+            channel_id: channel_id.to_owned(),
+        })
+    }
+}
+
+impl Drop for ChannelSubscription {
+    fn drop(&mut self) {
+        // Dropped when the consumer disconnects too, so the lock can't be awaited here.
+        let channel_manager = self.channel_manager.clone();
+        let channel_id = std::mem::take(&mut self.channel_id);
+        tokio::spawn(async move {
+            if let Ok(Some(channel)) = channel_manager.lock().await.get_mut_channel(&channel_id) {
+                channel.unsubscribe();
+                channel.reset_msec_from_last_op();
+            }
+        });
+    }
+}
+
+/// Fetches the events of a channel, waiting until `deadline` (forever if `None`) for
+/// some to be due when there are none.
+/// The wait ends when events are scheduled or the scheduler reports events as due.
+/// Channels with a buffer time fetch events before they are due, so they also wake
+/// up when the earliest event of their queues enters the buffer.
+async fn wait_for_events<S: StorageBackend>(
+    channel_manager: &ChannelManagerState,
+    queue_pool: &QueuePoolState<S>,
+    event_notifier: &EventNotifierState,
+    channel_id: &String,
+    delete: bool,
+    cursor: Option<ChannelCursor>,
+    deadline: Option<Instant>,
+) -> Result<FetchedEvents, Status> {
+    loop {
+        // Registered before fetching, so events scheduled right after aren't missed.
+        let notified = event_notifier.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let (fetched, next_due_in) = {
+            let mut channel_manager = channel_manager.lock().await;
+            let mut queue_pool = queue_pool.lock().await;
+            let channel = channel_manager
+                .get_mut_channel(channel_id)
+                .map_err(|e| Status::internal(e.to_string()))?
+                .ok_or(Status::not_found(format!(
+                    "{} channel does not exist",
+                    channel_id
+                )))?;
+
+            let fetched = fetch_events(channel, &mut queue_pool, delete, cursor.clone())?;
+            if !fetched.events.is_empty() || deadline.is_some_and(|d| d <= Instant::now()) {
+                (Some(fetched), None)
+            } else {
+                match channel.buffer_time() {
+                    0 => (None, None),
+                    _ => (
+                        None,
+                        next_due_in(channel, &queue_pool, delete, cursor.as_ref())?,
+                    ),
+                }
+            }
+        };
+        if let Some(fetched) = fetched {
+            return fetched.written().await;
+        }
+
+        let wake_at = match (next_due_in.map(|due_in| Instant::now() + due_in), deadline) {
+            (Some(due_at), Some(deadline)) => Some(due_at.min(deadline)),
+            (due_at, deadline) => due_at.or(deadline),
+        };
+        match wake_at {
+            Some(wake_at) => {
+                tokio::select! {
+                    _ = notified => {}
+                    _ = sleep_until(wake_at) => {}
+                }
+            }
+            None => notified.await,
+        }
+    }
+}
+
+/// Time left before the earliest event of the channel's queues can be fetched,
+/// `None` when the queues are empty.
+fn next_due_in<S: StorageBackend>(
+    channel: &QueueChannel,
+    queue_pool: &QueuePool<S>,
+    delete: bool,
+    cursor: Option<&ChannelCursor>,
+) -> Result<Option<Duration>, Status> {
+    let consume = delete || channel.lease_time().is_some();
+    let mut next_due: Option<u128> = None;
+    for queue_name in channel.queues() {
+        let after = cursor
+            .filter(|_| !consume)
+            .and_then(|cursor| cursor.position(queue_name));
+        let earliest = queue_pool
+            .peek_until(queue_name, u128::MAX, after, 1)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if let Some((key, _)) = earliest.first() {
+            next_due = Some(next_due.map_or(key.timestamp, |due| due.min(key.timestamp)));
+        }
+    }
+
+    let fetchable_from = Clock::now() + channel.buffer_time();
+    Ok(next_due.map(|due| {
+        Duration::from_nanos(u64::try_from(due.saturating_sub(fetchable_from)).unwrap_or(u64::MAX))
+    }))
+}
+
+/// An event fetched from one of the channel's queues, rendered by each API version.
+pub(crate) struct DeliveredEvent {
+    pub(crate) queue_name: String,
+    pub(crate) key: EventKey,
+    pub(crate) event: ScheduledEvent,
+}
+
+impl DeliveredEvent {
+    /// Leased events report the timestamp they were due at, not the lease expiration.
+    pub(crate) fn timestamp(&self) -> u128 {
+        self.event.scheduled_for.unwrap_or(self.key.timestamp)
+    }
+}
+
+pub(crate) struct FetchedEvents {
+    pub(crate) events: Vec<DeliveredEvent>,
+    pub(crate) has_more: bool,
+    cursor: Option<ChannelCursor>,
+    /// Writes of the events that were dequeued or leased.
+    writes: Vec<StorageFuture<()>>,
+}
+
+impl FetchedEvents {
+    /// Waits for the dequeued or leased events to be written, to be called once the
+    /// queue pool is released.
+    async fn written(mut self) -> Result<Self, Status> {
+        for write in std::mem::take(&mut self.writes) {
+            write.await.map_err(|e| Status::internal(e.to_string()))?;
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn cursor_bytes(&self) -> Result<Option<Vec<u8>>, Status> {
+        self.cursor
+            .as_ref()
+            .map(|cursor| cursor.to_bytes())
+            .transpose()
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+/// Fetches the events of the channel's queues due within its buffer time, at most
+/// `buffer_size` of them. The batch is split fairly among the queues and events are
+/// interleaved queue by queue.
+/// Channels with a lease time lease the events, the others dequeue them, deleting
+/// them when `delete` is set. Events that are not deleted are returned along with a
+/// cursor to resume from, when more are due.
+fn fetch_events<S: StorageBackend>(
+    channel: &mut QueueChannel,
+    queue_pool: &mut QueuePool<S>,
+    delete: bool,
+    cursor: Option<ChannelCursor>,
+) -> Result<FetchedEvents, Status> {
+    channel.reset_msec_from_last_op();
+    let now = Clock::now();
+    let timestamp = now + channel.buffer_time();
+    let budget = match channel.buffer_size() {
+        0 => usize::MAX,
+        buffer_size => buffer_size,
+    };
+    let consume = delete || channel.lease_time().is_some();
+    let cursor = if consume { None } else { cursor };
+    let queues = channel.queues().to_owned();
+    debug!("Found {:?}", &queues);
+
+    // Peeking one event more than the budget tells whether more are due.
+    let mut due = Vec::with_capacity(queues.len());
+    for queue_name in &queues {
+        let after = cursor
+            .as_ref()
+            .and_then(|cursor| cursor.position(queue_name));
+        due.push(
+            queue_pool
+                .peek_until(queue_name, timestamp, after, budget.saturating_add(1))
+                .map_err(|e| Status::internal(e.to_string()))?,
+        );
+    }
+
+    let mut taken = vec![0; queues.len()];
+    let mut total = 0;
+    'allocation: loop {
+        let mut progressed = false;
+        for (queue_taken, queue_due) in taken.iter_mut().zip(&due) {
+            if total == budget {
+                break 'allocation;
+            }
+            if *queue_taken < queue_due.len() {
+                *queue_taken += 1;
+                total += 1;
+                progressed = true;
+            }
+        }
+        if !progressed {
+            break;
+        }
+    }
+    let has_more = taken
+        .iter()
+        .zip(&due)
+        .any(|(queue_taken, queue_due)| *queue_taken < queue_due.len());
+
+    let mut next_cursor = cursor.unwrap_or_default();
+    let mut writes = vec![];
+    let mut batches = Vec::with_capacity(queues.len());
+    for ((queue_name, queue_due), limit) in queues.iter().zip(due).zip(taken) {
+        let data = match channel.lease_time() {
+            Some(lease_time) => {
+                channel.purge_expired_leases(now);
+                // Fetches look `buffer_time` ahead, so leases start at the end of the
+                // buffer window: the next fetches only reach them once they expired.
+                let (leased, write) = queue_pool
+                    .lease_until(queue_name, timestamp, timestamp + lease_time, limit)
+                    .map_err(|e| Status::internal(e.to_string()))?
+                    .into_parts();
+                writes.push(write);
+                for (key, _) in &leased {
+                    channel.add_lease(queue_name.to_owned(), *key);
+                }
+                leased
+            }
+            None if delete => {
+                let (dequeued, write) = queue_pool
+                    .dequeue_until(queue_name, timestamp, true, limit)
+                    .map_err(|e| Status::internal(e.to_string()))?
+                    .into_parts();
+                writes.push(write);
+                dequeued
+            }
+            None => queue_due.into_iter().take(limit).collect(),
+        };
+        debug!("Data Found {:?}", &data);
+
+        if let Some((key, _)) = data.last() {
+            next_cursor.advance(queue_name.to_owned(), *key);
+        }
+        batches.push(data.into_iter().map(|(key, event)| DeliveredEvent {
+            queue_name: queue_name.to_owned(),
+            key,
+            event,
+        }));
+    }
+
+    let mut events = Vec::with_capacity(total);
+    while events.len() < total {
+        let before = events.len();
+        events.extend(batches.iter_mut().filter_map(|batch| batch.next()));
+        if events.len() == before {
+            break;
+        }
+    }
+
+    Ok(FetchedEvents {
+        events,
+        has_more,
+        cursor: (has_more && !consume).then_some(next_cursor),
+        writes,
+    })
+}
+
+/// Renders an event for v1 consumers, whose payloads are strings. Payloads that
+/// aren't valid UTF-8 are base64-encoded and flagged with `data_base64`: the v2 API
+/// delivers them as raw bytes.
+fn to_event(delivered: &DeliveredEvent) -> Event {
+    let (data, data_base64) = match String::from_utf8(delivered.event.data.clone()) {
+        Ok(data) => (data, false),
+        Err(e) => (BASE64_STANDARD.encode(e.as_bytes()), true),
+    };
+
+    Event {
+        timestamp: delivered.timestamp().to_le_bytes().to_vec(),
+        queue_name: delivered.queue_name.clone(),
+        data,
+        remaining_occurrences: delivered
+            .event
+            .remaining_occurrences()
+            .map(|remaining| remaining.to_le_bytes().to_vec()),
+        event_id: delivered.key.id.to_le_bytes().to_vec(),
+        delivery_attempts: delivered.event.delivery_attempts,
+        dead_lettered_from: delivered.event.dead_lettered_from.clone(),
+        headers: delivered.event.headers.clone().into_iter().collect(),
+        data_base64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mora_core::models::queues::QueueOptions;
+    use mora_queue::{channel_manager::ChannelManager, pool::QueuePoolOptions};
+    use mora_storage::{memory_storage::MemoryStorage, storage_thread::StorageThread};
+
+    use super::*;
+
+    fn delivered(data: &[u8]) -> DeliveredEvent {
+        DeliveredEvent {
+            queue_name: "queue".to_string(),
+            key: EventKey::new(1, 1),
+            event: ScheduledEvent::new(data.to_vec(), None),
+        }
+    }
+
+    #[test]
+    fn text_payloads_are_delivered_as_is() {
+        let event = to_event(&delivered(b"text"));
+        assert_eq!(event.data, "text");
+        assert!(!event.data_base64);
+    }
+
+    #[test]
+    fn binary_payloads_are_base64_encoded() {
+        let event = to_event(&delivered(&[0xff, 0x00, 0xfe]));
+        assert_eq!(event.data, "/wD+");
+        assert!(event.data_base64);
+    }
+
+    #[tokio::test]
+    async fn leases_shorter_than_the_buffer_time_outlast_it(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let storage = StorageThread::spawn(|| Ok(MemoryStorage::new())).await?;
+        let mut pool = QueuePool::from_storage(storage, QueuePoolOptions::default()).await?;
+        let queue = "queue".to_string();
+        pool.create_queue(queue.clone(), QueueOptions::default())?
+            .written()
+            .await?;
+        pool.enqueue(
+            &queue,
+            Clock::now(),
+            ScheduledEvent::new(b"data".to_vec(), None),
+        )?
+        .written()
+        .await?;
+        let buffer_time = 3_600_000 * NANOS_PER_MSEC;
+        let mut channel = ChannelManager::default().create_channel(
+            &pool,
+            vec![queue],
+            0,
+            buffer_time,
+            Some(NANOS_PER_MSEC),
+        )?;
+
+        for expected in [1, 0] {
+            let fetched = fetch_events(&mut channel, &mut pool, false, None)?;
+            assert_eq!(fetched.written().await?.events.len(), expected);
         }
+        Ok(())
     }
 }
@@ -1,36 +1,150 @@
-use crate::QueuePoolState;
-use log::debug;
-use mora_core::result::MoraError;
+use crate::{EventNotifierState, QueuePoolState, StorageBackend};
+use log::{debug, error};
+use mora_core::{
+    models::events::{CronOptions, MissedFirePolicy, Recurrence, RecurringOptions},
+    result::MoraError,
+    traits::storage::DurabilityTicket,
+};
 use mora_proto::events::{
-    event_service_server::EventService, ScheduleEventRequest, ScheduleEventResponse,
+    event_service_server::EventService, schedule_rule, CancelEventRequest, CancelEventResponse,
+    CronOptions as ProtoCronOptions, MissedFirePolicy as ProtoMissedFirePolicy,
+    RecurringOptions as ProtoRecurringOptions, RescheduleEventRequest, RescheduleEventResponse,
+    ScheduleEventRequest, ScheduleEventResponse, ScheduleRule,
 };
+use mora_queue::{cron::CronSchedule, event::ScheduledEvent, pool::PendingWrite};
 use tonic::{Request, Response, Status};
 
-pub struct EventServiceImpl {
-    pub queue_pool: QueuePoolState,
+pub struct EventServiceImpl<S: StorageBackend> {
+    pub queue_pool: QueuePoolState<S>,
+    pub event_notifier: EventNotifierState,
+}
+
+impl<S: StorageBackend> Clone for EventServiceImpl<S> {
+    fn clone(&self) -> Self {
+        Self {
+            queue_pool: self.queue_pool.clone(),
+            event_notifier: self.event_notifier.clone(),
+        }
+    }
 }
 
 #[tonic::async_trait]
-impl EventService for EventServiceImpl {
+impl<S: StorageBackend> EventService for EventServiceImpl<S> {
     async fn schedule_event(
         &self,
         request: Request<ScheduleEventRequest>,
     ) -> Result<Response<ScheduleEventResponse>, Status> {
         debug!("gRPC Received schedule_event request");
         let req = request.into_inner();
-        let binary_data = req.data.into_bytes();
+        let event_ids = self
+            .schedule_events(
+                req.data.into_bytes(),
+                req.schedule_rules,
+                req.idempotency_key,
+            )
+            .await?;
 
-        for rule in req.schedule_rules {
-            let queue_name = rule.queue.clone();
-            let schedule_for_bytes: [u8; 16] = rule
-                .schedule_for
-                .as_slice()
-                .try_into()
-                .map_err(|_| Status::invalid_argument("Invalid schedule_for timestamp"))?;
-            let schedule_for = u128::from_le_bytes(schedule_for_bytes);
+        Ok(Response::new(ScheduleEventResponse {
+            event_ids: event_ids
+                .iter()
+                .map(|id| id.to_le_bytes().to_vec())
+                .collect(),
+        }))
+    }
+
+    async fn cancel_event(
+        &self,
+        request: Request<CancelEventRequest>,
+    ) -> Result<Response<CancelEventResponse>, Status> {
+        debug!("gRPC Received cancel_event request");
+        let req = request.into_inner();
+        let event_id = parse_u128(&req.event_id, "event_id")?;
 
+        let (cancelled, ticket) = {
             let mut queue_pool = self.queue_pool.lock().await;
-            if let Err(e) = queue_pool.get_queue_mut(&queue_name) {
+            let cancelled = queue_pool
+                .cancel(&req.queue, event_id)
+                .map_err(|e| event_error_to_status(e, &req.queue))?;
+            (cancelled, queue_pool.durability_ticket())
+        };
+        persisted(cancelled, ticket).await?;
+
+        Ok(Response::new(CancelEventResponse {}))
+    }
+
+    async fn reschedule_event(
+        &self,
+        request: Request<RescheduleEventRequest>,
+    ) -> Result<Response<RescheduleEventResponse>, Status> {
+        debug!("gRPC Received reschedule_event request");
+        let req = request.into_inner();
+        let event_id = parse_u128(&req.event_id, "event_id")?;
+        let schedule_for = parse_u128(&req.schedule_for, "schedule_for")?;
+
+        let (rescheduled, ticket) = {
+            let mut queue_pool = self.queue_pool.lock().await;
+            let rescheduled = queue_pool
+                .reschedule(&req.queue, event_id, schedule_for)
+                .map_err(|e| event_error_to_status(e, &req.queue))?;
+            (rescheduled, queue_pool.durability_ticket())
+        };
+        self.event_notifier.notify_waiters();
+        persisted(rescheduled, ticket).await?;
+
+        Ok(Response::new(RescheduleEventResponse {}))
+    }
+}
+
+impl<S: StorageBackend> EventServiceImpl<S> {
+    /// Schedules `data` according to every rule, returning the event ids in order
+    /// once they are durable. The rules are all validated before any event is scheduled,
+    /// and the events are stored along with the idempotency key, see
+    /// `QueuePool::enqueue_all`: when they can't be, the request is taken back. Shared
+    /// by all the versions of the API.
+    pub(crate) async fn schedule_events(
+        &self,
+        data: Vec<u8>,
+        schedule_rules: Vec<ScheduleRule>,
+        idempotency_key: Option<String>,
+    ) -> Result<Vec<u128>, Status> {
+        let mut events = Vec::with_capacity(schedule_rules.len());
+
+        let mut queue_pool = self.queue_pool.lock().await;
+        if let Some(idempotency_key) = &idempotency_key {
+            if let Some(original_event_ids) = queue_pool.get_idempotency_key(idempotency_key) {
+                debug!("idempotency key {} already used", idempotency_key);
+                // The original request may still be waiting for its events to be durable.
+                let ticket = queue_pool.durability_ticket();
+                drop(queue_pool);
+                durable(ticket).await?;
+                return Ok(original_event_ids);
+            }
+        }
+
+        for rule in schedule_rules {
+            let queue_name = rule.queue.clone();
+            let mut schedule_for = parse_u128(&rule.schedule_for, "schedule_for")?;
+            if rule.headers.keys().any(|name| name.is_empty()) {
+                return Err(Status::invalid_argument("header names can't be empty"));
+            }
+            let recurrence = match rule.recurrence {
+                None => None,
+                Some(schedule_rule::Recurrence::RecurringOptions(options)) => {
+                    Some(Recurrence::Fixed(parse_recurring_options(options)?))
+                }
+                Some(schedule_rule::Recurrence::CronOptions(options)) => {
+                    let options = parse_cron_options(options)?;
+                    schedule_for = CronSchedule::parse(&options)
+                        .map_err(|e| Status::invalid_argument(e.to_string()))?
+                        .first_from(schedule_for)
+                        .ok_or(Status::invalid_argument(
+                            "cron_options never fire after schedule_for",
+                        ))?;
+                    Some(Recurrence::Cron(options))
+                }
+            };
+
+            if let Err(e) = queue_pool.get_queue(&queue_name) {
                 if let MoraError::QueueNotFound(..) = e {
                     return Err(Status::not_found(format!(
                         "{} queue does not exist",
@@ -41,11 +155,187 @@ impl EventService for EventServiceImpl {
                 }
             }
 
-            queue_pool
-                .enqueue(&queue_name, schedule_for, binary_data.clone())
-                .map_err(|e| Status::internal(e.to_string()))?;
+            events.push((
+                queue_name,
+                schedule_for,
+                ScheduledEvent::new(data.clone(), recurrence)
+                    .with_headers(rule.headers.into_iter().collect()),
+            ));
         }
 
-        Ok(Response::new(ScheduleEventResponse {}))
+        let queues = events
+            .iter()
+            .map(|(queue_name, _, _)| queue_name.clone())
+            .collect::<Vec<_>>();
+        let scheduled = queue_pool
+            .enqueue_all(events, idempotency_key.clone())
+            .map_err(schedule_error_to_status)?;
+        let keys = scheduled.value().clone();
+        let ticket = queue_pool.durability_ticket();
+        drop(queue_pool);
+
+        if let Err(e) = scheduled.written().await {
+            // The request has no effect unless every event of it is stored.
+            let scheduled = queues.into_iter().zip(keys).collect::<Vec<_>>();
+            let undone = self
+                .queue_pool
+                .lock()
+                .await
+                .undo_enqueue_all(&scheduled, idempotency_key.as_deref());
+            let undone = match undone {
+                Ok(undone) => undone.written().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = undone {
+                error!("error taking back the events of a failed schedule request: {e}");
+            }
+            return Err(Status::internal(e.to_string()));
+        }
+        self.event_notifier.notify_waiters();
+        durable(ticket).await?;
+
+        Ok(keys.into_iter().map(|key| key.id).collect())
+    }
+}
+
+const NANOS_PER_MILLI: u128 = 1_000_000;
+
+pub(crate) fn parse_u128(bytes: &[u8], field: &str) -> Result<u128, Status> {
+    let bytes: [u8; 16] = bytes
+        .try_into()
+        .map_err(|_| Status::invalid_argument(format!("Invalid {field}")))?;
+    Ok(u128::from_le_bytes(bytes))
+}
+
+/// Waits for the changes covered by `ticket` to be durable, to be called once the
+/// queue pool is released so concurrent requests share the same sync.
+pub(crate) async fn durable(ticket: DurabilityTicket) -> Result<(), Status> {
+    ticket.await.map_err(|e| Status::internal(e.to_string()))
+}
+
+/// Waits for `change` to be written, then to be durable, see `durable`.
+pub(crate) async fn persisted<R>(
+    change: PendingWrite<R>,
+    ticket: DurabilityTicket,
+) -> Result<R, Status> {
+    let value = change
+        .written()
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+    durable(ticket).await?;
+    Ok(value)
+}
+
+fn event_error_to_status(e: MoraError, queue_name: &str) -> Status {
+    match e {
+        MoraError::QueueNotFound(..) => {
+            Status::not_found(format!("{} queue does not exist", queue_name))
+        }
+        MoraError::EventNotFound(..) => {
+            Status::not_found(format!("event does not exist in {} queue", queue_name))
+        }
+        MoraError::QueueFull => Status::resource_exhausted(format!("{} queue is full", queue_name)),
+        e => schedule_error_to_status(e),
+    }
+}
+
+/// Maps the error of a schedule request to the status reported to the client.
+fn schedule_error_to_status(e: MoraError) -> Status {
+    match e {
+        MoraError::QueueNotFound(queue) => {
+            Status::not_found(format!("{} queue does not exist", queue))
+        }
+        MoraError::QueueFull => Status::resource_exhausted(e.to_string()),
+        MoraError::InvalidDeadLetterPolicy(..) | MoraError::InvalidSchedule(..) => {
+            Status::invalid_argument(e.to_string())
+        }
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+/// The API takes `delay` in milliseconds, events keep it in nanoseconds like every
+/// other timestamp.
+fn parse_recurring_options(options: ProtoRecurringOptions) -> Result<RecurringOptions, Status> {
+    let delay_ms = parse_u128(&options.delay, "recurring_options.delay")?;
+    let recurring_options = RecurringOptions {
+        times: parse_u128(&options.times, "recurring_options.times")?,
+        delay: delay_ms
+            .checked_mul(NANOS_PER_MILLI)
+            .ok_or_else(|| Status::invalid_argument("recurring_options.delay is too large"))?,
+    };
+
+    if recurring_options.times == 0 {
+        return Err(Status::invalid_argument(
+            "recurring_options.times must be greater than zero",
+        ));
+    }
+    if recurring_options.delay == 0 && recurring_options.times > 1 {
+        return Err(Status::invalid_argument(
+            "recurring_options.delay must be greater than zero",
+        ));
+    }
+
+    Ok(recurring_options)
+}
+
+fn parse_cron_options(options: ProtoCronOptions) -> Result<CronOptions, Status> {
+    let missed_fire_policy = match ProtoMissedFirePolicy::try_from(options.missed_fire_policy)
+        .map_err(|_| Status::invalid_argument("Invalid cron_options.missed_fire_policy"))?
+    {
+        ProtoMissedFirePolicy::FireOnce => MissedFirePolicy::FireOnce,
+        ProtoMissedFirePolicy::Skip => MissedFirePolicy::Skip,
+        ProtoMissedFirePolicy::FireAll => MissedFirePolicy::FireAll,
+    };
+
+    Ok(CronOptions {
+        expression: options.expression,
+        timezone: options.timezone,
+        missed_fire_policy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(times: u128, delay_ms: u128) -> ProtoRecurringOptions {
+        ProtoRecurringOptions {
+            times: times.to_le_bytes().to_vec(),
+            delay: delay_ms.to_le_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn recurring_delay_is_converted_from_milliseconds() {
+        let parsed = parse_recurring_options(options(20, 3_600_000)).unwrap();
+        assert_eq!(parsed.times, 20);
+        assert_eq!(parsed.delay, 3_600_000_000_000);
+    }
+
+    #[test]
+    fn overflowing_recurring_delay_is_rejected() {
+        let status = parse_recurring_options(options(2, u128::MAX)).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn schedule_errors_map_to_their_status() {
+        for (error, code) in [
+            (MoraError::QueueFull, tonic::Code::ResourceExhausted),
+            (
+                MoraError::QueueNotFound("queue".to_string()),
+                tonic::Code::NotFound,
+            ),
+            (
+                MoraError::InvalidSchedule("never fires".to_string()),
+                tonic::Code::InvalidArgument,
+            ),
+            (
+                MoraError::SerializationError("oops".to_string()),
+                tonic::Code::Internal,
+            ),
+        ] {
+            assert_eq!(schedule_error_to_status(error).code(), code);
+        }
     }
 }
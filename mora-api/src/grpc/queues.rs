@@ -1,36 +1,49 @@
-use crate::QueuePoolState;
+use crate::{EventNotifierState, QueuePoolState, StorageBackend};
 use log::{debug, error};
+use mora_core::{
+    models::queues::{DeadLetterPolicy, QueueOptions},
+    result::MoraError,
+};
 use mora_proto::queues::{
     queue_service_server::QueueService, CreateQueueRequest, CreateQueueResponse,
-    DeleteQueueRequest, DeleteQueueResponse, GetQueueRequest, GetQueueResponse, ListQueuesRequest,
-    ListQueuesResponse, Queue,
+    DeadLetterPolicy as ProtoDeadLetterPolicy, DeleteQueueRequest, DeleteQueueResponse,
+    GetQueueRequest, GetQueueResponse, ListQueuesRequest, ListQueuesResponse, Queue,
+    RedriveQueueRequest, RedriveQueueResponse,
 };
 use tonic::{Request, Response, Status};
 
-pub struct QueueServiceImpl {
-    pub queue_pool: QueuePoolState,
+use super::events::{parse_u128, persisted};
+
+pub struct QueueServiceImpl<S: StorageBackend> {
+    pub queue_pool: QueuePoolState<S>,
+    pub event_notifier: EventNotifierState,
 }
 
 #[tonic::async_trait]
-impl QueueService for QueueServiceImpl {
+impl<S: StorageBackend> QueueService for QueueServiceImpl<S> {
     async fn list_queues(
         &self,
         _request: Request<ListQueuesRequest>,
     ) -> Result<Response<ListQueuesResponse>, Status> {
         debug!("gRPC Received list_queues request");
 
-        let queues: Vec<Queue> = self
-            .queue_pool
-            .lock()
-            .await
+        let queue_pool = self.queue_pool.lock().await;
+        let queues: Vec<Queue> = queue_pool
             .get_queues(regex::Regex::new(r".*").unwrap())
             .map_err(|e| Status::internal(e.to_string()))?
             .iter()
-            .map(|q| Queue {
-                id: q.0.to_owned(),
-                pending_events_count: q.1.len as u64,
+            .map(|q| {
+                Ok(Queue {
+                    id: q.0.to_owned(),
+                    pending_events_count: q.1.len() as u64,
+                    dead_letter_policy: queue_pool
+                        .get_queue_options(&q.0)
+                        .map_err(|e| Status::internal(e.to_string()))?
+                        .dead_letter_policy
+                        .map(to_proto_dead_letter_policy),
+                })
             })
-            .collect();
+            .collect::<Result<_, Status>>()?;
 
         Ok(Response::new(ListQueuesResponse { queues }))
     }
@@ -42,10 +55,8 @@ impl QueueService for QueueServiceImpl {
         debug!("gRPC Received get_queue request");
         let queue_id = request.into_inner().queue_id;
 
-        let queue: GetQueueResponse = self
-            .queue_pool
-            .lock()
-            .await
+        let queue_pool = self.queue_pool.lock().await;
+        let queue: GetQueueResponse = queue_pool
             .get_queues(regex::Regex::new(&queue_id).unwrap())
             .map_err(|e| {
                 error!("{e}");
@@ -55,6 +66,11 @@ impl QueueService for QueueServiceImpl {
             .map(|q| GetQueueResponse {
                 id: q.0.to_owned(),
                 pending_events_count: 0,
+                dead_letter_policy: queue_pool
+                    .get_queue_options(&q.0)
+                    .ok()
+                    .and_then(|options| options.dead_letter_policy)
+                    .map(to_proto_dead_letter_policy),
             })
             .collect::<Vec<GetQueueResponse>>()
             .first()
@@ -68,21 +84,42 @@ impl QueueService for QueueServiceImpl {
         &self,
         request: Request<CreateQueueRequest>,
     ) -> Result<Response<CreateQueueResponse>, Status> {
-        let id = request.into_inner().id;
+        let req = request.into_inner();
+        let id = req.id;
         debug!("gRPC Received create_queue request: {}", &id);
 
-        self.queue_pool
-            .lock()
-            .await
-            .create_queue(id.to_owned())
-            .map_err(|e| {
-                error!("{e}");
-                Status::internal(e.to_string())
-            })?;
+        let options = QueueOptions {
+            dead_letter_policy: req
+                .dead_letter_policy
+                .clone()
+                .map(|policy| DeadLetterPolicy {
+                    max_delivery_attempts: policy.max_delivery_attempts,
+                    dead_letter_queue: policy.dead_letter_queue,
+                }),
+        };
+
+        let (created, ticket) = {
+            let mut queue_pool = self.queue_pool.lock().await;
+            let created = queue_pool
+                .create_queue(id.to_owned(), options)
+                .map_err(|e| {
+                    error!("{e}");
+                    match e {
+                        MoraError::ReservedQueueName(..)
+                        | MoraError::InvalidDeadLetterPolicy(..) => {
+                            Status::invalid_argument(e.to_string())
+                        }
+                        _ => Status::internal(e.to_string()),
+                    }
+                })?;
+            (created, queue_pool.durability_ticket())
+        };
+        persisted(created, ticket).await?;
 
         Ok(Response::new(CreateQueueResponse {
             id: id.to_owned(),
             pending_events_count: 0,
+            dead_letter_policy: req.dead_letter_policy,
         }))
     }
 
@@ -93,19 +130,66 @@ impl QueueService for QueueServiceImpl {
         let queue_id = request.into_inner().queue_id;
         debug!("gRPC Received delete_queue request: {}", &queue_id);
 
-        let deleted_id = self
-            .queue_pool
-            .lock()
-            .await
-            .delete_queue(queue_id)
-            .map_err(|e| {
+        let (deleted, ticket) = {
+            let mut queue_pool = self.queue_pool.lock().await;
+            let deleted = queue_pool.delete_queue(queue_id).map_err(|e| {
                 let e_msg = format!("error deleting queue: {:?}", e);
                 error!("{e_msg}");
                 Status::internal(e_msg)
             })?;
+            (deleted, queue_pool.durability_ticket())
+        };
+        let deleted_id = persisted(deleted, ticket).await?;
 
         Ok(Response::new(DeleteQueueResponse {
             message: format!("{} deleted", deleted_id),
         }))
     }
+
+    async fn redrive_queue(
+        &self,
+        request: Request<RedriveQueueRequest>,
+    ) -> Result<Response<RedriveQueueResponse>, Status> {
+        let req = request.into_inner();
+        debug!("gRPC Received redrive_queue request: {}", &req.queue_id);
+
+        let event_ids = req
+            .event_ids
+            .iter()
+            .map(|event_id| parse_u128(event_id, "event_id"))
+            .collect::<Result<Vec<_>, Status>>()?;
+
+        let (redriven, ticket) = {
+            let mut queue_pool = self.queue_pool.lock().await;
+            let redriven = queue_pool
+                .redrive(&req.queue_id, &event_ids)
+                .map_err(|e| match e {
+                    MoraError::QueueNotFound(..) => {
+                        Status::not_found(format!("{} queue does not exist", req.queue_id))
+                    }
+                    MoraError::EventNotFound(..) => Status::not_found(format!(
+                        "dead-lettered event does not exist in {} queue",
+                        req.queue_id
+                    )),
+                    _ => Status::internal(e.to_string()),
+                })?;
+            (redriven, queue_pool.durability_ticket())
+        };
+        self.event_notifier.notify_waiters();
+        let redriven = persisted(redriven, ticket).await?;
+
+        Ok(Response::new(RedriveQueueResponse {
+            event_ids: redriven
+                .iter()
+                .map(|key| key.id.to_le_bytes().to_vec())
+                .collect(),
+        }))
+    }
+}
+
+fn to_proto_dead_letter_policy(policy: DeadLetterPolicy) -> ProtoDeadLetterPolicy {
+    ProtoDeadLetterPolicy {
+        max_delivery_attempts: policy.max_delivery_attempts,
+        dead_letter_queue: policy.dead_letter_queue,
+    }
 }
@@ -0,0 +1,68 @@
+use log::info;
+use mora_proto::v2::channels::{
+    channel_service_server::ChannelService, Event, GetChannelEventsRequest,
+    GetChannelEventsResponse, SubscribeChannelRequest, SubscribeChannelResponse,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    grpc::channels::{ChannelServiceImpl, DeliveredEvent},
+    StorageBackend,
+};
+
+#[tonic::async_trait]
+impl<S: StorageBackend> ChannelService for ChannelServiceImpl<S> {
+    async fn get_channel_events(
+        &self,
+        request: Request<GetChannelEventsRequest>,
+    ) -> Result<Response<GetChannelEventsResponse>, Status> {
+        info!("gRPC Received v2 get_channel_events request");
+        let req = request.into_inner();
+        let fetched = self
+            .get_events(&req.channel_id, req.delete, req.cursor, req.max_wait_ms)
+            .await?;
+
+        Ok(Response::new(GetChannelEventsResponse {
+            has_more: fetched.has_more,
+            cursor: fetched.cursor_bytes()?,
+            events: fetched.events.into_iter().map(to_event).collect(),
+        }))
+    }
+
+    type SubscribeChannelStream = ReceiverStream<Result<SubscribeChannelResponse, Status>>;
+
+    async fn subscribe_channel(
+        &self,
+        request: Request<SubscribeChannelRequest>,
+    ) -> Result<Response<Self::SubscribeChannelStream>, Status> {
+        let channel_id = request.into_inner().channel_id;
+        info!(
+            "gRPC Received v2 subscribe_channel request: {}",
+            &channel_id
+        );
+
+        let stream = self
+            .subscribe(channel_id, |events| SubscribeChannelResponse {
+                events: events.into_iter().map(to_event).collect(),
+            })
+            .await?;
+        Ok(Response::new(stream))
+    }
+}
+
+fn to_event(delivered: DeliveredEvent) -> Event {
+    Event {
+        timestamp: delivered.timestamp().to_le_bytes().to_vec(),
+        remaining_occurrences: delivered
+            .event
+            .remaining_occurrences()
+            .map(|remaining| remaining.to_le_bytes().to_vec()),
+        event_id: delivered.key.id.to_le_bytes().to_vec(),
+        delivery_attempts: delivered.event.delivery_attempts,
+        queue_name: delivered.queue_name,
+        data: delivered.event.data,
+        dead_lettered_from: delivered.event.dead_lettered_from,
+        headers: delivered.event.headers.into_iter().collect(),
+    }
+}
@@ -0,0 +1,58 @@
+use log::debug;
+use mora_proto::{
+    events::{
+        schedule_rule::Recurrence as V1Recurrence, CronOptions as V1CronOptions,
+        RecurringOptions as V1RecurringOptions, ScheduleRule as V1ScheduleRule,
+    },
+    v2::events::{
+        event_service_server::EventService, schedule_rule::Recurrence, ScheduleEventRequest,
+        ScheduleEventResponse, ScheduleRule,
+    },
+};
+use tonic::{Request, Response, Status};
+
+use crate::{grpc::events::EventServiceImpl, StorageBackend};
+
+#[tonic::async_trait]
+impl<S: StorageBackend> EventService for EventServiceImpl<S> {
+    async fn schedule_event(
+        &self,
+        request: Request<ScheduleEventRequest>,
+    ) -> Result<Response<ScheduleEventResponse>, Status> {
+        debug!("gRPC Received v2 schedule_event request");
+        let req = request.into_inner();
+        let schedule_rules = req.schedule_rules.into_iter().map(to_v1_rule).collect();
+        let event_ids = self
+            .schedule_events(req.data, schedule_rules, req.idempotency_key)
+            .await?;
+
+        Ok(Response::new(ScheduleEventResponse {
+            event_ids: event_ids
+                .iter()
+                .map(|id| id.to_le_bytes().to_vec())
+                .collect(),
+        }))
+    }
+}
+
+/// Schedule rules are the same in both versions, only the payload type changed.
+fn to_v1_rule(rule: ScheduleRule) -> V1ScheduleRule {
+    V1ScheduleRule {
+        schedule_for: rule.schedule_for,
+        queue: rule.queue,
+        recurrence: rule.recurrence.map(|recurrence| match recurrence {
+            Recurrence::RecurringOptions(options) => {
+                V1Recurrence::RecurringOptions(V1RecurringOptions {
+                    times: options.times,
+                    delay: options.delay,
+                })
+            }
+            Recurrence::CronOptions(options) => V1Recurrence::CronOptions(V1CronOptions {
+                expression: options.expression,
+                timezone: options.timezone,
+                missed_fire_policy: options.missed_fire_policy,
+            }),
+        }),
+        headers: rule.headers,
+    }
+}
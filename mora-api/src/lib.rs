@@ -1,19 +1,40 @@
 use log::info;
-use mora_core::result::{MoraError, MoraResult};
+use mora_core::{
+    models::events::EventKey,
+    result::{MoraError, MoraResult},
+    traits::storage::AsyncStorage,
+};
 use mora_queue::{channel_manager::ChannelManager, pool::QueuePool};
-use mora_storage::wal_file_storage::WalFileStorage;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 
 use crate::connections::Connections;
 
 pub(crate) mod connections;
 pub(crate) mod grpc;
 
-pub type QueuePoolState = Arc<Mutex<QueuePool<WalFileStorage>>>;
+/// Storage engines queues can be served from. Blocking engines are served through
+/// a `StorageThread`, so they never block request handlers.
+pub trait StorageBackend:
+    AsyncStorage<ContainerId = String, SortKey = EventKey, Item = Vec<u8>> + Send + Sync + 'static
+{
+}
+
+impl<T> StorageBackend for T where
+    T: AsyncStorage<ContainerId = String, SortKey = EventKey, Item = Vec<u8>>
+        + Send
+        + Sync
+        + 'static
+{
+}
+
+pub type QueuePoolState<S> = Arc<Mutex<QueuePool<S>>>;
 pub type ChannelManagerState = Arc<Mutex<ChannelManager>>;
 pub type ConnectionsState = Arc<Mutex<Connections>>;
+/// Notified whenever events are scheduled or become due, to wake up consumers
+/// waiting for them.
+pub type EventNotifierState = Arc<Notify>;
 
 pub struct MoraApi {
     port: u16,
@@ -24,10 +45,11 @@ impl MoraApi {
         MoraApi { port }
     }
 
-    pub async fn start_grpc_server(
+    pub async fn start_grpc_server<S: StorageBackend>(
         &self,
         channel_manager: Arc<Mutex<ChannelManager>>,
-        queue_pool: Arc<Mutex<QueuePool<WalFileStorage>>>,
+        queue_pool: QueuePoolState<S>,
+        event_notifier: EventNotifierState,
     ) -> MoraResult<()> {
         use mora_proto::{
             channels::channel_service_server::ChannelServiceServer,
@@ -35,6 +57,10 @@ impl MoraApi {
             events::event_service_server::EventServiceServer,
             health::health_service_server::HealthServiceServer,
             queues::queue_service_server::QueueServiceServer,
+            v2::{
+                channels::channel_service_server::ChannelServiceServer as ChannelServiceV2Server,
+                events::event_service_server::EventServiceServer as EventServiceV2Server,
+            },
         };
 
         let connections = Arc::new(Mutex::new(Connections::default()));
@@ -56,13 +82,16 @@ impl MoraApi {
         let health_service = grpc::health::HealthServiceImpl;
         let queue_service = grpc::queues::QueueServiceImpl {
             queue_pool: queue_pool.clone(),
+            event_notifier: event_notifier.clone(),
         };
         let channel_service = grpc::channels::ChannelServiceImpl {
             channel_manager: channel_manager.clone(),
             queue_pool: queue_pool.clone(),
+            event_notifier: event_notifier.clone(),
         };
         let event_service = grpc::events::EventServiceImpl {
             queue_pool: queue_pool.clone(),
+            event_notifier: event_notifier.clone(),
         };
         let connection_service = grpc::connections::ConnectionServiceImpl {
             connections: connections.clone(),
@@ -83,8 +112,10 @@ impl MoraApi {
         tonic::transport::Server::builder()
             .add_service(HealthServiceServer::new(health_service))
             .add_service(QueueServiceServer::new(queue_service))
-            .add_service(ChannelServiceServer::new(channel_service))
-            .add_service(EventServiceServer::new(event_service))
+            .add_service(ChannelServiceServer::new(channel_service.clone()))
+            .add_service(ChannelServiceV2Server::new(channel_service))
+            .add_service(EventServiceServer::new(event_service.clone()))
+            .add_service(EventServiceV2Server::new(event_service))
             .add_service(ConnectionServiceServer::new(connection_service))
             .add_service(reflection_service)
             .serve(addr)
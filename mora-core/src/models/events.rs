@@ -1,20 +1,118 @@
 use serde::{Deserialize, Serialize};
 
+/// Identifies a scheduled event: events are ordered by `timestamp` first and
+/// `id` breaks ties between events scheduled for the same instant.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub struct EventKey {
+    pub timestamp: u128,
+    pub id: u128,
+}
+
+impl EventKey {
+    pub const BYTES: usize = 32;
+
+    pub fn new(timestamp: u128, id: u128) -> Self {
+        Self { timestamp, id }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::BYTES] {
+        let mut bytes = [0_u8; Self::BYTES];
+        bytes[..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.id.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::BYTES]) -> Self {
+        let mut timestamp = [0_u8; 16];
+        let mut id = [0_u8; 16];
+        timestamp.copy_from_slice(&bytes[..16]);
+        id.copy_from_slice(&bytes[16..]);
+        Self {
+            timestamp: u128::from_le_bytes(timestamp),
+            id: u128::from_le_bytes(id),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct ScheduleEventRequest {
-    data: String,
-    schedule_rules: Vec<ScheduleRules>,
+pub struct ScheduleEventRequest {
+    pub data: String,
+    pub schedule_rules: Vec<ScheduleRules>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct ScheduleRules {
-    schedule_for: u128,
-    queue: String,
-    recurring_options: Option<RecurringOptions>,
+pub struct ScheduleRules {
+    pub schedule_for: u128,
+    pub queue: String,
+    pub recurrence: Option<Recurrence>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct RecurringOptions {
-    times: u128,
-    delay: u128,
+/// How an event repeats after its first occurrence.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Recurrence {
+    Fixed(RecurringOptions),
+    Cron(CronOptions),
+}
+
+/// Fixed-delay repetition.
+///
+/// `times` is the total number of occurrences still to be fired, including the
+/// current one. `u128::MAX` (the little-endian encoding of `-1`) repeats forever.
+/// `delay` is the distance between two occurrences, in nanoseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecurringOptions {
+    pub times: u128,
+    pub delay: u128,
+}
+
+impl RecurringOptions {
+    pub const INFINITE: u128 = u128::MAX;
+
+    pub fn is_infinite(&self) -> bool {
+        self.times == Self::INFINITE
+    }
+
+    /// Number of occurrences left once the current one has been dispatched.
+    pub fn remaining_occurrences(&self) -> u128 {
+        if self.is_infinite() {
+            Self::INFINITE
+        } else {
+            self.times.saturating_sub(1)
+        }
+    }
+
+    /// Options describing the occurrence that follows the current one, if any.
+    pub fn next(&self) -> Option<RecurringOptions> {
+        match self.remaining_occurrences() {
+            0 => None,
+            times => Some(RecurringOptions {
+                times,
+                delay: self.delay,
+            }),
+        }
+    }
+}
+
+/// Calendar schedule evaluated in the given IANA timezone, e.g. `0 9 * * MON-FRI`
+/// in `Europe/Rome`. Cron schedules repeat forever.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CronOptions {
+    pub expression: String,
+    pub timezone: String,
+    pub missed_fire_policy: MissedFirePolicy,
+}
+
+/// What to do with cron occurrences that were due while nothing was dispatching,
+/// e.g. while the server was down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MissedFirePolicy {
+    /// Fire a single catch-up occurrence for all the missed ones.
+    #[default]
+    FireOnce,
+    /// Drop missed occurrences and wait for the next one in the future.
+    Skip,
+    /// Fire the missed occurrences in order, only the most recent ones past a limit.
+    FireAll,
 }
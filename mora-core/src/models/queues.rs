@@ -17,3 +17,16 @@ pub struct Queue {
     pub id: String,
     pub pending_events_count: u128,
 }
+
+/// Moves events to `dead_letter_queue` once they have been delivered
+/// `max_delivery_attempts` times without being acknowledged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetterPolicy {
+    pub max_delivery_attempts: u32,
+    pub dead_letter_queue: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueOptions {
+    pub dead_letter_policy: Option<DeadLetterPolicy>,
+}
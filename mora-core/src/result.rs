@@ -16,6 +16,12 @@ pub enum MoraError {
     ApiError(String),
     #[error("queue not found: `{0}`")]
     QueueNotFound(String),
+    #[error("queue name is reserved: `{0}`")]
+    ReservedQueueName(String),
+    #[error("event not found: `{0}`")]
+    EventNotFound(String),
+    #[error("invalid dead letter policy: `{0}`")]
+    InvalidDeadLetterPolicy(String),
     #[error("generic error: `{0}`")]
     GenericError(String),
     #[error("connection error: `{0}`")]
@@ -24,6 +30,10 @@ pub enum MoraError {
     QueueFull,
     #[error("file error: `{0}`")]
     FileError(String),
+    #[error("serialization error: `{0}`")]
+    SerializationError(String),
+    #[error("invalid schedule: `{0}`")]
+    InvalidSchedule(String),
     #[error("storage error: `{0}`")]
     StorageError(StorageError),
 }
@@ -50,4 +60,22 @@ pub enum StorageError {
     ItemNotFound(String),
     #[error("item write failed: `{0}`")]
     ItemWriteFailed(String),
+    #[error("compaction failed: `{0}`")]
+    CompactionFailed(String),
+    #[error("not a wal file: `{0}`")]
+    InvalidWalFile(String),
+    #[error("unsupported wal version: `{0}`: `{1}`")]
+    UnsupportedWalVersion(String, u32),
+    #[error("corrupted record: `{0}` at offset `{1}`")]
+    CorruptedRecord(String, u64),
+    #[error("sync failed: `{0}`")]
+    SyncFailed(String),
+    #[error("snapshot failed: `{0}`")]
+    SnapshotFailed(String),
+    #[error("database open failed: `{0}`")]
+    DatabaseOpenFailed(String),
+    #[error("migration failed: `{0}`")]
+    MigrationFailed(String),
+    #[error("storage thread failed: `{0}`")]
+    StorageThreadFailed(String),
 }
@@ -1,7 +1,41 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future, pin::Pin};
 
 use crate::result::MoraResult;
 
+/// Outcome of a `Storage::snapshot` run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotStats {
+    /// Containers that were snapshotted.
+    pub containers: usize,
+    /// Live items written to the snapshots.
+    pub items: u64,
+}
+
+/// Resolves once the writes made before it was taken are durable, see
+/// `Storage::durability_ticket`.
+pub type DurabilityTicket = Pin<Box<dyn Future<Output = MoraResult<()>> + Send>>;
+
+/// Storage call that doesn't borrow the storage, so it can be awaited after releasing
+/// any lock on it.
+pub type StorageFuture<T> = Pin<Box<dyn Future<Output = MoraResult<T>> + Send>>;
+
+/// A change applied by `Storage::write`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageWrite<C, K, I> {
+    CreateContainer(C),
+    DeleteContainer(C),
+    StoreItems(C, Vec<(K, I)>),
+    DeleteItems(C, Vec<K>),
+}
+
+/// Outcome of a `Storage::compact` run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Containers that were compacted.
+    pub containers: usize,
+    pub reclaimed_bytes: u64,
+}
+
 pub trait Storage {
     type ContainerId;
     type SortKey;
@@ -40,6 +74,20 @@ pub trait Storage {
         item: &Self::Item,
     ) -> MoraResult<()>;
 
+    /// Stores multiple items by their sort keys, overwriting existing ones.
+    /// Engines that can store them atomically: either every item is stored or none
+    /// is. The default stores them one by one.
+    fn store_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        items: &[(Self::SortKey, Self::Item)],
+    ) -> MoraResult<()> {
+        for (item_sort_key, item) in items {
+            self.store_item(container_id, item_sort_key, item)?;
+        }
+        Ok(())
+    }
+
     /// Get all items in a container.
     fn get_all_items(
         &mut self,
@@ -52,4 +100,142 @@ pub trait Storage {
         container_id: &Self::ContainerId,
         item_sort_keys: &[Self::SortKey],
     ) -> MoraResult<()>;
+
+    /// Applies `writes` in order, stopping at the first one that fails: later writes
+    /// can rely on the earlier ones, e.g. an item is only deleted once it is stored
+    /// elsewhere. The default applies them one by one.
+    fn write(
+        &mut self,
+        writes: &[StorageWrite<Self::ContainerId, Self::SortKey, Self::Item>],
+    ) -> MoraResult<()> {
+        for write in writes {
+            match write {
+                StorageWrite::CreateContainer(container_id) => {
+                    self.create_container(container_id)?
+                }
+                StorageWrite::DeleteContainer(container_id) => {
+                    self.delete_container(container_id)?
+                }
+                StorageWrite::StoreItems(container_id, items) => {
+                    self.store_items(container_id, items)?
+                }
+                StorageWrite::DeleteItems(container_id, item_sort_keys) => {
+                    self.delete_items(container_id, item_sort_keys)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reclaims the space held by deleted or overwritten items, for the containers
+    /// that need it. Engines that don't need compacting do nothing.
+    fn compact(&mut self) -> MoraResult<CompactionStats> {
+        Ok(CompactionStats::default())
+    }
+
+    /// Snapshots the live items of the containers whose log grew enough since their
+    /// last snapshot, so that `get_all_items` only replays what was written after it.
+    /// Engines that don't replay a log do nothing.
+    fn snapshot(&mut self) -> MoraResult<SnapshotStats> {
+        Ok(SnapshotStats::default())
+    }
+
+    /// Returns a ticket resolving once every write made so far reached the durability
+    /// the engine is configured for. The ticket doesn't borrow the storage, so it can
+    /// be awaited after releasing any lock on it, letting concurrent writers share
+    /// the same sync. Engines durable on write return a ready ticket.
+    fn durability_ticket(&self) -> DurabilityTicket {
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+/// Asynchronous counterpart of `Storage`, for callers running on an async runtime
+/// that must not block it on storage I/O, such as the queue pool.
+///
+/// The returned futures are `Send`, so they can be awaited while serving requests,
+/// and don't borrow the storage. Calls are issued when they are made, in order, not
+/// when their future is first polled: a caller can make them under a lock and await
+/// them after releasing it.
+pub trait AsyncStorage {
+    type ContainerId;
+    type SortKey;
+    type Item;
+
+    /// Loads the storage engine, see `Storage::load`.
+    fn load() -> impl Future<Output = MoraResult<Self>> + Send
+    where
+        Self: Sized;
+
+    /// See `Storage::create_container`.
+    fn create_container(
+        &mut self,
+        container_id: &Self::ContainerId,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static;
+
+    /// See `Storage::delete_container`.
+    fn delete_container(
+        &mut self,
+        container_id: &Self::ContainerId,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static;
+
+    /// See `Storage::list_containers`.
+    fn list_containers(
+        &self,
+    ) -> impl Future<Output = MoraResult<Vec<Self::ContainerId>>> + Send + 'static;
+
+    /// See `Storage::delete_item`.
+    fn delete_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static;
+
+    /// See `Storage::store_item`.
+    fn store_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+        item: &Self::Item,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static;
+
+    /// See `Storage::store_items`.
+    fn store_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        items: &[(Self::SortKey, Self::Item)],
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static;
+
+    /// See `Storage::get_all_items`.
+    fn get_all_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+    ) -> impl Future<Output = MoraResult<HashMap<Self::SortKey, Self::Item>>> + Send + 'static;
+
+    /// See `Storage::delete_items`.
+    fn delete_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_keys: &[Self::SortKey],
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static;
+
+    /// See `Storage::write`.
+    fn write(
+        &mut self,
+        writes: Vec<StorageWrite<Self::ContainerId, Self::SortKey, Self::Item>>,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static;
+
+    /// See `Storage::compact`.
+    fn compact(&mut self) -> impl Future<Output = MoraResult<CompactionStats>> + Send + 'static {
+        std::future::ready(Ok(CompactionStats::default()))
+    }
+
+    /// See `Storage::snapshot`.
+    fn snapshot(&mut self) -> impl Future<Output = MoraResult<SnapshotStats>> + Send + 'static {
+        std::future::ready(Ok(SnapshotStats::default()))
+    }
+
+    /// See `Storage::durability_ticket`.
+    fn durability_ticket(&self) -> DurabilityTicket {
+        Box::pin(std::future::ready(Ok(())))
+    }
 }
@@ -14,6 +14,17 @@ pub mod events {
     tonic::include_proto!("mora.events.v1");
 }
 
+/// Version 2 of the payload-carrying APIs, where payloads are raw bytes.
+pub mod v2 {
+    pub mod channels {
+        tonic::include_proto!("mora.channels.v2");
+    }
+
+    pub mod events {
+        tonic::include_proto!("mora.events.v2");
+    }
+}
+
 pub mod connections {
     tonic::include_proto!("mora.connections.v1");
 }
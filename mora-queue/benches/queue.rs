@@ -0,0 +1,126 @@
+//! Queue benchmarks, run with `cargo bench -p mora-queue`.
+//!
+//! Benchmarks are run for 10k, 1M and 10M events; set `MORA_BENCH_EVENTS` to a
+//! comma separated list of sizes to override them, e.g. `MORA_BENCH_EVENTS=10000`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use mora_core::{clock::Clock, models::events::EventKey, result::MoraResult};
+use mora_queue::{
+    event::ScheduledEvent,
+    pool::{QueuePool, QueuePoolOptions},
+    priority_queue::{btree::BTreePriorityQueue, naive::NaivePriorityQueue, PriorityQueue},
+    temporal_queue::TemporalQueue,
+};
+use mora_storage::{storage_thread::StorageThread, wal_file_storage::WalFileStorage};
+
+const DEFAULT_SIZES: [usize; 3] = [10_000, 1_000_000, 10_000_000];
+/// The naive queue enqueues in linear time, bigger sizes would take forever.
+const NAIVE_MAX_SIZE: usize = 10_000;
+/// Events due at each `dequeue_until`, as a consumer would fetch them.
+const DUE_EVENTS: usize = 1_000;
+
+fn sizes() -> Vec<usize> {
+    match std::env::var("MORA_BENCH_EVENTS") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|size| size.trim().parse().expect("invalid MORA_BENCH_EVENTS"))
+            .collect(),
+        Err(_) => DEFAULT_SIZES.to_vec(),
+    }
+}
+
+/// Keys spread over `size` timestamps, in a scrambled order.
+fn keys(size: usize) -> Vec<EventKey> {
+    (0..size as u128)
+        .map(|n| EventKey::new(n.wrapping_mul(2_654_435_761) % size as u128, n))
+        .collect()
+}
+
+fn fill_and_drain<Q: PriorityQueue<EventKey, u128> + Default>(keys: &[EventKey]) -> usize {
+    let mut queue = Q::default();
+    for key in keys {
+        queue.enqueue(*key, key.id);
+    }
+    queue.dequeue(keys.len()).len()
+}
+
+fn priority_queues(c: &mut Criterion) {
+    let mut group = c.benchmark_group("priority_queue/fill_and_drain");
+    group.sample_size(10);
+    for size in sizes() {
+        let keys = keys(size);
+        group.throughput(Throughput::Elements(size as u64));
+        if size <= NAIVE_MAX_SIZE {
+            group.bench_with_input(BenchmarkId::new("naive", size), &keys, |b, keys| {
+                b.iter(|| fill_and_drain::<NaivePriorityQueue<EventKey, u128>>(black_box(keys)))
+            });
+        }
+        group.bench_with_input(BenchmarkId::new("btree", size), &keys, |b, keys| {
+            b.iter(|| fill_and_drain::<BTreePriorityQueue<EventKey, u128>>(black_box(keys)))
+        });
+    }
+    group.finish();
+}
+
+fn temporal_queue_dequeue_until(c: &mut Criterion) {
+    let mut group = c.benchmark_group("temporal_queue/dequeue_until");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(DUE_EVENTS as u64));
+    for size in sizes() {
+        let mut queue = TemporalQueue::default();
+        for key in keys(size) {
+            queue.enqueue(key, key.id).unwrap();
+        }
+        let due_at = DUE_EVENTS.min(size) as u128 - 1;
+
+        group.bench_with_input(BenchmarkId::new("peek", size), &queue, |b, queue| {
+            b.iter(|| queue.peek_until(black_box(due_at), None, usize::MAX))
+        });
+        group.bench_with_input(BenchmarkId::new("delete", size), &queue, |b, queue| {
+            b.iter_batched(
+                || queue.clone(),
+                |mut queue| queue.dequeue_until(black_box(due_at), true, usize::MAX),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn pool_enqueue(c: &mut Criterion) {
+    let wal_dir = tempfile::tempdir().unwrap();
+    std::env::set_var("MORA_WAL_PATH", wal_dir.path());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut pool: QueuePool<StorageThread<WalFileStorage>> = runtime
+        .block_on(QueuePool::new(QueuePoolOptions::default()))
+        .unwrap();
+    let queue = "bench".to_string();
+    runtime
+        .block_on(
+            pool.create_queue(queue.clone(), Default::default())
+                .unwrap()
+                .written(),
+        )
+        .unwrap();
+    let event = ScheduledEvent::new(b"{\"hello\":\"world\"}".to_vec(), None);
+
+    let mut group = c.benchmark_group("queue_pool/enqueue");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("wal_file_storage", |b| {
+        b.iter(|| -> MoraResult<EventKey> {
+            let enqueued = pool.enqueue(&queue, Clock::now(), black_box(event.clone()))?;
+            runtime.block_on(enqueued.written())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    priority_queues,
+    temporal_queue_dequeue_until,
+    pool_enqueue
+);
+criterion_main!(benches);
@@ -1,5 +1,13 @@
-use mora_core::{result::MoraError, traits::storage::Storage};
-use std::collections::HashMap;
+use mora_core::{
+    models::events::EventKey,
+    result::{MoraError, MoraResult},
+    traits::storage::AsyncStorage,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
 
 use crate::pool::{Bytes, EventId, QueueId, QueuePool};
 
@@ -14,7 +22,49 @@ pub struct Channel {
     queues: Vec<String>,
     buffer_size: usize,
     buffer_time: u128,
-    msec_from_last_op: usize,
+    lease_time: Option<u128>,
+    // Keyed by queue too: dead-lettered events and next occurrences keep their id.
+    leases: HashMap<(QueueId, EventId), Lease>,
+    last_op: Instant,
+    subscribers: usize,
+}
+
+/// An event delivered by an acknowledging channel and not yet acked or nacked.
+///
+/// Leases are only known to their channel, in memory, while the lease expiration is
+/// stored with the event, see `QueuePool::lease_until`. After a restart the leases
+/// can't be acked or nacked anymore and their events are delivered again once the
+/// lease expires: delivery is at least once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub queue: QueueId,
+    /// Key the event is stored under while leased, its timestamp is the lease expiration.
+    pub key: EventKey,
+}
+
+/// Position reached by a consumer peeking the events of a channel: the key of the
+/// last event returned for each queue. Handed to consumers as opaque bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelCursor {
+    positions: BTreeMap<QueueId, EventKey>,
+}
+
+impl ChannelCursor {
+    pub fn position(&self, queue: &str) -> Option<EventKey> {
+        self.positions.get(queue).copied()
+    }
+
+    pub fn advance(&mut self, queue: QueueId, key: EventKey) {
+        self.positions.insert(queue, key);
+    }
+
+    pub fn to_bytes(&self) -> MoraResult<Bytes> {
+        rmp_serde::to_vec(self).map_err(|e| MoraError::SerializationError(e.to_string()))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> MoraResult<Self> {
+        rmp_serde::from_slice(bytes).map_err(|e| MoraError::SerializationError(e.to_string()))
+    }
 }
 
 impl Channel {
@@ -33,26 +83,62 @@ impl Channel {
         self.buffer_time
     }
 
-    pub fn msec_from_last_op(&self) -> usize {
-        self.msec_from_last_op
+    /// How long delivered events stay leased, in nanoseconds. Channels without a
+    /// lease time deliver events without waiting for acknowledgements.
+    pub fn lease_time(&self) -> Option<u128> {
+        self.lease_time
+    }
+
+    pub fn add_lease(&mut self, queue: QueueId, key: EventKey) {
+        self.leases
+            .insert((queue.clone(), key.id), Lease { queue, key });
+    }
+
+    /// Releases the lease held on `event_id` of `queue`, unless it expired before `now`.
+    pub fn take_lease(&mut self, queue: &str, event_id: EventId, now: u128) -> Option<Lease> {
+        self.leases
+            .remove(&(queue.to_owned(), event_id))
+            .filter(|lease| lease.key.timestamp > now)
+    }
+
+    /// Forgets leases expired before `now`: their events are due again.
+    pub fn purge_expired_leases(&mut self, now: u128) {
+        self.leases.retain(|_, lease| lease.key.timestamp > now);
     }
 
-    pub fn update_msec_from_last_op(&mut self, msec: usize) {
-        self.msec_from_last_op += msec;
+    pub fn msec_from_last_op(&self) -> usize {
+        self.last_op.elapsed().as_millis() as usize
     }
 
     pub fn reset_msec_from_last_op(&mut self) {
-        self.msec_from_last_op = 0;
+        self.last_op = Instant::now();
+    }
+
+    /// Whether a streaming subscription is open on the channel. Subscribed channels
+    /// never time out.
+    pub fn is_subscribed(&self) -> bool {
+        self.subscribers > 0
+    }
+
+    pub fn subscribe(&mut self) {
+        self.subscribers += 1;
+    }
+
+    pub fn unsubscribe(&mut self) {
+        self.subscribers = self.subscribers.saturating_sub(1);
     }
 }
 
 impl ChannelManager {
-    pub fn create_channel<T: Storage<ContainerId = QueueId, SortKey = EventId, Item = Bytes>>(
+    pub fn create_channel<
+        T: AsyncStorage<ContainerId = QueueId, SortKey = EventKey, Item = Bytes>,
+    >(
         &mut self,
         queue_pool: &QueuePool<T>,
         queues: Vec<String>,
         buffer_size: usize,
         buffer_time: u128,
+        lease_time: Option<u128>,
     ) -> Result<Channel, MoraError> {
         let mut channel_id = uuid::Uuid::new_v4().to_string();
 
@@ -71,7 +157,10 @@ impl ChannelManager {
             queues,
             buffer_size,
             buffer_time,
-            msec_from_last_op: 0,
+            lease_time,
+            leases: HashMap::default(),
+            last_op: Instant::now(),
+            subscribers: 0,
         };
         self.channels.insert(channel_id, channel.clone());
         Ok(channel)
@@ -99,4 +188,116 @@ impl ChannelManager {
     pub fn close_channel(&mut self, channel_id: &String) {
         self.channels.remove(channel_id);
     }
+
+    /// Closes the channels without subscribers idle for more than `timeout_in_msec`,
+    /// returning how many were closed.
+    pub fn close_inactive_channels(&mut self, timeout_in_msec: usize) -> usize {
+        let before = self.channels.len();
+        self.channels.retain(|_, channel| {
+            channel.is_subscribed() || channel.msec_from_last_op() <= timeout_in_msec
+        });
+        before - self.channels.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel() -> Channel {
+        Channel {
+            id: "channel".to_string(),
+            queues: vec!["queue".to_string()],
+            buffer_size: 10,
+            buffer_time: 0,
+            lease_time: Some(100),
+            leases: HashMap::default(),
+            last_op: Instant::now(),
+            subscribers: 0,
+        }
+    }
+
+    #[test]
+    fn lease_can_be_taken_only_once_before_expiring() {
+        let mut channel = channel();
+        channel.add_lease("queue".to_string(), EventKey::new(100, 1));
+
+        assert_eq!(channel.take_lease("queue", 2, 50), None);
+        assert_eq!(
+            channel.take_lease("queue", 1, 50),
+            Some(Lease {
+                queue: "queue".to_string(),
+                key: EventKey::new(100, 1)
+            })
+        );
+        assert_eq!(channel.take_lease("queue", 1, 50), None);
+    }
+
+    #[test]
+    fn leases_of_the_same_event_id_in_other_queues_are_kept_apart() {
+        let mut channel = channel();
+        channel.add_lease("queue".to_string(), EventKey::new(100, 1));
+        channel.add_lease("dead_letters".to_string(), EventKey::new(200, 1));
+
+        assert_eq!(
+            channel.take_lease("dead_letters", 1, 50),
+            Some(Lease {
+                queue: "dead_letters".to_string(),
+                key: EventKey::new(200, 1)
+            })
+        );
+        assert_eq!(
+            channel.take_lease("queue", 1, 50),
+            Some(Lease {
+                queue: "queue".to_string(),
+                key: EventKey::new(100, 1)
+            })
+        );
+    }
+
+    #[test]
+    fn subscribed_channels_never_time_out() {
+        let mut manager = ChannelManager::default();
+        let mut idle = channel();
+        idle.last_op = Instant::now() - std::time::Duration::from_millis(50);
+        let mut subscribed = idle.clone();
+        subscribed.id = "subscribed".to_string();
+        subscribed.subscribe();
+        manager.channels.insert(idle.id.clone(), idle);
+        manager
+            .channels
+            .insert(subscribed.id.clone(), subscribed.clone());
+
+        assert_eq!(manager.close_inactive_channels(10), 1);
+        assert!(manager.get_channel(&subscribed.id).unwrap().is_some());
+
+        manager
+            .get_mut_channel(&subscribed.id)
+            .unwrap()
+            .unwrap()
+            .unsubscribe();
+        assert_eq!(manager.close_inactive_channels(10), 1);
+    }
+
+    #[test]
+    fn channel_cursor_roundtrips_through_bytes() -> MoraResult<()> {
+        let mut cursor = ChannelCursor::default();
+        cursor.advance("queue".to_string(), EventKey::new(1, 2));
+        let cursor = ChannelCursor::from_bytes(&cursor.to_bytes()?)?;
+        assert_eq!(cursor.position("queue"), Some(EventKey::new(1, 2)));
+        assert_eq!(cursor.position("other"), None);
+        assert!(ChannelCursor::from_bytes(b"garbage").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn expired_leases_are_not_returned() {
+        let mut channel = channel();
+        channel.add_lease("queue".to_string(), EventKey::new(100, 1));
+        channel.add_lease("queue".to_string(), EventKey::new(200, 2));
+
+        assert_eq!(channel.take_lease("queue", 1, 100), None);
+        channel.purge_expired_leases(200);
+        assert_eq!(channel.take_lease("queue", 2, 150), None);
+    }
 }
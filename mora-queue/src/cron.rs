@@ -0,0 +1,292 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use mora_core::{
+    models::events::{CronOptions, MissedFirePolicy},
+    result::{MoraError, MoraResult},
+};
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+/// Missed occurrences replayed by `MissedFirePolicy::FireAll` at most, the most
+/// recent ones. Older occurrences are dropped.
+const MAX_FIRE_ALL_MISSED: usize = 100;
+
+/// A parsed cron expression bound to its timezone.
+///
+/// Expressions use the usual five fields (an optional leading seconds field is
+/// accepted too) and are evaluated on the wall clock of `timezone`.
+/// Daylight saving transitions follow the OCPS rules implemented by `croner`:
+/// - a fixed-time occurrence falling into a spring-forward gap fires at the
+///   first valid instant after the gap;
+/// - a fixed-time occurrence falling into a fall-back overlap fires once, on its
+///   first occurrence;
+/// - interval/wildcard expressions fire on every matching real instant.
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    cron: Cron,
+    timezone: Tz,
+    missed_fire_policy: MissedFirePolicy,
+}
+
+impl CronSchedule {
+    pub fn parse(options: &CronOptions) -> MoraResult<Self> {
+        let cron = Cron::from_str(&options.expression)
+            .map_err(|e| MoraError::InvalidSchedule(format!("{}: {e}", options.expression)))?;
+        let timezone = Tz::from_str(&options.timezone)
+            .map_err(|e| MoraError::InvalidSchedule(format!("{}: {e}", options.timezone)))?;
+
+        Ok(Self {
+            cron,
+            timezone,
+            missed_fire_policy: options.missed_fire_policy,
+        })
+    }
+
+    /// First occurrence at or after `timestamp`.
+    pub fn first_from(&self, timestamp: u128) -> Option<u128> {
+        let from = match timestamp % NANOS_PER_SECOND {
+            0 => timestamp,
+            rest => timestamp.checked_add(NANOS_PER_SECOND - rest)?,
+        };
+        self.find(from, true, Direction::Forward)
+    }
+
+    /// First occurrence strictly after `timestamp`.
+    pub fn next_after(&self, timestamp: u128) -> Option<u128> {
+        self.find(
+            timestamp - timestamp % NANOS_PER_SECOND,
+            false,
+            Direction::Forward,
+        )
+    }
+
+    /// Occurrence to schedule once the one at `timestamp` was dispatched at `now`,
+    /// applying the missed-fire policy to occurrences that are already in the past.
+    /// Missed occurrences are found by seeking back from `now`, never by walking
+    /// through all of them.
+    pub fn next_occurrence(&self, timestamp: u128, now: u128) -> Option<u128> {
+        let next = self.next_after(timestamp)?;
+        if next > now {
+            return Some(next);
+        }
+
+        match self.missed_fire_policy {
+            MissedFirePolicy::Skip => self.next_after(now),
+            MissedFirePolicy::FireOnce => {
+                Some(self.last_until(now).map_or(next, |last| last.max(next)))
+            }
+            MissedFirePolicy::FireAll => {
+                let Some(mut oldest_kept) = self.last_until(now) else {
+                    return Some(next);
+                };
+                for _ in 1..MAX_FIRE_ALL_MISSED {
+                    match self.find(oldest_kept, false, Direction::Backward) {
+                        Some(previous) if previous > next => oldest_kept = previous,
+                        _ => return Some(next),
+                    }
+                }
+                Some(oldest_kept.max(next))
+            }
+        }
+    }
+
+    /// Last occurrence at or before `timestamp`.
+    fn last_until(&self, timestamp: u128) -> Option<u128> {
+        self.find(
+            timestamp - timestamp % NANOS_PER_SECOND,
+            true,
+            Direction::Backward,
+        )
+    }
+
+    fn find(&self, timestamp: u128, inclusive: bool, direction: Direction) -> Option<u128> {
+        let nanos = i64::try_from(timestamp).ok()?;
+        let from = Utc.timestamp_nanos(nanos).with_timezone(&self.timezone);
+        let occurrence: DateTime<Tz> = match direction {
+            Direction::Forward => self.cron.find_next_occurrence(&from, inclusive),
+            Direction::Backward => self.cron.find_previous_occurrence(&from, inclusive),
+        }
+        .ok()?;
+        let occurrence = u128::try_from(occurrence.timestamp_nanos_opt()?).ok()?;
+        Some(occurrence - occurrence % NANOS_PER_SECOND)
+    }
+}
+
+enum Direction {
+    Forward,
+    Backward,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(expression: &str, timezone: &str, policy: MissedFirePolicy) -> CronSchedule {
+        CronSchedule::parse(&CronOptions {
+            expression: expression.to_string(),
+            timezone: timezone.to_string(),
+            missed_fire_policy: policy,
+        })
+        .unwrap()
+    }
+
+    fn at(timezone: &str, y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> u128 {
+        let tz = Tz::from_str(timezone).unwrap();
+        tz.with_ymd_and_hms(y, mo, d, h, mi, s)
+            .earliest()
+            .unwrap()
+            .timestamp_nanos_opt()
+            .unwrap() as u128
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> u128 {
+        at("UTC", y, mo, d, h, mi, s)
+    }
+
+    #[test]
+    fn invalid_expression_is_rejected() {
+        let result = CronSchedule::parse(&CronOptions {
+            expression: "not a cron".to_string(),
+            timezone: "UTC".to_string(),
+            missed_fire_policy: MissedFirePolicy::FireOnce,
+        });
+        assert!(matches!(result, Err(MoraError::InvalidSchedule(_))));
+    }
+
+    #[test]
+    fn invalid_timezone_is_rejected() {
+        let result = CronSchedule::parse(&CronOptions {
+            expression: "0 9 * * *".to_string(),
+            timezone: "Mars/Olympus_Mons".to_string(),
+            missed_fire_policy: MissedFirePolicy::FireOnce,
+        });
+        assert!(matches!(result, Err(MoraError::InvalidSchedule(_))));
+    }
+
+    #[test]
+    fn weekday_schedule_skips_weekends_in_timezone() {
+        let cron = schedule("0 9 * * MON-FRI", "Europe/Rome", MissedFirePolicy::FireOnce);
+        // Friday 2024-03-08 09:00 Rome
+        let friday = at("Europe/Rome", 2024, 3, 8, 9, 0, 0);
+        assert_eq!(cron.first_from(friday), Some(friday));
+        assert_eq!(
+            cron.next_after(friday),
+            Some(at("Europe/Rome", 2024, 3, 11, 9, 0, 0))
+        );
+    }
+
+    #[test]
+    fn first_from_rounds_sub_second_timestamps_up() {
+        let cron = schedule("* * * * * *", "UTC", MissedFirePolicy::FireOnce);
+        let start = utc(2024, 1, 1, 0, 0, 0);
+        assert_eq!(cron.first_from(start + 1), Some(start + NANOS_PER_SECOND));
+        assert_eq!(cron.next_after(start + 1), Some(start + NANOS_PER_SECOND));
+    }
+
+    #[test]
+    fn fixed_time_in_spring_forward_gap_fires_after_the_gap() {
+        // 2024-03-31 02:30 does not exist in Rome, clocks jump from 02:00 to 03:00.
+        let cron = schedule("30 2 * * *", "Europe/Rome", MissedFirePolicy::FireOnce);
+        let before = at("Europe/Rome", 2024, 3, 30, 2, 30, 0);
+        assert_eq!(
+            cron.next_after(before),
+            Some(at("Europe/Rome", 2024, 3, 31, 3, 0, 0))
+        );
+    }
+
+    #[test]
+    fn fixed_time_in_fall_back_overlap_fires_once() {
+        // 2024-10-27 02:30 happens twice in Rome.
+        let cron = schedule("30 2 * * *", "Europe/Rome", MissedFirePolicy::FireOnce);
+        let first = at("Europe/Rome", 2024, 10, 27, 2, 30, 0);
+        assert_eq!(
+            cron.next_after(at("Europe/Rome", 2024, 10, 26, 2, 30, 0)),
+            Some(first)
+        );
+        assert_eq!(
+            cron.next_after(first),
+            Some(at("Europe/Rome", 2024, 10, 28, 2, 30, 0))
+        );
+    }
+
+    #[test]
+    fn on_time_dispatch_schedules_next_occurrence() {
+        let cron = schedule("0 * * * *", "UTC", MissedFirePolicy::Skip);
+        let timestamp = utc(2024, 1, 1, 10, 0, 0);
+        assert_eq!(
+            cron.next_occurrence(timestamp, timestamp + 1),
+            Some(utc(2024, 1, 1, 11, 0, 0))
+        );
+    }
+
+    #[test]
+    fn missed_fire_policy_fire_once_collapses_missed_occurrences() {
+        let cron = schedule("0 * * * *", "UTC", MissedFirePolicy::FireOnce);
+        let timestamp = utc(2024, 1, 1, 10, 0, 0);
+        let now = utc(2024, 1, 1, 13, 30, 0);
+        assert_eq!(
+            cron.next_occurrence(timestamp, now),
+            Some(utc(2024, 1, 1, 13, 0, 0))
+        );
+    }
+
+    #[test]
+    fn missed_fire_policy_skip_waits_for_the_future() {
+        let cron = schedule("0 * * * *", "UTC", MissedFirePolicy::Skip);
+        let timestamp = utc(2024, 1, 1, 10, 0, 0);
+        let now = utc(2024, 1, 1, 13, 30, 0);
+        assert_eq!(
+            cron.next_occurrence(timestamp, now),
+            Some(utc(2024, 1, 1, 14, 0, 0))
+        );
+    }
+
+    #[test]
+    fn missed_fire_policy_fire_all_replays_every_occurrence() {
+        let cron = schedule("0 * * * *", "UTC", MissedFirePolicy::FireAll);
+        let now = utc(2024, 1, 1, 13, 30, 0);
+        let mut timestamp = utc(2024, 1, 1, 10, 0, 0);
+        let mut fired = vec![];
+        while let Some(next) = cron.next_occurrence(timestamp, now) {
+            if next > now {
+                break;
+            }
+            fired.push(next);
+            timestamp = next;
+        }
+        assert_eq!(
+            fired,
+            vec![
+                utc(2024, 1, 1, 11, 0, 0),
+                utc(2024, 1, 1, 12, 0, 0),
+                utc(2024, 1, 1, 13, 0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn missed_fire_policy_fire_all_keeps_the_most_recent_occurrences() {
+        let cron = schedule("* * * * * *", "UTC", MissedFirePolicy::FireAll);
+        let timestamp = utc(2024, 1, 1, 0, 0, 0);
+        let now = utc(2024, 1, 2, 0, 0, 0);
+        let oldest_kept = now - (MAX_FIRE_ALL_MISSED as u128 - 1) * NANOS_PER_SECOND;
+        assert_eq!(cron.next_occurrence(timestamp, now), Some(oldest_kept));
+        assert_eq!(
+            cron.next_occurrence(oldest_kept, now),
+            Some(oldest_kept + NANOS_PER_SECOND)
+        );
+    }
+
+    #[test]
+    fn missed_fire_policy_fire_once_seeks_past_long_outages() {
+        let cron = schedule("* * * * * *", "UTC", MissedFirePolicy::FireOnce);
+        let timestamp = utc(2020, 1, 1, 0, 0, 0);
+        let now = utc(2024, 1, 1, 12, 0, 0) + 1;
+        assert_eq!(
+            cron.next_occurrence(timestamp, now),
+            Some(utc(2024, 1, 1, 12, 0, 0))
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+
+use mora_core::{
+    models::events::{Recurrence, RecurringOptions},
+    result::{MoraError, MoraResult},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cron::CronSchedule,
+    pool::{Bytes, QueueId},
+};
+
+/// Metadata attached to an event, e.g. `content-type` or a correlation id.
+pub type Headers = BTreeMap<String, String>;
+
+/// Leads the event records written since their format is versioned, followed by
+/// the format version. MessagePack never uses this byte, so records written before
+/// can't start with it.
+const FORMAT_MARKER: u8 = 0xc1;
+/// MessagePack encoding of `ScheduledEvent`.
+const FORMAT_VERSION: u8 = 1;
+
+/// Record stored for every scheduled event, both in memory and in storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledEvent {
+    pub data: Bytes,
+    pub recurrence: Option<Recurrence>,
+    /// Timestamp the event was due at before being leased to a channel. While
+    /// leased, the event is stored under the lease expiration instead.
+    #[serde(default)]
+    pub scheduled_for: Option<u128>,
+    /// Times the event has been leased to a channel.
+    #[serde(default)]
+    pub delivery_attempts: u32,
+    /// Queue the event was moved from, set for dead-lettered events.
+    #[serde(default)]
+    pub dead_lettered_from: Option<QueueId>,
+    #[serde(default)]
+    pub headers: Headers,
+}
+
+impl ScheduledEvent {
+    pub fn new(data: Bytes, recurrence: Option<Recurrence>) -> Self {
+        Self {
+            data,
+            recurrence,
+            scheduled_for: None,
+            delivery_attempts: 0,
+            dead_lettered_from: None,
+            headers: Headers::default(),
+        }
+    }
+
+    pub fn with_headers(mut self, headers: Headers) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn to_bytes(&self) -> MoraResult<Bytes> {
+        let mut bytes = vec![FORMAT_MARKER, FORMAT_VERSION];
+        rmp_serde::encode::write(&mut bytes, self)
+            .map_err(|e| MoraError::SerializationError(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> MoraResult<Self> {
+        match bytes {
+            [FORMAT_MARKER, FORMAT_VERSION, record @ ..] => rmp_serde::from_slice(record)
+                .map_err(|e| MoraError::SerializationError(e.to_string())),
+            [FORMAT_MARKER, version, ..] => Err(MoraError::SerializationError(format!(
+                "unsupported event format version {version}"
+            ))),
+            _ => Ok(Self::from_unversioned_bytes(bytes)),
+        }
+    }
+
+    /// Decodes the records written before their format was versioned: the current
+    /// layout, then the one from before cron schedules, which only had fixed-delay
+    /// recurrences. Records that are neither were written before events had any
+    /// metadata and are the payload alone.
+    fn from_unversioned_bytes(bytes: &[u8]) -> Self {
+        #[derive(Deserialize)]
+        struct FixedDelayEvent {
+            data: Bytes,
+            recurring_options: Option<RecurringOptions>,
+        }
+
+        if let Ok(event) = rmp_serde::from_slice(bytes) {
+            return event;
+        }
+        match rmp_serde::from_slice::<FixedDelayEvent>(bytes) {
+            Ok(event) => Self::new(event.data, event.recurring_options.map(Recurrence::Fixed)),
+            Err(_) => Self::new(bytes.to_vec(), None),
+        }
+    }
+
+    /// Occurrences left once this one has been dispatched, `None` for one-off events.
+    /// Cron schedules never run out and report `RecurringOptions::INFINITE`.
+    pub fn remaining_occurrences(&self) -> Option<u128> {
+        match self.recurrence.as_ref()? {
+            Recurrence::Fixed(options) => Some(options.remaining_occurrences()),
+            Recurrence::Cron(_) => Some(RecurringOptions::INFINITE),
+        }
+    }
+
+    /// Computes the occurrence following the one scheduled at `timestamp`,
+    /// dispatched at `now`.
+    pub fn next_occurrence(
+        &self,
+        timestamp: u128,
+        now: u128,
+    ) -> MoraResult<Option<(u128, ScheduledEvent)>> {
+        let next = match &self.recurrence {
+            None => None,
+            Some(Recurrence::Fixed(options)) => options.next().and_then(|next_options| {
+                timestamp
+                    .checked_add(options.delay)
+                    .map(|next_timestamp| (next_timestamp, Recurrence::Fixed(next_options)))
+            }),
+            Some(Recurrence::Cron(options)) => CronSchedule::parse(options)?
+                .next_occurrence(timestamp, now)
+                .map(|next_timestamp| (next_timestamp, Recurrence::Cron(options.clone()))),
+        };
+
+        Ok(next.map(|(next_timestamp, recurrence)| {
+            (
+                next_timestamp,
+                ScheduledEvent::new(self.data.clone(), Some(recurrence))
+                    .with_headers(self.headers.clone()),
+            )
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mora_core::models::events::{CronOptions, MissedFirePolicy};
+
+    use super::*;
+
+    fn recurring(times: u128, delay: u128) -> ScheduledEvent {
+        ScheduledEvent::new(
+            b"data".to_vec(),
+            Some(Recurrence::Fixed(RecurringOptions { times, delay })),
+        )
+    }
+
+    #[test]
+    fn one_off_event_has_no_next_occurrence() -> MoraResult<()> {
+        let event = ScheduledEvent::new(b"data".to_vec(), None);
+        assert_eq!(event.next_occurrence(10, 10)?, None);
+        assert_eq!(event.remaining_occurrences(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn next_occurrence_keeps_headers() -> MoraResult<()> {
+        let headers = Headers::from([("content-type".to_string(), "text/plain".to_string())]);
+        let event = recurring(2, 5).with_headers(headers.clone());
+        let (_, next) = event.next_occurrence(10, 10)?.unwrap();
+        assert_eq!(next.headers, headers);
+        Ok(())
+    }
+
+    #[test]
+    fn recurring_event_counts_down_remaining_occurrences() -> MoraResult<()> {
+        let event = recurring(3, 5);
+        assert_eq!(event.remaining_occurrences(), Some(2));
+
+        let (timestamp, next) = event.next_occurrence(10, 10)?.unwrap();
+        assert_eq!(timestamp, 15);
+        assert_eq!(next.remaining_occurrences(), Some(1));
+
+        let (timestamp, last) = next.next_occurrence(timestamp, timestamp)?.unwrap();
+        assert_eq!(timestamp, 20);
+        assert_eq!(last.remaining_occurrences(), Some(0));
+        assert_eq!(last.next_occurrence(timestamp, timestamp)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn infinite_recurring_event_never_runs_out() -> MoraResult<()> {
+        let event = recurring(RecurringOptions::INFINITE, 5);
+        let (_, next) = event.next_occurrence(10, 10)?.unwrap();
+        assert_eq!(
+            next.remaining_occurrences(),
+            Some(RecurringOptions::INFINITE)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cron_event_schedules_next_calendar_occurrence() -> MoraResult<()> {
+        let event = ScheduledEvent::new(
+            b"data".to_vec(),
+            Some(Recurrence::Cron(CronOptions {
+                expression: "0 0 * * *".to_string(),
+                timezone: "UTC".to_string(),
+                missed_fire_policy: MissedFirePolicy::Skip,
+            })),
+        );
+        let day = 24 * 3600 * 1_000_000_000;
+        let (timestamp, next) = event.next_occurrence(day, day)?.unwrap();
+        assert_eq!(timestamp, 2 * day);
+        assert_eq!(next.recurrence, event.recurrence);
+        Ok(())
+    }
+
+    #[test]
+    fn scheduled_event_roundtrips_through_bytes() -> MoraResult<()> {
+        let mut event = recurring(2, 7);
+        assert_eq!(ScheduledEvent::from_bytes(&event.to_bytes()?)?, event);
+        event.scheduled_for = Some(42);
+        event.delivery_attempts = 3;
+        event.dead_lettered_from = Some("queue".to_string());
+        event
+            .headers
+            .insert("tenant".to_string(), "acme".to_string());
+        assert_eq!(ScheduledEvent::from_bytes(&event.to_bytes()?)?, event);
+        Ok(())
+    }
+
+    #[test]
+    fn unversioned_records_are_decoded() -> MoraResult<()> {
+        let event = recurring(2, 7)
+            .with_headers(Headers::from([("tenant".to_string(), "acme".to_string())]));
+        let current = rmp_serde::to_vec(&event).unwrap();
+        assert_eq!(ScheduledEvent::from_bytes(&current)?, event);
+
+        #[derive(Serialize)]
+        struct FixedDelayEvent {
+            data: Bytes,
+            recurring_options: Option<RecurringOptions>,
+        }
+        let fixed_delay = rmp_serde::to_vec(&FixedDelayEvent {
+            data: b"data".to_vec(),
+            recurring_options: Some(RecurringOptions { times: 2, delay: 7 }),
+        })
+        .unwrap();
+        assert_eq!(ScheduledEvent::from_bytes(&fixed_delay)?, recurring(2, 7));
+
+        assert_eq!(
+            ScheduledEvent::from_bytes(b"payload")?,
+            ScheduledEvent::new(b"payload".to_vec(), None)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_format_versions_are_rejected() -> MoraResult<()> {
+        let mut bytes = recurring(2, 7).to_bytes()?;
+        bytes[1] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            ScheduledEvent::from_bytes(&bytes),
+            Err(MoraError::SerializationError(_))
+        ));
+        Ok(())
+    }
+}
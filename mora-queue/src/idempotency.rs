@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashMap};
+
+use mora_core::{
+    models::events::EventKey,
+    result::{MoraError, MoraResult},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::pool::{Bytes, EventId};
+
+/// Idempotency key remembered for a `ScheduleEvent` request, as persisted in storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    pub key: String,
+    pub event_ids: Vec<EventId>,
+}
+
+impl IdempotencyRecord {
+    pub fn to_bytes(&self) -> MoraResult<Bytes> {
+        rmp_serde::to_vec(self).map_err(|e| MoraError::SerializationError(e.to_string()))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> MoraResult<Self> {
+        rmp_serde::from_slice(bytes).map_err(|e| MoraError::SerializationError(e.to_string()))
+    }
+}
+
+/// In-memory view of the remembered idempotency keys.
+///
+/// Each key is stored under an `EventKey` whose timestamp is the expiration time,
+/// so expired keys can be purged in order.
+#[derive(Debug, Default)]
+pub struct IdempotencyKeys {
+    records: HashMap<String, (EventKey, Vec<EventId>)>,
+    expirations: BTreeMap<EventKey, String>,
+}
+
+impl IdempotencyKeys {
+    /// Remembers `record`, returning the storage key of the record it replaced, if any.
+    pub fn insert(&mut self, storage_key: EventKey, record: IdempotencyRecord) -> Option<EventKey> {
+        let previous_key = self
+            .records
+            .insert(record.key.clone(), (storage_key, record.event_ids))
+            .map(|(previous_key, _)| previous_key);
+        if let Some(previous_key) = &previous_key {
+            self.expirations.remove(previous_key);
+        }
+        self.expirations.insert(storage_key, record.key);
+        previous_key
+    }
+
+    /// Forgets `key`, returning the storage key of its record.
+    pub fn remove(&mut self, key: &str) -> Option<EventKey> {
+        let (storage_key, _) = self.records.remove(key)?;
+        self.expirations.remove(&storage_key);
+        Some(storage_key)
+    }
+
+    /// Event ids remembered for `key`, unless it expired before `now`.
+    pub fn get(&self, key: &str, now: u128) -> Option<&Vec<EventId>> {
+        self.records
+            .get(key)
+            .filter(|(storage_key, _)| storage_key.timestamp > now)
+            .map(|(_, event_ids)| event_ids)
+    }
+
+    /// Forgets every key expired at `now`, returning their storage keys.
+    pub fn purge_expired(&mut self, now: u128) -> Vec<EventKey> {
+        let still_valid = self
+            .expirations
+            .split_off(&EventKey::new(now.saturating_add(1), 0));
+        let expired = std::mem::replace(&mut self.expirations, still_valid);
+
+        expired
+            .into_iter()
+            .map(|(storage_key, key)| {
+                self.records.remove(&key);
+                storage_key
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: &str, event_ids: Vec<EventId>) -> IdempotencyRecord {
+        IdempotencyRecord {
+            key: key.to_string(),
+            event_ids,
+        }
+    }
+
+    #[test]
+    fn remembered_key_returns_original_event_ids_until_expiration() {
+        let mut keys = IdempotencyKeys::default();
+        keys.insert(EventKey::new(100, 1), record("retry-me", vec![7, 8]));
+
+        assert_eq!(keys.get("retry-me", 50), Some(&vec![7, 8]));
+        assert_eq!(keys.get("retry-me", 100), None);
+        assert_eq!(keys.get("unknown", 50), None);
+    }
+
+    #[test]
+    fn purge_expired_forgets_only_expired_keys() {
+        let mut keys = IdempotencyKeys::default();
+        keys.insert(EventKey::new(100, 1), record("old", vec![1]));
+        keys.insert(EventKey::new(200, 2), record("new", vec![2]));
+
+        assert_eq!(keys.purge_expired(100), vec![EventKey::new(100, 1)]);
+        assert_eq!(keys.get("old", 50), None);
+        assert_eq!(keys.get("new", 150), Some(&vec![2]));
+    }
+
+    #[test]
+    fn reinserting_a_key_replaces_its_expiration() {
+        let mut keys = IdempotencyKeys::default();
+        keys.insert(EventKey::new(100, 1), record("key", vec![1]));
+        assert_eq!(
+            keys.insert(EventKey::new(300, 2), record("key", vec![2])),
+            Some(EventKey::new(100, 1))
+        );
+
+        assert!(keys.purge_expired(200).is_empty());
+        assert_eq!(keys.get("key", 200), Some(&vec![2]));
+    }
+
+    #[test]
+    fn idempotency_record_roundtrips_through_bytes() -> MoraResult<()> {
+        let record = record("key", vec![1, 2, 3]);
+        assert_eq!(IdempotencyRecord::from_bytes(&record.to_bytes()?)?, record);
+        Ok(())
+    }
+}
@@ -1,5 +1,11 @@
-pub(crate) mod priority_queue;
-pub(crate) mod temporal_queue;
+pub(crate) mod idempotency;
+pub(crate) mod queue_options;
+pub(crate) mod timing_wheel;
 
 pub mod channel_manager;
+pub mod cron;
+pub mod event;
 pub mod pool;
+pub mod priority_queue;
+pub mod scheduler;
+pub mod temporal_queue;
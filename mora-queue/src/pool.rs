@@ -1,75 +1,372 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
+use log::{error, warn};
 use mora_core::{
+    clock::Clock,
+    models::{
+        events::EventKey,
+        queues::{DeadLetterPolicy, QueueOptions},
+    },
     result::{MoraError, MoraResult},
-    traits::storage::Storage,
+    traits::storage::{
+        AsyncStorage, CompactionStats, DurabilityTicket, SnapshotStats, StorageFuture, StorageWrite,
+    },
 };
 use regex::Regex;
+use tokio::sync::Notify;
 
-use crate::temporal_queue::TemporalQueue;
+use crate::{
+    event::ScheduledEvent,
+    idempotency::{IdempotencyKeys, IdempotencyRecord},
+    queue_options::QueueOptionsRecord,
+    scheduler::DueEvents,
+    temporal_queue::TemporalQueue,
+};
 
 pub(crate) type Bytes = Vec<u8>;
 pub(crate) type QueueId = String;
 pub(crate) type EventId = u128;
 
-pub struct QueuePool<T: Storage<ContainerId = QueueId, SortKey = EventId, Item = Bytes>> {
-    queues: HashMap<QueueId, TemporalQueue<Bytes>>,
+/// Storage containers whose name starts with this prefix hold server state
+/// rather than queues. Queues can't be created with such a name.
+pub const SYSTEM_CONTAINER_PREFIX: &str = "__mora_";
+const IDEMPOTENCY_KEYS_CONTAINER: &str = "__mora_idempotency_keys";
+const QUEUE_OPTIONS_CONTAINER: &str = "__mora_queue_options";
+
+const DEFAULT_IDEMPOTENCY_WINDOW_IN_MSEC: u128 = 24 * 3600 * 1000;
+const NANOS_PER_MSEC: u128 = 1_000_000;
+
+pub struct QueuePoolOptions {
+    /// How long idempotency keys of `ScheduleEvent` requests are remembered.
+    pub idempotency_window_in_msec: u128,
+}
+
+impl Default for QueuePoolOptions {
+    fn default() -> Self {
+        Self {
+            idempotency_window_in_msec: DEFAULT_IDEMPOTENCY_WINDOW_IN_MSEC,
+        }
+    }
+}
+
+/// Queues of events, kept in memory and persisted to `storage`.
+///
+/// Changes are made in memory right away and return a `PendingWrite`: their storage
+/// writes are issued at once, in the order the changes were made, and only awaited
+/// by the caller, so the pool can be released while the storage works. When a write
+/// fails its change stays in memory, but not in storage: it is lost on restart.
+pub struct QueuePool<T: AsyncStorage<ContainerId = QueueId, SortKey = EventKey, Item = Bytes>> {
+    queues: HashMap<QueueId, TemporalQueue<ScheduledEvent>>,
+    queue_options: HashMap<QueueId, (EventKey, QueueOptions)>,
+    idempotency_keys: IdempotencyKeys,
+    due_events: DueEvents,
+    options: QueuePoolOptions,
     storage: T,
 }
 
-impl<T: Storage<ContainerId = QueueId, SortKey = EventId, Item = Bytes>> QueuePool<T> {
-    pub async fn new() -> MoraResult<Self> {
-        let storage = T::load()?;
+/// A change made to the pool, being persisted. The write doesn't borrow the pool:
+/// await `written` after releasing it.
+#[must_use = "the change may not be persisted"]
+pub struct PendingWrite<R> {
+    value: R,
+    write: StorageFuture<()>,
+}
+
+impl<R> PendingWrite<R> {
+    /// The outcome of the change, known before it is persisted.
+    pub fn value(&self) -> &R {
+        &self.value
+    }
+
+    /// Resolves to the outcome of the change once it is persisted.
+    pub async fn written(self) -> MoraResult<R> {
+        self.write.await?;
+        Ok(self.value)
+    }
+
+    /// Splits the outcome of the change from its write, for callers handing the
+    /// outcome over before it is persisted.
+    pub fn into_parts(self) -> (R, StorageFuture<()>) {
+        (self.value, self.write)
+    }
+}
+
+/// Events `enqueue_all` schedules in a queue, along with their records.
+type Batch = (QueueId, Vec<(EventKey, ScheduledEvent, Bytes)>);
+
+/// Storage writes of a change, in order. Consecutive writes of the same kind to the
+/// same container are batched.
+#[derive(Default)]
+struct Writes(Vec<StorageWrite<QueueId, EventKey, Bytes>>);
+
+impl Writes {
+    fn push(&mut self, write: StorageWrite<QueueId, EventKey, Bytes>) {
+        self.0.push(write);
+    }
+
+    fn store(&mut self, id: &QueueId, key: EventKey, item: Bytes) {
+        match self.0.last_mut() {
+            Some(StorageWrite::StoreItems(container, items)) if container == id => {
+                items.push((key, item))
+            }
+            _ => self.push(StorageWrite::StoreItems(id.to_owned(), vec![(key, item)])),
+        }
+    }
+
+    fn delete(&mut self, id: &QueueId, key: EventKey) {
+        match self.0.last_mut() {
+            Some(StorageWrite::DeleteItems(container, keys)) if container == id => keys.push(key),
+            _ => self.push(StorageWrite::DeleteItems(id.to_owned(), vec![key])),
+        }
+    }
+}
+
+impl<T: AsyncStorage<ContainerId = QueueId, SortKey = EventKey, Item = Bytes>> QueuePool<T> {
+    pub async fn new(options: QueuePoolOptions) -> MoraResult<Self> {
+        Self::from_storage(T::load().await?, options).await
+    }
+
+    /// Loads the queues kept in `storage`. Records that can't be decoded, e.g. written
+    /// by a newer version, are logged and left in storage rather than failing the load.
+    pub async fn from_storage(storage: T, options: QueuePoolOptions) -> MoraResult<Self> {
         let mut pool = Self {
             queues: HashMap::default(),
+            queue_options: HashMap::default(),
+            idempotency_keys: IdempotencyKeys::default(),
+            due_events: DueEvents::default(),
+            options,
             storage,
         };
 
-        let containers = pool.storage.list_containers()?;
+        let containers = pool.storage.list_containers().await?;
         for container in containers {
+            if container.starts_with(SYSTEM_CONTAINER_PREFIX) {
+                continue;
+            }
             pool.queues
                 .insert(container.to_owned(), TemporalQueue::default());
-            for (key, item) in pool.storage.get_all_items(&container)? {
-                pool.get_queue_mut(&container)?.enqueue(key, item)?;
+            for (key, item) in pool.storage.get_all_items(&container).await? {
+                let event = match ScheduledEvent::from_bytes(&item) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        error!("skipping event {key:?} of {container}, can't be decoded: {e}");
+                        continue;
+                    }
+                };
+                pool.get_queue_mut(&container)?.enqueue(key, event)?;
+                pool.due_events.track(&container, key);
             }
         }
 
+        pool.load_queue_options().await?;
+        pool.load_idempotency_keys().await?;
+
         Ok(pool)
     }
 
-    pub fn create_queue(&mut self, id: QueueId) -> MoraResult<()> {
+    async fn load_queue_options(&mut self) -> MoraResult<()> {
+        let container = QUEUE_OPTIONS_CONTAINER.to_string();
+        if !self.storage.list_containers().await?.contains(&container) {
+            return self.storage.create_container(&container).await;
+        }
+
+        for (key, item) in self.storage.get_all_items(&container).await? {
+            let record = match QueueOptionsRecord::from_bytes(&item) {
+                Ok(record) => record,
+                Err(e) => {
+                    error!("skipping queue options {key:?}, can't be decoded: {e}");
+                    continue;
+                }
+            };
+            self.queue_options
+                .insert(record.queue, (key, record.options));
+        }
+        Ok(())
+    }
+
+    async fn load_idempotency_keys(&mut self) -> MoraResult<()> {
+        let container = IDEMPOTENCY_KEYS_CONTAINER.to_string();
+        if !self.storage.list_containers().await?.contains(&container) {
+            return self.storage.create_container(&container).await;
+        }
+
+        for (key, item) in self.storage.get_all_items(&container).await? {
+            match IdempotencyRecord::from_bytes(&item) {
+                Ok(record) => {
+                    self.idempotency_keys.insert(key, record);
+                }
+                Err(e) => error!("skipping idempotency key {key:?}, can't be decoded: {e}"),
+            }
+        }
+        let expired = self.idempotency_keys.purge_expired(Clock::now());
+        if !expired.is_empty() {
+            self.storage.delete_items(&container, &expired).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes a change with `change`, which queues the storage writes of what it changed
+    /// in memory, then issues the writes. They are issued even when `change` fails, so
+    /// that storage keeps up with the changes it made before failing.
+    fn change<R>(
+        &mut self,
+        change: impl FnOnce(&mut Self, &mut Writes) -> MoraResult<R>,
+    ) -> MoraResult<PendingWrite<R>> {
+        let mut writes = Writes::default();
+        let result = change(self, &mut writes);
+        let write: StorageFuture<()> = match writes.0.is_empty() {
+            true => Box::pin(std::future::ready(Ok(()))),
+            false => Box::pin(self.storage.write(writes.0)),
+        };
+        result.map(|value| PendingWrite { value, write })
+    }
+
+    /// Event ids of the `ScheduleEvent` request previously made with `key`, if it is
+    /// still within the idempotency window.
+    pub fn get_idempotency_key(&self, key: &str) -> Option<Vec<EventId>> {
+        self.idempotency_keys.get(key, Clock::now()).cloned()
+    }
+
+    /// Record of an idempotency key, along with its storage key and bytes.
+    fn idempotency_record(
+        &self,
+        key: String,
+        event_ids: Vec<EventId>,
+    ) -> MoraResult<(EventKey, IdempotencyRecord, Bytes)> {
+        let expires_at =
+            Clock::now().saturating_add(self.options.idempotency_window_in_msec * NANOS_PER_MSEC);
+        let record = IdempotencyRecord { key, event_ids };
+        let item = record.to_bytes()?;
+        Ok((EventKey::new(expires_at, new_event_id()), record, item))
+    }
+
+    /// Remembers an idempotency key, forgetting the expired ones along.
+    fn remember_idempotency_key(
+        &mut self,
+        (storage_key, record, item): (EventKey, IdempotencyRecord, Bytes),
+        writes: &mut Writes,
+    ) {
+        let container = IDEMPOTENCY_KEYS_CONTAINER.to_string();
+        writes.store(&container, storage_key, item);
+
+        let expired = self.idempotency_keys.purge_expired(Clock::now());
+        let previous_key = self.idempotency_keys.insert(storage_key, record);
+        for key in expired.into_iter().chain(previous_key) {
+            writes.delete(&container, key);
+        }
+    }
+
+    pub fn create_queue(
+        &mut self,
+        id: QueueId,
+        options: QueueOptions,
+    ) -> MoraResult<PendingWrite<()>> {
+        if id.starts_with(SYSTEM_CONTAINER_PREFIX) {
+            return Err(MoraError::ReservedQueueName(id));
+        }
+
         if self.queues.contains_key(&id) {
             return Err(MoraError::QueueAlreadyExists(id));
         }
 
-        self.storage.create_container(&id)?;
-        self.queues.insert(id, TemporalQueue::default());
+        if let Some(policy) = &options.dead_letter_policy {
+            self.validate_dead_letter_policy(&id, policy)?;
+        }
+
+        self.change(|pool, writes| {
+            let options_record = match options != QueueOptions::default() {
+                true => Some(
+                    QueueOptionsRecord {
+                        queue: id.clone(),
+                        options: options.clone(),
+                    }
+                    .to_bytes()?,
+                ),
+                false => None,
+            };
 
+            writes.push(StorageWrite::CreateContainer(id.clone()));
+            pool.queues.insert(id.clone(), TemporalQueue::default());
+            if let Some(record) = options_record {
+                let key = EventKey::new(0, new_event_id());
+                writes.store(&QUEUE_OPTIONS_CONTAINER.to_string(), key, record);
+                pool.queue_options.insert(id, (key, options));
+            }
+            Ok(())
+        })
+    }
+
+    fn validate_dead_letter_policy(
+        &self,
+        id: &QueueId,
+        policy: &DeadLetterPolicy,
+    ) -> MoraResult<()> {
+        if policy.max_delivery_attempts == 0 {
+            return Err(MoraError::InvalidDeadLetterPolicy(
+                "max_delivery_attempts must be positive".to_string(),
+            ));
+        }
+        if &policy.dead_letter_queue == id {
+            return Err(MoraError::InvalidDeadLetterPolicy(
+                "a queue can't be its own dead letter queue".to_string(),
+            ));
+        }
+        if !self.queues.contains_key(&policy.dead_letter_queue) {
+            return Err(MoraError::InvalidDeadLetterPolicy(format!(
+                "{} queue does not exist",
+                policy.dead_letter_queue
+            )));
+        }
         Ok(())
     }
 
-    pub fn delete_queue(&mut self, id: QueueId) -> MoraResult<QueueId> {
-        self.storage.delete_container(&id)?;
+    pub fn get_queue_options(&self, id: &QueueId) -> MoraResult<QueueOptions> {
+        self.get_queue(id)?;
+        Ok(self
+            .queue_options
+            .get(id)
+            .map(|(_, options)| options.clone())
+            .unwrap_or_default())
+    }
 
-        self.queues
+    pub fn delete_queue(&mut self, id: QueueId) -> MoraResult<PendingWrite<QueueId>> {
+        let queue = self
+            .queues
             .remove(&id)
-            .ok_or(MoraError::QueueNotFound(id.to_string()))
-            .map(|_| id)
+            .ok_or(MoraError::QueueNotFound(id.to_string()))?;
+
+        self.change(|pool, writes| {
+            writes.push(StorageWrite::DeleteContainer(id.clone()));
+            if let Some((key, _)) = pool.queue_options.remove(&id) {
+                writes.delete(&QUEUE_OPTIONS_CONTAINER.to_string(), key);
+            }
+            for (key, _) in queue.peek_until(u128::MAX, None, usize::MAX) {
+                pool.due_events.untrack(&id, key);
+            }
+            pool.due_events.forget_queue(&id);
+            Ok(id)
+        })
     }
 
-    pub fn get_queue(&self, id: &QueueId) -> MoraResult<&TemporalQueue<Bytes>> {
+    pub fn get_queue(&self, id: &QueueId) -> MoraResult<&TemporalQueue<ScheduledEvent>> {
         self.queues
             .get(id)
             .ok_or(MoraError::QueueNotFound(id.to_string()))
     }
 
-    pub fn get_queue_mut(&mut self, id: &QueueId) -> MoraResult<&mut TemporalQueue<Bytes>> {
+    pub fn get_queue_mut(
+        &mut self,
+        id: &QueueId,
+    ) -> MoraResult<&mut TemporalQueue<ScheduledEvent>> {
         self.queues
             .get_mut(id)
             .ok_or(MoraError::QueueNotFound(id.to_string()))
     }
 
-    pub fn get_queues(&self, pattern: Regex) -> MoraResult<Vec<(String, &TemporalQueue<Bytes>)>> {
+    pub fn get_queues(
+        &self,
+        pattern: Regex,
+    ) -> MoraResult<Vec<(String, &TemporalQueue<ScheduledEvent>)>> {
         Ok(self
             .queues
             .keys()
@@ -82,7 +379,7 @@ impl<T: Storage<ContainerId = QueueId, SortKey = EventId, Item = Bytes>> QueuePo
         self.queues.contains_key(id)
     }
 
-    pub fn get_all_queues(&self) -> MoraResult<Vec<(String, &TemporalQueue<Bytes>)>> {
+    pub fn get_all_queues(&self) -> MoraResult<Vec<(String, &TemporalQueue<ScheduledEvent>)>> {
         Ok(self
             .queues
             .keys()
@@ -93,7 +390,7 @@ impl<T: Storage<ContainerId = QueueId, SortKey = EventId, Item = Bytes>> QueuePo
     pub fn get_queues_mut(
         &mut self,
         pattern: Regex,
-    ) -> MoraResult<Vec<(String, &mut TemporalQueue<Bytes>)>> {
+    ) -> MoraResult<Vec<(String, &mut TemporalQueue<ScheduledEvent>)>> {
         let mut queues = vec![];
         for (k, queue) in self.queues.iter_mut() {
             if pattern.is_match(k) {
@@ -103,25 +400,1062 @@ impl<T: Storage<ContainerId = QueueId, SortKey = EventId, Item = Bytes>> QueuePo
         Ok(queues)
     }
 
-    pub fn enqueue(&mut self, id: &QueueId, timestamp: EventId, value: Bytes) -> MoraResult<()> {
-        self.storage.store_item(&id, &timestamp, &value)?;
-        self.get_queue_mut(id)?.enqueue(timestamp, value)?;
+    /// Schedules `event` for `timestamp`, returning its newly generated key.
+    pub fn enqueue(
+        &mut self,
+        id: &QueueId,
+        timestamp: u128,
+        event: ScheduledEvent,
+    ) -> MoraResult<PendingWrite<EventKey>> {
+        let key = EventKey::new(timestamp, new_event_id());
+        self.change(|pool, writes| {
+            pool.enqueue_with_key(id, key, event, writes)?;
+            Ok(key)
+        })
+    }
+
+    /// Schedules every `(queue, timestamp, event)`, returning their newly generated
+    /// keys in order, and remembers them under `idempotency_key` if given. Either every
+    /// event is scheduled or none is: the queues and their room are checked first. The
+    /// events of each queue are stored in a single batch, see `Storage::store_items`,
+    /// followed by the idempotency key. Storage engines apply these writes one by one:
+    /// when the write fails, take the request back with `undo_enqueue_all`.
+    pub fn enqueue_all(
+        &mut self,
+        events: Vec<(QueueId, u128, ScheduledEvent)>,
+        idempotency_key: Option<String>,
+    ) -> MoraResult<PendingWrite<Vec<EventKey>>> {
+        let mut keys = Vec::with_capacity(events.len());
+        let mut batches: Vec<Batch> = vec![];
+        for (id, timestamp, event) in events {
+            self.get_queue(&id)?;
+            let key = EventKey::new(timestamp, new_event_id());
+            let item = event.to_bytes()?;
+            match batches.iter_mut().find(|(queue, _)| *queue == id) {
+                Some((_, batch)) => batch.push((key, event, item)),
+                None => batches.push((id, vec![(key, event, item)])),
+            }
+            keys.push(key);
+        }
+        for (id, batch) in &batches {
+            if !self.get_queue(id)?.has_room_for_new(batch.len()) {
+                return Err(MoraError::QueueFull);
+            }
+        }
+        let idempotency_record = idempotency_key
+            .map(|key| self.idempotency_record(key, keys.iter().map(|key| key.id).collect()))
+            .transpose()?;
+
+        self.change(|pool, writes| {
+            let mut enqueued: Vec<(&QueueId, EventKey)> = Vec::with_capacity(keys.len());
+            for (id, batch) in &batches {
+                for (key, event, _) in batch {
+                    if let Err(e) = pool.get_queue_mut(id)?.enqueue(*key, event.clone()) {
+                        // Nothing was written yet: forget the events enqueued so far.
+                        for (id, key) in enqueued {
+                            pool.get_queue_mut(id)?.remove(key.id);
+                            pool.due_events.untrack(id, key);
+                        }
+                        return Err(e);
+                    }
+                    pool.due_events.track(id, *key);
+                    enqueued.push((id, *key));
+                }
+            }
+            for (id, batch) in batches {
+                for (key, _, item) in batch {
+                    writes.store(&id, key, item);
+                }
+            }
+            if let Some(record) = idempotency_record {
+                pool.remember_idempotency_key(record, writes);
+            }
+            Ok(keys)
+        })
+    }
+
+    /// Takes back an `enqueue_all` whose write failed, so that it has no effect
+    /// whichever of its writes were applied: its events still queued are cancelled
+    /// and `idempotency_key` is forgotten, in memory and in storage. Events consumed
+    /// in the meantime can't be taken back.
+    pub fn undo_enqueue_all(
+        &mut self,
+        events: &[(QueueId, EventKey)],
+        idempotency_key: Option<&str>,
+    ) -> MoraResult<PendingWrite<()>> {
+        self.change(|pool, writes| {
+            // First, so that retries aren't answered with these events if the rest fails.
+            if let Some(storage_key) =
+                idempotency_key.and_then(|key| pool.idempotency_keys.remove(key))
+            {
+                writes.delete(&IDEMPOTENCY_KEYS_CONTAINER.to_string(), storage_key);
+            }
+            for (id, key) in events {
+                // The queue may have been deleted with its events in the meantime.
+                let Ok(queue) = pool.get_queue_mut(id) else {
+                    continue;
+                };
+                // Leased events are stored under another key by now.
+                if let Some((current, _)) = queue.remove(key.id) {
+                    pool.due_events.untrack(id, current);
+                    writes.delete(id, current);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn enqueue_with_key(
+        &mut self,
+        id: &QueueId,
+        key: EventKey,
+        event: ScheduledEvent,
+        writes: &mut Writes,
+    ) -> MoraResult<()> {
+        // Checked first, so a full queue never gets an event stored that it can't hold.
+        if !self.get_queue(id)?.has_room_for(&key) {
+            return Err(MoraError::QueueFull);
+        }
+        let item = event.to_bytes()?;
+        self.get_queue_mut(id)?.enqueue(key, event)?;
+        self.due_events.track(id, key);
+        writes.store(id, key, item);
         Ok(())
     }
 
+    /// Compacts the storage where needed, see `Storage::compact`. Await it after
+    /// releasing the pool: compaction runs storage-side, without blocking requests.
+    pub fn compact_storage(&mut self) -> StorageFuture<CompactionStats> {
+        Box::pin(self.storage.compact())
+    }
+
+    /// Snapshots the storage where needed, see `Storage::snapshot`. Await it after
+    /// releasing the pool, like `compact_storage`.
+    pub fn snapshot_storage(&mut self) -> StorageFuture<SnapshotStats> {
+        Box::pin(self.storage.snapshot())
+    }
+
+    /// Resolves once the changes made so far are durable, see
+    /// `Storage::durability_ticket`. Await it after releasing the pool.
+    pub fn durability_ticket(&self) -> DurabilityTicket {
+        self.storage.durability_ticket()
+    }
+
+    /// Returns the events that became due at `now` since the last call, skipping the
+    /// ones that were cancelled or moved in the meantime.
+    pub fn advance_due_events(&mut self, now: u128) -> Vec<(Arc<str>, EventKey)> {
+        self.due_events
+            .advance(now)
+            .into_iter()
+            .filter(|(id, key)| {
+                self.queues
+                    .get(&**id)
+                    .is_some_and(|queue| queue.find(key.id) == Some(*key))
+            })
+            .collect()
+    }
+
+    /// Earliest time `advance_due_events` may return events.
+    pub fn next_due_event_at(&self) -> Option<u128> {
+        self.due_events.next_expiration()
+    }
+
+    /// Notified when an event is scheduled before `next_due_event_at`.
+    pub fn due_events_wakeup(&self) -> Arc<Notify> {
+        self.due_events.wakeup()
+    }
+
+    /// Cancels a scheduled event. For recurring events the whole series is cancelled.
+    pub fn cancel(
+        &mut self,
+        id: &QueueId,
+        event_id: EventId,
+    ) -> MoraResult<PendingWrite<EventKey>> {
+        let (key, _) = self
+            .get_queue_mut(id)?
+            .remove(event_id)
+            .ok_or(MoraError::EventNotFound(event_id.to_string()))?;
+        self.change(|pool, writes| {
+            pool.due_events.untrack(id, key);
+            writes.delete(id, key);
+            Ok(key)
+        })
+    }
+
+    /// Moves a scheduled event to `timestamp`, keeping its id.
+    pub fn reschedule(
+        &mut self,
+        id: &QueueId,
+        event_id: EventId,
+        timestamp: u128,
+    ) -> MoraResult<PendingWrite<EventKey>> {
+        self.change(|pool, writes| {
+            // A rescheduled event is no longer leased to anyone.
+            pool.move_event(id, event_id, timestamp, writes, |event| {
+                event.scheduled_for = None
+            })
+        })
+    }
+
+    fn move_event(
+        &mut self,
+        id: &QueueId,
+        event_id: EventId,
+        timestamp: u128,
+        writes: &mut Writes,
+        update: impl FnOnce(&mut ScheduledEvent),
+    ) -> MoraResult<EventKey> {
+        let new_key = EventKey::new(timestamp, event_id);
+        let queue = self.get_queue_mut(id)?;
+        if queue.find(event_id) == Some(new_key) {
+            return Ok(new_key);
+        }
+        let (old_key, event) = queue
+            .remove(event_id)
+            .ok_or(MoraError::EventNotFound(event_id.to_string()))?;
+        self.due_events.untrack(id, old_key);
+        let mut moved = event.clone();
+        update(&mut moved);
+
+        // The new record is written before the old one is tombstoned, so a crash in
+        // between can never lose the event.
+        if let Err(e) = self.enqueue_with_key(id, new_key, moved, writes) {
+            self.get_queue_mut(id)?.enqueue(old_key, event)?;
+            self.due_events.track(id, old_key);
+            return Err(e);
+        }
+        writes.delete(id, old_key);
+        Ok(new_key)
+    }
+
+    /// Returns at most `limit` events of the queue due at `timestamp`.
+    /// When `delete` is set the events are considered dispatched: they are removed
+    /// from the queue and, for recurring events, the next occurrence is scheduled.
     pub fn dequeue_until(
         &mut self,
         id: &QueueId,
         timestamp: u128,
         delete: bool,
-    ) -> MoraResult<Vec<(EventId, Bytes)>> {
-        let dequeued = self.get_queue_mut(id)?.dequeue_until(timestamp, delete);
-        let sort_keys = dequeued.iter().map(|pair| pair.0).collect::<Vec<_>>();
+        limit: usize,
+    ) -> MoraResult<PendingWrite<Vec<(EventKey, ScheduledEvent)>>> {
+        let dequeued = self
+            .get_queue_mut(id)?
+            .dequeue_until(timestamp, delete, limit);
+
+        self.change(|pool, writes| {
+            if delete {
+                for (key, _) in &dequeued {
+                    pool.due_events.untrack(id, *key);
+                    writes.delete(id, *key);
+                }
+
+                let now = Clock::now();
+                for (key, event) in &dequeued {
+                    pool.enqueue_next_occurrence(id, key, event, now, writes)?;
+                }
+            }
+            Ok(dequeued)
+        })
+    }
+
+    /// Returns at most `limit` events of the queue due at `timestamp`, following `after`.
+    pub fn peek_until(
+        &self,
+        id: &QueueId,
+        timestamp: u128,
+        after: Option<EventKey>,
+        limit: usize,
+    ) -> MoraResult<Vec<(EventKey, ScheduledEvent)>> {
+        Ok(self.get_queue(id)?.peek_until(timestamp, after, limit))
+    }
+
+    /// Leases at most `limit` events of the queue due at `timestamp` until `lease_until`.
+    /// Leased events are moved to `lease_until`, so they are invisible until the lease
+    /// expires and are delivered again unless they are acknowledged in the meantime.
+    /// Events that exhausted the delivery attempts of the queue dead letter policy are
+    /// moved to the dead letter queue instead.
+    /// Returns the leased keys, needed to `ack` or `nack` the events.
+    pub fn lease_until(
+        &mut self,
+        id: &QueueId,
+        timestamp: u128,
+        lease_until: u128,
+        limit: usize,
+    ) -> MoraResult<PendingWrite<Vec<(EventKey, ScheduledEvent)>>> {
+        let dead_letter_policy = self.get_queue_options(id)?.dead_letter_policy;
+        let dequeued = self
+            .get_queue_mut(id)?
+            .dequeue_until(timestamp, true, limit);
 
-        if delete {
-            self.storage.delete_items(id, &sort_keys)?;
+        self.change(|pool, writes| {
+            for (key, _) in &dequeued {
+                pool.due_events.untrack(id, *key);
+            }
+            let mut leased = Vec::with_capacity(dequeued.len());
+            let mut expired_keys = Vec::with_capacity(dequeued.len());
+            let mut failed = None;
+            let mut dequeued = dequeued.into_iter();
+            while let Some((key, event)) = dequeued.next() {
+                match pool.lease_event(
+                    id,
+                    dead_letter_policy.as_ref(),
+                    key,
+                    event.clone(),
+                    lease_until,
+                    writes,
+                ) {
+                    Ok(Some((leased_key, event))) => {
+                        if leased_key != key {
+                            expired_keys.push(key);
+                        }
+                        leased.push((leased_key, event));
+                    }
+                    Ok(None) => expired_keys.push(key),
+                    Err(e) => {
+                        // The events left are still stored under their key: put them back.
+                        for (key, event) in std::iter::once((key, event)).chain(dequeued) {
+                            pool.get_queue_mut(id)?.enqueue(key, event)?;
+                            pool.due_events.track(id, key);
+                        }
+                        failed = Some(e);
+                        break;
+                    }
+                }
+            }
+            for key in expired_keys {
+                writes.delete(id, key);
+            }
+
+            match failed {
+                Some(e) => Err(e),
+                None => Ok(leased),
+            }
+        })
+    }
+
+    /// Leases the event stored under `key` until `lease_until`, unless it exhausted the
+    /// delivery attempts of `dead_letter_policy`: it is dead-lettered then, and `None`
+    /// is returned. Events whose dead letter queue is missing or full are leased.
+    fn lease_event(
+        &mut self,
+        id: &QueueId,
+        dead_letter_policy: Option<&DeadLetterPolicy>,
+        key: EventKey,
+        mut event: ScheduledEvent,
+        lease_until: u128,
+        writes: &mut Writes,
+    ) -> MoraResult<Option<(EventKey, ScheduledEvent)>> {
+        if let Some(policy) = dead_letter_policy {
+            if event.delivery_attempts >= policy.max_delivery_attempts {
+                match self.get_queue(&policy.dead_letter_queue) {
+                    Ok(queue) if !queue.is_full() => {
+                        self.dead_letter(id, &policy.dead_letter_queue, &key, event, writes)?;
+                        return Ok(None);
+                    }
+                    Ok(_) => warn!(
+                        "dead letter queue {} of {} is full, delivering event again",
+                        policy.dead_letter_queue, id
+                    ),
+                    Err(_) => warn!(
+                        "dead letter queue {} of {} does not exist, delivering event again",
+                        policy.dead_letter_queue, id
+                    ),
+                }
+            }
+        }
+
+        event.scheduled_for.get_or_insert(key.timestamp);
+        event.delivery_attempts = event.delivery_attempts.saturating_add(1);
+        let leased_key = EventKey::new(lease_until, key.id);
+        self.enqueue_with_key(id, leased_key, event.clone(), writes)?;
+        Ok(Some((leased_key, event)))
+    }
+
+    /// Acknowledges a leased event: it is dispatched and, for recurring events, the
+    /// next occurrence is scheduled. Returns `false` when the event is no longer
+    /// stored under `leased_key`, i.e. it was cancelled, rescheduled or leased again.
+    pub fn ack(&mut self, id: &QueueId, leased_key: EventKey) -> MoraResult<PendingWrite<bool>> {
+        let queue = self.get_queue_mut(id)?;
+        if queue.find(leased_key.id) != Some(leased_key) {
+            return self.change(|_, _| Ok(false));
+        }
+        let (key, event) = queue
+            .remove(leased_key.id)
+            .ok_or(MoraError::EventNotFound(leased_key.id.to_string()))?;
+
+        self.change(|pool, writes| {
+            pool.due_events.untrack(id, key);
+            writes.delete(id, key);
+            pool.enqueue_next_occurrence(id, &key, &event, Clock::now(), writes)?;
+            Ok(true)
+        })
+    }
+
+    /// Gives a leased event back, making it due again right away. Returns `false`
+    /// when the event is no longer stored under `leased_key`.
+    pub fn nack(&mut self, id: &QueueId, leased_key: EventKey) -> MoraResult<PendingWrite<bool>> {
+        let leased = self.get_queue(id)?.find(leased_key.id) == Some(leased_key);
+        self.change(|pool, writes| {
+            if leased {
+                pool.move_event(id, leased_key.id, Clock::now(), writes, |_| {})?;
+            }
+            Ok(leased)
+        })
+    }
+
+    /// Moves a single occurrence of an event to `dead_letter_queue`, where it is due
+    /// right away. Recurring events keep recurring in their own queue.
+    fn dead_letter(
+        &mut self,
+        id: &QueueId,
+        dead_letter_queue: &QueueId,
+        key: &EventKey,
+        event: ScheduledEvent,
+        writes: &mut Writes,
+    ) -> MoraResult<()> {
+        let now = Clock::now();
+        let dead_letter_key = EventKey::new(now, self.free_event_id(dead_letter_queue, key.id)?);
+        let dead_lettered = ScheduledEvent {
+            data: event.data.clone(),
+            recurrence: None,
+            scheduled_for: None,
+            delivery_attempts: event.delivery_attempts,
+            dead_lettered_from: Some(id.to_owned()),
+            headers: event.headers.clone(),
+        };
+        self.enqueue_with_key(dead_letter_queue, dead_letter_key, dead_lettered, writes)?;
+        self.enqueue_next_occurrence(id, key, &event, now, writes)
+    }
+
+    /// Moves dead-lettered events of the queue back to the queue they came from, where
+    /// they are due right away with their delivery attempts reset. When `event_ids` is
+    /// empty every dead-lettered event is redriven.
+    /// Returns the keys of the redriven events in their source queue.
+    pub fn redrive(
+        &mut self,
+        id: &QueueId,
+        event_ids: &[EventId],
+    ) -> MoraResult<PendingWrite<Vec<EventKey>>> {
+        let dead_lettered = self
+            .get_queue(id)?
+            .peek_until(u128::MAX, None, usize::MAX)
+            .into_iter()
+            .filter(|(key, event)| {
+                event.dead_lettered_from.is_some()
+                    && (event_ids.is_empty() || event_ids.contains(&key.id))
+            })
+            .collect::<Vec<_>>();
+        if let Some(missing) = event_ids
+            .iter()
+            .find(|event_id| !dead_lettered.iter().any(|(key, _)| key.id == **event_id))
+        {
+            return Err(MoraError::EventNotFound(missing.to_string()));
+        }
+
+        self.change(|pool, writes| {
+            let now = Clock::now();
+            let mut redriven = Vec::with_capacity(dead_lettered.len());
+            for (key, event) in dead_lettered {
+                let Some(source) = event.dead_lettered_from.clone() else {
+                    continue;
+                };
+                if !pool.contains_queue(&source) {
+                    warn!(
+                        "source queue {} of dead-lettered event does not exist",
+                        source
+                    );
+                    continue;
+                }
+
+                let source_key = EventKey::new(now, pool.free_event_id(&source, key.id)?);
+                let event = ScheduledEvent {
+                    delivery_attempts: 0,
+                    dead_lettered_from: None,
+                    ..event
+                };
+                pool.enqueue_with_key(&source, source_key, event, writes)?;
+                pool.get_queue_mut(id)?.remove(key.id);
+                pool.due_events.untrack(id, key);
+                writes.delete(id, key);
+                redriven.push(source_key);
+            }
+            Ok(redriven)
+        })
+    }
+
+    /// `event_id` unless the queue already holds an event with that id, in which
+    /// case a new id is generated.
+    fn free_event_id(&self, id: &QueueId, event_id: EventId) -> MoraResult<EventId> {
+        Ok(match self.get_queue(id)?.find(event_id) {
+            Some(_) => new_event_id(),
+            None => event_id,
+        })
+    }
+
+    fn enqueue_next_occurrence(
+        &mut self,
+        id: &QueueId,
+        key: &EventKey,
+        event: &ScheduledEvent,
+        now: u128,
+        writes: &mut Writes,
+    ) -> MoraResult<()> {
+        let timestamp = event.scheduled_for.unwrap_or(key.timestamp);
+        if let Some((next_timestamp, next_event)) = event.next_occurrence(timestamp, now)? {
+            // Next occurrences keep the event id, so they can be tracked as one event.
+            self.enqueue_with_key(
+                id,
+                EventKey::new(next_timestamp, key.id),
+                next_event,
+                writes,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn new_event_id() -> EventId {
+    uuid::Uuid::new_v4().as_u128()
+}
+
+#[cfg(test)]
+mod tests {
+    use mora_core::models::events::{Recurrence, RecurringOptions};
+    use mora_storage::{
+        memory_storage::MemoryStorage,
+        storage_thread::StorageThread,
+        wal_file_storage::{WalFileStorage, WalFileStorageConfig},
+    };
+
+    use super::*;
+
+    type TestPool = QueuePool<StorageThread<MemoryStorage>>;
+
+    async fn memory_storage() -> MoraResult<StorageThread<MemoryStorage>> {
+        StorageThread::spawn(|| Ok(MemoryStorage::new())).await
+    }
+
+    async fn pool_with_queues(queues: &[&str]) -> MoraResult<TestPool> {
+        let storage = memory_storage().await?;
+        let mut pool = TestPool::from_storage(storage, QueuePoolOptions::default()).await?;
+        for queue in queues {
+            pool.create_queue(queue.to_string(), QueueOptions::default())?
+                .written()
+                .await?;
+        }
+        Ok(pool)
+    }
+
+    fn event(data: &[u8]) -> ScheduledEvent {
+        ScheduledEvent::new(data.to_vec(), None)
+    }
+
+    /// Events of the queue in order, checking that storage holds the same ones.
+    async fn events(
+        pool: &mut TestPool,
+        queue: &QueueId,
+    ) -> MoraResult<Vec<(EventKey, ScheduledEvent)>> {
+        let in_memory = pool.peek_until(queue, u128::MAX, None, usize::MAX)?;
+        let mut stored = pool
+            .storage
+            .get_all_items(queue)
+            .await?
+            .into_iter()
+            .map(|(key, item)| Ok((key, ScheduledEvent::from_bytes(&item)?)))
+            .collect::<MoraResult<Vec<_>>>()?;
+        stored.sort_by_key(|(key, _)| *key);
+        assert_eq!(stored, in_memory);
+        Ok(in_memory)
+    }
+
+    #[tokio::test]
+    async fn cancelled_events_are_removed_from_memory_and_storage() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        let cancelled = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+        let kept = pool
+            .enqueue(&queue, 20, event(b"second"))?
+            .written()
+            .await?;
+
+        assert_eq!(
+            pool.cancel(&queue, cancelled.id)?.written().await?,
+            cancelled
+        );
+        assert_eq!(
+            events(&mut pool, &queue).await?,
+            vec![(kept, event(b"second"))]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_apply_in_the_order_of_the_changes() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        let enqueued = pool.enqueue(&queue, 10, event(b"first"))?;
+        let key = *enqueued.value();
+        let rescheduled = pool.reschedule(&queue, key.id, 20)?;
+        let cancelled = pool.cancel(&queue, key.id)?;
+
+        // Awaiting the writes in another order doesn't reorder them.
+        cancelled.written().await?;
+        rescheduled.written().await?;
+        enqueued.written().await?;
+        assert!(events(&mut pool, &queue).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removed_events_are_untracked() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue", "deleted"]).await?;
+        let queue = "queue".to_string();
+        let now = Clock::now();
+        let cancelled = pool
+            .enqueue(&queue, now + 10, event(b"first"))?
+            .written()
+            .await?;
+        let moved = pool
+            .enqueue(&queue, now + 20, event(b"second"))?
+            .written()
+            .await?;
+        pool.enqueue(&"deleted".to_string(), now + 10, event(b"third"))?
+            .written()
+            .await?;
+
+        pool.cancel(&queue, cancelled.id)?.written().await?;
+        let rescheduled = pool
+            .reschedule(&queue, moved.id, now + 30)?
+            .written()
+            .await?;
+        pool.delete_queue("deleted".to_string())?.written().await?;
+        assert_eq!(
+            pool.due_events.advance(u128::MAX),
+            vec![(Arc::from("queue"), rescheduled)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelling_unknown_events_changes_nothing() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+
+        assert!(matches!(
+            pool.cancel(&queue, key.id + 1),
+            Err(MoraError::EventNotFound(_))
+        ));
+        assert!(matches!(
+            pool.cancel(&"missing".to_string(), key.id),
+            Err(MoraError::QueueNotFound(_))
+        ));
+        assert_eq!(
+            events(&mut pool, &queue).await?,
+            vec![(key, event(b"first"))]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rescheduled_events_keep_their_id() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        let mut leased = event(b"first");
+        leased.scheduled_for = Some(5);
+        let key = pool.enqueue(&queue, 10, leased)?.written().await?;
+
+        let rescheduled = pool.reschedule(&queue, key.id, 30)?.written().await?;
+        assert_eq!(rescheduled, EventKey::new(30, key.id));
+        assert_eq!(
+            events(&mut pool, &queue).await?,
+            vec![(rescheduled, event(b"first"))]
+        );
+
+        // Rescheduling to the same timestamp changes nothing.
+        assert_eq!(
+            pool.reschedule(&queue, key.id, 30)?.written().await?,
+            rescheduled
+        );
+        assert_eq!(
+            events(&mut pool, &queue).await?,
+            vec![(rescheduled, event(b"first"))]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rescheduling_unknown_events_changes_nothing() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+
+        assert!(matches!(
+            pool.reschedule(&queue, key.id + 1, 30),
+            Err(MoraError::EventNotFound(_))
+        ));
+        assert_eq!(
+            events(&mut pool, &queue).await?,
+            vec![(key, event(b"first"))]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_queues_reject_events_before_storing_them() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        pool.queues.insert(queue.clone(), TemporalQueue::new(1));
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+
+        assert!(matches!(
+            pool.enqueue(&queue, 20, event(b"second")),
+            Err(MoraError::QueueFull)
+        ));
+        assert_eq!(
+            events(&mut pool, &queue).await?,
+            vec![(key, event(b"first"))]
+        );
+
+        // Events already in a full queue can still be moved.
+        let rescheduled = pool.reschedule(&queue, key.id, 30)?.written().await?;
+        assert_eq!(
+            events(&mut pool, &queue).await?,
+            vec![(rescheduled, event(b"first"))]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn events_are_enqueued_together_only_if_every_queue_has_room() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["first", "second"]).await?;
+        let (first, second) = ("first".to_string(), "second".to_string());
+        pool.queues.insert(second.clone(), TemporalQueue::new(2));
+        let key = pool.enqueue(&second, 10, event(b"kept"))?.written().await?;
+
+        assert!(matches!(
+            pool.enqueue_all(
+                vec![
+                    (first.clone(), 20, event(b"first")),
+                    (second.clone(), 20, event(b"second")),
+                    (second.clone(), 30, event(b"third")),
+                ],
+                Some("key".to_string()),
+            ),
+            Err(MoraError::QueueFull)
+        ));
+        assert!(events(&mut pool, &first).await?.is_empty());
+        assert_eq!(
+            events(&mut pool, &second).await?,
+            vec![(key, event(b"kept"))]
+        );
+        assert_eq!(pool.get_idempotency_key("key"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys_are_stored_with_their_events() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["first", "second"]).await?;
+        let keys = pool
+            .enqueue_all(
+                vec![
+                    ("first".to_string(), 10, event(b"first")),
+                    ("second".to_string(), 20, event(b"second")),
+                ],
+                Some("key".to_string()),
+            )?
+            .written()
+            .await?;
+
+        let mut pool = TestPool::from_storage(pool.storage, QueuePoolOptions::default()).await?;
+        let ids = keys.iter().map(|key| key.id).collect::<Vec<_>>();
+        assert_eq!(pool.get_idempotency_key("key"), Some(ids));
+        for (queue, key) in ["first", "second"].into_iter().zip(keys) {
+            assert_eq!(
+                events(&mut pool, &queue.to_string()).await?,
+                vec![(key, event(queue.as_bytes()))]
+            );
         }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_enqueue_all_writes_can_be_undone() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["first", "second"]).await?;
+        let (first, second) = ("first".to_string(), "second".to_string());
+        pool.storage.delete_container(&second).await?;
+
+        let enqueued = pool.enqueue_all(
+            vec![
+                (first.clone(), 10, event(b"first")),
+                (second.clone(), 20, event(b"second")),
+            ],
+            Some("key".to_string()),
+        )?;
+        let events_enqueued = [first.clone(), second.clone()]
+            .into_iter()
+            .zip(enqueued.value().clone())
+            .collect::<Vec<_>>();
+        assert!(enqueued.written().await.is_err());
+        // The container of the second queue is still missing, so its delete fails too.
+        let undone = pool.undo_enqueue_all(&events_enqueued, Some("key"))?;
+        assert!(undone.written().await.is_err());
+
+        // The events of the first queue were stored before the write failed.
+        assert!(events(&mut pool, &first).await?.is_empty());
+        assert!(pool.get_queue(&second)?.is_empty());
+        assert_eq!(pool.get_idempotency_key("key"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn undecodable_records_are_skipped_on_load() -> MoraResult<()> {
+        let mut storage = memory_storage().await?;
+        let queue = "queue".to_string();
+        let event = ScheduledEvent::new(b"data".to_vec(), None);
+        let unsupported = vec![0xc1, u8::MAX];
+        storage.create_container(&queue).await?;
+        storage
+            .store_item(&queue, &EventKey::new(1, 1), &event.to_bytes()?)
+            .await?;
+        storage
+            .store_item(&queue, &EventKey::new(2, 2), &unsupported)
+            .await?;
+
+        let mut pool = TestPool::from_storage(storage, QueuePoolOptions::default()).await?;
+        let queue_events = pool.get_queue(&queue)?;
+        assert_eq!(queue_events.find(1), Some(EventKey::new(1, 1)));
+        assert_eq!(queue_events.find(2), None);
+        // The record is kept, for a version that can decode it.
+        let stored = pool.storage.get_all_items(&queue).await?;
+        assert_eq!(stored.get(&EventKey::new(2, 2)), Some(&unsupported));
+        Ok(())
+    }
 
-        Ok(dequeued)
+    fn leased(mut event: ScheduledEvent, scheduled_for: u128, attempts: u32) -> ScheduledEvent {
+        event.scheduled_for = Some(scheduled_for);
+        event.delivery_attempts = attempts;
+        event
+    }
+
+    #[tokio::test]
+    async fn leased_events_are_stored_under_their_lease_expiration() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+        pool.enqueue(&queue, 20, event(b"later"))?.written().await?;
+
+        let leased_key = EventKey::new(100, key.id);
+        let expected = (leased_key, leased(event(b"first"), 10, 1));
+        assert_eq!(
+            pool.lease_until(&queue, 10, 100, 10)?.written().await?,
+            vec![expected.clone()]
+        );
+        assert_eq!(events(&mut pool, &queue).await?[1], expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn acked_events_are_dispatched() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+        let leased = pool.lease_until(&queue, 10, 100, 10)?.written().await?;
+
+        assert!(pool.ack(&queue, leased[0].0)?.written().await?);
+        assert!(events(&mut pool, &queue).await?.is_empty());
+        // Acks of events no longer leased under the key are ignored.
+        assert!(!pool.ack(&queue, leased[0].0)?.written().await?);
+        assert!(!pool.ack(&queue, key)?.written().await?);
+        assert!(matches!(
+            pool.ack(&"missing".to_string(), key),
+            Err(MoraError::QueueNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn acked_recurring_events_get_their_next_occurrence() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        let recurrence = |times| Some(Recurrence::Fixed(RecurringOptions { times, delay: 5 }));
+        let recurring = ScheduledEvent::new(b"data".to_vec(), recurrence(2));
+        let key = pool.enqueue(&queue, 10, recurring)?.written().await?;
+        let leased = pool.lease_until(&queue, 10, 100, 10)?.written().await?;
+
+        // The next occurrence follows the one that was due, not the lease expiration.
+        assert!(pool.ack(&queue, leased[0].0)?.written().await?);
+        assert_eq!(
+            events(&mut pool, &queue).await?,
+            vec![(
+                EventKey::new(15, key.id),
+                ScheduledEvent::new(b"data".to_vec(), recurrence(1))
+            )]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nacked_events_are_due_right_away() -> MoraResult<()> {
+        let mut pool = pool_with_queues(&["queue"]).await?;
+        let queue = "queue".to_string();
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+        let leased_key = pool
+            .lease_until(&queue, 10, u128::MAX, 10)?
+            .written()
+            .await?[0]
+            .0;
+
+        let before = Clock::now();
+        assert!(pool.nack(&queue, leased_key)?.written().await?);
+        let nacked = events(&mut pool, &queue).await?;
+        assert_eq!(nacked.len(), 1);
+        let (nacked_key, nacked_event) = &nacked[0];
+        assert_eq!(nacked_key.id, key.id);
+        assert!((before..=Clock::now()).contains(&nacked_key.timestamp));
+        assert_eq!(nacked_event, &leased(event(b"first"), 10, 1));
+
+        assert!(!pool.nack(&queue, leased_key)?.written().await?);
+        assert_eq!(events(&mut pool, &queue).await?, nacked);
+        Ok(())
+    }
+
+    async fn wal_pool(
+        dir: &std::path::Path,
+    ) -> MoraResult<QueuePool<StorageThread<WalFileStorage>>> {
+        let config = WalFileStorageConfig::new(dir.to_string_lossy().to_string());
+        let storage = StorageThread::spawn(move || WalFileStorage::open(config)).await?;
+        QueuePool::from_storage(storage, QueuePoolOptions::default()).await
+    }
+
+    #[tokio::test]
+    async fn leased_events_are_delivered_again_after_a_restart() -> MoraResult<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = "queue".to_string();
+        let mut pool = wal_pool(dir.path()).await?;
+        pool.create_queue(queue.clone(), QueueOptions::default())?
+            .written()
+            .await?;
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+        pool.lease_until(&queue, 10, 50, 10)?.written().await?;
+        pool.durability_ticket().await?;
+        drop(pool);
+
+        // The lease isn't known anymore, but its expiration is: the event stays
+        // invisible until then and is delivered again afterwards.
+        let mut pool = wal_pool(dir.path()).await?;
+        assert!(pool
+            .lease_until(&queue, 40, 100, 10)?
+            .written()
+            .await?
+            .is_empty());
+        assert_eq!(
+            pool.lease_until(&queue, 50, 100, 10)?.written().await?,
+            vec![(EventKey::new(100, key.id), leased(event(b"first"), 10, 2))]
+        );
+        Ok(())
+    }
+
+    /// A pool whose `queue` dead-letters events to `dlq` after a single delivery.
+    async fn pool_with_dead_letter_queue() -> MoraResult<TestPool> {
+        let mut pool = pool_with_queues(&["dlq"]).await?;
+        let options = QueueOptions {
+            dead_letter_policy: Some(DeadLetterPolicy {
+                max_delivery_attempts: 1,
+                dead_letter_queue: "dlq".to_string(),
+            }),
+        };
+        pool.create_queue("queue".to_string(), options)?
+            .written()
+            .await?;
+        Ok(pool)
+    }
+
+    fn dead_lettered(data: &[u8], attempts: u32) -> ScheduledEvent {
+        let mut event = event(data);
+        event.delivery_attempts = attempts;
+        event.dead_lettered_from = Some("queue".to_string());
+        event
+    }
+
+    #[tokio::test]
+    async fn exhausted_events_are_dead_lettered() -> MoraResult<()> {
+        let mut pool = pool_with_dead_letter_queue().await?;
+        let (queue, dlq) = ("queue".to_string(), "dlq".to_string());
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+        pool.lease_until(&queue, 10, 20, 10)?.written().await?;
+
+        let before = Clock::now();
+        assert!(pool
+            .lease_until(&queue, 20, 30, 10)?
+            .written()
+            .await?
+            .is_empty());
+        assert!(events(&mut pool, &queue).await?.is_empty());
+        let dead = events(&mut pool, &dlq).await?;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].0.id, key.id);
+        assert!(dead[0].0.timestamp >= before);
+        assert_eq!(dead[0].1, dead_lettered(b"first", 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn events_are_delivered_again_when_the_dead_letter_queue_is_full() -> MoraResult<()> {
+        let mut pool = pool_with_dead_letter_queue().await?;
+        let (queue, dlq) = ("queue".to_string(), "dlq".to_string());
+        pool.queues.insert(dlq.clone(), TemporalQueue::new(0));
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+        pool.lease_until(&queue, 10, 20, 10)?.written().await?;
+
+        let expected = (EventKey::new(30, key.id), leased(event(b"first"), 10, 2));
+        assert_eq!(
+            pool.lease_until(&queue, 20, 30, 10)?.written().await?,
+            vec![expected.clone()]
+        );
+        assert_eq!(events(&mut pool, &queue).await?, vec![expected]);
+        assert!(events(&mut pool, &dlq).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redriven_events_go_back_to_their_queue() -> MoraResult<()> {
+        let mut pool = pool_with_dead_letter_queue().await?;
+        let (queue, dlq) = ("queue".to_string(), "dlq".to_string());
+        let key = pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+        pool.lease_until(&queue, 10, 20, 10)?.written().await?;
+        pool.lease_until(&queue, 20, 30, 10)?.written().await?;
+
+        assert!(matches!(
+            pool.redrive(&dlq, &[key.id + 1]),
+            Err(MoraError::EventNotFound(_))
+        ));
+        assert_eq!(events(&mut pool, &dlq).await?.len(), 1);
+
+        let redriven = pool.redrive(&dlq, &[key.id])?.written().await?;
+        assert_eq!(redriven.len(), 1);
+        assert_eq!(redriven[0].id, key.id);
+        assert_eq!(
+            events(&mut pool, &queue).await?,
+            vec![(redriven[0], event(b"first"))]
+        );
+        assert!(events(&mut pool, &dlq).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redriving_to_a_full_queue_keeps_the_dead_lettered_events() -> MoraResult<()> {
+        let mut pool = pool_with_dead_letter_queue().await?;
+        let (queue, dlq) = ("queue".to_string(), "dlq".to_string());
+        pool.enqueue(&queue, 10, event(b"first"))?.written().await?;
+        pool.lease_until(&queue, 10, 20, 10)?.written().await?;
+        pool.lease_until(&queue, 20, 30, 10)?.written().await?;
+        pool.queues.insert(queue.clone(), TemporalQueue::new(0));
+
+        let dead = events(&mut pool, &dlq).await?;
+        assert!(matches!(pool.redrive(&dlq, &[]), Err(MoraError::QueueFull)));
+        assert_eq!(events(&mut pool, &dlq).await?, dead);
+        assert!(events(&mut pool, &queue).await?.is_empty());
+        Ok(())
     }
 }
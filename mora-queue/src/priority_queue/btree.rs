@@ -0,0 +1,68 @@
+use std::{collections::BTreeMap, ops::Bound};
+
+use super::PriorityQueue;
+
+/// Priority queue backed by a B-tree: `O(log n)` enqueue, peek and removal by key,
+/// `O(log n + k)` iteration over `k` items from any key.
+///
+/// Keys are unique: enqueuing an existing key replaces its value.
+#[derive(Debug, Clone)]
+pub struct BTreePriorityQueue<K, V> {
+    items: BTreeMap<K, V>,
+}
+
+impl<K, V> Default for BTreePriorityQueue<K, V> {
+    fn default() -> Self {
+        Self {
+            items: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Ord, V> Iterator for BTreePriorityQueue<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.items.pop_first()
+    }
+}
+
+impl<K: Clone + Ord, V: Clone> PriorityQueue<K, V> for BTreePriorityQueue<K, V> {
+    fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn enqueue(&mut self, key: K, value: V) -> Option<V> {
+        self.items.insert(key, value)
+    }
+
+    fn dequeue(&mut self, count: usize) -> Vec<V> {
+        self.by_ref().take(count).map(|(_, value)| value).collect()
+    }
+
+    fn peek(&self) -> Option<(K, V)> {
+        self.items
+            .first_key_value()
+            .map(|(key, value)| (key.clone(), value.clone()))
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.items.remove(key)
+    }
+
+    fn iter_after<'a>(&'a self, after: Option<&K>) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        let from = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        self.items.range((from, Bound::Unbounded))
+    }
+}
@@ -87,4 +87,22 @@ where
             .map(|k| self.map.get_key_value(&k).unwrap())
             .map(|kv| (kv.0.clone(), kv.1.clone()))
     }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.map.remove(key)
+    }
+
+    fn iter_after<'a>(&'a self, after: Option<&K>) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        let mut items = self
+            .map
+            .iter()
+            .filter(|(k, _)| after.is_none_or(|after| *k > after))
+            .collect::<Vec<_>>();
+        items.sort_by(|a, b| a.0.cmp(b.0));
+        items.into_iter()
+    }
 }
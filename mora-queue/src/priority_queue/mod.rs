@@ -1,13 +1,22 @@
+pub mod btree;
+#[cfg(test)]
 pub mod dumb;
 pub mod naive;
 
-// WARNING: implementations right now are virtually shit, they should be refactored to use a more efficient data structure
+// `btree` is the implementation used by queues, `naive` is kept as a baseline for tests and benches.
 pub trait PriorityQueue<K, V>: Iterator<Item = (K, V)> {
     fn is_empty(&self) -> bool;
     fn len(&self) -> usize;
     fn enqueue(&mut self, key: K, value: V) -> Option<V>;
     fn dequeue(&mut self, count: usize) -> Vec<V>;
     fn peek(&self) -> Option<(K, V)>;
+    fn remove(&mut self, key: &K) -> Option<V>;
+    /// Iterates in order over the items whose key follows `after`, or over all of
+    /// them when `after` is `None`, without removing them.
+    fn iter_after<'a>(&'a self, after: Option<&K>) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a;
 }
 
 #[cfg(test)]
@@ -68,6 +77,31 @@ macro_rules! priority_queue_tests {
             let value= pq.peek();
             assert_eq!(value, None);
         }
+
+        #[test]
+        fn remove_takes_out_only_the_given_key(){
+            let mut pq = <$type>::default();
+            pq.enqueue(3, 3);
+            pq.enqueue(1, 1);
+            pq.enqueue(2, 2);
+            assert_eq!(pq.remove(&2), Some(2));
+            assert_eq!(pq.remove(&2), None);
+            assert_eq!(pq.len(), 2);
+            assert_eq!(pq.dequeue(2), [1, 3]);
+        }
+
+        #[test]
+        fn iter_after_skips_keys_up_to_the_given_one(){
+            let mut pq = <$type>::default();
+            pq.enqueue(3, 30);
+            pq.enqueue(1, 10);
+            pq.enqueue(2, 20);
+            let all: Vec<(u32, u32)> = pq.iter_after(None).map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(all, [(1, 10), (2, 20), (3, 30)]);
+            let after: Vec<(u32, u32)> = pq.iter_after(Some(&1)).map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(after, [(2, 20), (3, 30)]);
+            assert_eq!(pq.len(), 3);
+        }
     }
     )*
     }
@@ -78,5 +112,6 @@ mod tests {
     priority_queue_tests! {
         dumb_priority_queue: super::super::dumb::DumbPriorityQueue::<u32,u32>,
         naive_priority_queue: super::super::naive::NaivePriorityQueue<u32,u32>,
+        btree_priority_queue: super::super::btree::BTreePriorityQueue<u32,u32>,
     }
 }
@@ -95,6 +95,22 @@ impl<K: Clone + Ord, V: Clone> PriorityQueue<K, V> for NaivePriorityQueue<K, V>
     }
 
     fn peek(&self) -> Option<(K, V)> {
-        self.items.get(0).map(|n| (n.key.clone(), n.value.clone()))
+        self.items.front().map(|n| (n.key.clone(), n.value.clone()))
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.items.iter().position(|n| &n.key == key)?;
+        self.items.remove(index).map(|n| n.value)
+    }
+
+    fn iter_after<'a>(&'a self, after: Option<&K>) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        self.items
+            .iter()
+            .skip_while(move |n| after.is_some_and(|after| &n.key <= after))
+            .map(|n| (&n.key, &n.value))
     }
 }
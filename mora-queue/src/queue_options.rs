@@ -0,0 +1,46 @@
+use mora_core::{
+    models::queues::QueueOptions,
+    result::{MoraError, MoraResult},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::pool::{Bytes, QueueId};
+
+/// Options of a queue, as persisted in storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueOptionsRecord {
+    pub queue: QueueId,
+    pub options: QueueOptions,
+}
+
+impl QueueOptionsRecord {
+    pub fn to_bytes(&self) -> MoraResult<Bytes> {
+        rmp_serde::to_vec(self).map_err(|e| MoraError::SerializationError(e.to_string()))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> MoraResult<Self> {
+        rmp_serde::from_slice(bytes).map_err(|e| MoraError::SerializationError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mora_core::models::queues::DeadLetterPolicy;
+
+    use super::*;
+
+    #[test]
+    fn queue_options_record_roundtrips_through_bytes() -> MoraResult<()> {
+        let record = QueueOptionsRecord {
+            queue: "orders".to_string(),
+            options: QueueOptions {
+                dead_letter_policy: Some(DeadLetterPolicy {
+                    max_delivery_attempts: 5,
+                    dead_letter_queue: "orders_dlq".to_string(),
+                }),
+            },
+        };
+        assert_eq!(QueueOptionsRecord::from_bytes(&record.to_bytes()?)?, record);
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use std::{collections::HashSet, sync::Arc};
+
+use log::debug;
+use mora_core::{
+    clock::Clock, models::events::EventKey, result::MoraResult, traits::storage::AsyncStorage,
+};
+use tokio::{
+    sync::{Mutex, Notify},
+    time::{sleep, Duration},
+};
+
+use crate::{
+    pool::{Bytes, QueueId, QueuePool},
+    timing_wheel::TimingWheel,
+};
+
+/// Due times are tracked with millisecond precision.
+const RESOLUTION: u128 = 1_000_000;
+
+/// When the events of every queue of a pool become due.
+///
+/// Every stored event is tracked, including leased ones, which become due again
+/// when their lease expires. Events are untracked once cancelled, moved or
+/// dispatched; those that were due already when removed are recognized and skipped
+/// by `QueuePool::advance_due_events`. Queue ids are interned, so tracking an event
+/// never copies its queue id.
+#[derive(Debug)]
+pub(crate) struct DueEvents {
+    wheel: TimingWheel<(Arc<str>, EventKey)>,
+    queue_ids: HashSet<Arc<str>>,
+    wakeup: Arc<Notify>,
+}
+
+impl Default for DueEvents {
+    fn default() -> Self {
+        Self {
+            wheel: TimingWheel::new(RESOLUTION, Clock::now()),
+            queue_ids: HashSet::default(),
+            wakeup: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl DueEvents {
+    pub fn track(&mut self, queue: &str, key: EventKey) {
+        // The scheduler only needs waking when it is sleeping past the new due time.
+        if self
+            .wheel
+            .next_expiration()
+            .is_none_or(|next| key.timestamp < next)
+        {
+            self.wakeup.notify_one();
+        }
+        let queue = match self.queue_ids.get(queue) {
+            Some(queue) => queue.clone(),
+            None => {
+                let queue: Arc<str> = Arc::from(queue);
+                self.queue_ids.insert(queue.clone());
+                queue
+            }
+        };
+        self.wheel.insert(key.timestamp, (queue, key));
+    }
+
+    pub fn untrack(&mut self, queue: &str, key: EventKey) {
+        if let Some(queue) = self.queue_ids.get(queue).cloned() {
+            self.wheel.remove(key.timestamp, &(queue, key));
+        }
+    }
+
+    /// Forgets the id of a deleted queue. Its events still tracked are skipped once due.
+    pub fn forget_queue(&mut self, queue: &str) {
+        self.queue_ids.remove(queue);
+    }
+
+    pub fn advance(&mut self, now: u128) -> Vec<(Arc<str>, EventKey)> {
+        self.wheel.advance(now)
+    }
+
+    pub fn next_expiration(&self) -> Option<u128> {
+        self.wheel.next_expiration()
+    }
+
+    pub fn wakeup(&self) -> Arc<Notify> {
+        self.wakeup.clone()
+    }
+}
+
+/// Central task detecting when events become due across all the queues of a pool.
+///
+/// It sleeps until the next due time and wakes `event_notifier` waiters once events
+/// are due, so consumers never need to poll the queues themselves.
+pub struct Scheduler<T: AsyncStorage<ContainerId = QueueId, SortKey = EventKey, Item = Bytes>> {
+    queue_pool: Arc<Mutex<QueuePool<T>>>,
+    event_notifier: Arc<Notify>,
+}
+
+impl<T: AsyncStorage<ContainerId = QueueId, SortKey = EventKey, Item = Bytes>> Scheduler<T> {
+    pub fn new(queue_pool: Arc<Mutex<QueuePool<T>>>, event_notifier: Arc<Notify>) -> Self {
+        Self {
+            queue_pool,
+            event_notifier,
+        }
+    }
+
+    pub async fn run(self) -> MoraResult<()> {
+        let wakeup = self.queue_pool.lock().await.due_events_wakeup();
+        loop {
+            let next_expiration = {
+                let mut queue_pool = self.queue_pool.lock().await;
+                let due = queue_pool.advance_due_events(Clock::now());
+                if !due.is_empty() {
+                    debug!("{} events became due", due.len());
+                    self.event_notifier.notify_waiters();
+                }
+                queue_pool.next_due_event_at()
+            };
+
+            match next_expiration {
+                Some(next) => {
+                    let sleep_for = next.saturating_sub(Clock::now());
+                    tokio::select! {
+                        _ = wakeup.notified() => {}
+                        _ = sleep(Duration::from_nanos(
+                            u64::try_from(sleep_for).unwrap_or(u64::MAX),
+                        )) => {}
+                    }
+                }
+                None => wakeup.notified().await,
+            }
+        }
+    }
+}
@@ -1,12 +1,15 @@
-use mora_core::result::MoraError;
+use std::collections::HashMap;
 
-use crate::priority_queue::{naive::NaivePriorityQueue, PriorityQueue};
+use mora_core::{models::events::EventKey, result::MoraError};
+
+use crate::priority_queue::{btree::BTreePriorityQueue, PriorityQueue};
 
 #[derive(Debug, Clone)]
 pub struct TemporalQueue<V> {
-    inner: NaivePriorityQueue<u128, V>,
+    inner: BTreePriorityQueue<EventKey, V>,
+    // event id -> timestamp, to locate events by id
+    index: HashMap<u128, u128>,
     capacity: u128,
-    pub len: u128,
 }
 
 impl<V> Default for TemporalQueue<V>
@@ -16,7 +19,7 @@ where
     fn default() -> Self {
         Self {
             inner: Default::default(),
-            len: 0,
+            index: Default::default(),
             capacity: u128::MAX,
         }
     }
@@ -39,38 +42,102 @@ where
     V: Clone,
 {
     pub fn is_empty(&self) -> bool {
-        self.len == 0
-    }
-
-    /// Enqueues a value
-    pub(crate) fn enqueue(&mut self, timestamp: u128, value: V) -> Result<(), MoraError> {
-        match self.capacity {
-            n if self.len == n => Err(MoraError::QueueFull),
-            _ => {
-                self.inner.enqueue(timestamp, value);
-                self.len += 1;
-                Ok(())
-            }
+        self.inner.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns the earliest event in the queue without removing it.
+    pub fn peek(&self) -> Option<(EventKey, V)> {
+        self.inner.peek()
+    }
+
+    /// Whether the queue holds as many events as its capacity.
+    pub fn is_full(&self) -> bool {
+        self.inner.len() as u128 >= self.capacity
+    }
+
+    /// Whether `count` events not in the queue yet can be enqueued.
+    pub fn has_room_for_new(&self, count: usize) -> bool {
+        (self.inner.len() as u128).saturating_add(count as u128) <= self.capacity
+    }
+
+    /// Whether `key` can be enqueued: the queue has room for another event, or
+    /// already holds the event with its id.
+    pub fn has_room_for(&self, key: &EventKey) -> bool {
+        !self.is_full() || self.index.contains_key(&key.id)
+    }
+
+    /// Enqueues a value. An event is queued once: enqueueing the id of a queued event
+    /// replaces it, moving it to the new timestamp.
+    pub fn enqueue(&mut self, key: EventKey, value: V) -> Result<(), MoraError> {
+        if !self.has_room_for(&key) {
+            return Err(MoraError::QueueFull);
         }
+        if let Some(previous) = self.find(key.id).filter(|previous| *previous != key) {
+            self.inner.remove(&previous);
+        }
+        self.inner.enqueue(key, value);
+        self.index.insert(key.id, key.timestamp);
+        Ok(())
     }
 
-    pub fn dequeue_until(&mut self, timestamp: u128, delete: bool) -> Vec<(u128, V)> {
-        //todo: improve here using apposite data structure
-        let mut values: Vec<(u128, V)> = vec![];
-        // this is really bad
-        self.inner
-            .clone()
-            .take_while(|(k, _)| *k <= timestamp)
-            .for_each(|pair| {
-                if delete {
-                    self.len -= 1;
-                    self.inner.dequeue(1);
-                }
-                values.push(pair);
-            });
+    /// Looks up the key of the event with the given id.
+    pub fn find(&self, id: u128) -> Option<EventKey> {
+        self.index
+            .get(&id)
+            .map(|timestamp| EventKey::new(*timestamp, id))
+    }
 
+    /// Removes the event with the given id, returning it with its key.
+    pub(crate) fn remove(&mut self, id: u128) -> Option<(EventKey, V)> {
+        let key = self.find(id)?;
+        let value = self.inner.remove(&key)?;
+        self.index.remove(&id);
+        Some((key, value))
+    }
+
+    /// Returns at most `limit` events due at `timestamp`, removing them when `delete`
+    /// is set.
+    pub fn dequeue_until(
+        &mut self,
+        timestamp: u128,
+        delete: bool,
+        limit: usize,
+    ) -> Vec<(EventKey, V)> {
+        if !delete {
+            return self.peek_until(timestamp, None, limit);
+        }
+
+        let mut values: Vec<(EventKey, V)> = vec![];
+        while values.len() < limit {
+            let key = match self.inner.iter_after(None).next() {
+                Some((k, _)) if k.timestamp <= timestamp => *k,
+                _ => break,
+            };
+            self.index.remove(&key.id);
+            values.extend(self.inner.dequeue(1).into_iter().map(|v| (key, v)));
+        }
         values
     }
+
+    /// Returns at most `limit` events due at `timestamp` without removing them,
+    /// skipping the events up to `after` included.
+    pub fn peek_until(
+        &self,
+        timestamp: u128,
+        after: Option<EventKey>,
+        limit: usize,
+    ) -> Vec<(EventKey, V)> {
+        self.inner
+            .iter_after(after.as_ref())
+            .take_while(|(k, _)| k.timestamp <= timestamp)
+            .take(limit)
+            .map(|(k, v)| (*k, v.clone()))
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -84,13 +151,17 @@ mod tests {
         assert!(TemporalQueue::<i32>::default().is_empty())
     }
 
+    fn key(timestamp: u128) -> EventKey {
+        EventKey::new(timestamp, timestamp)
+    }
+
     #[test]
     fn temporal_queue_should_enqueue_items_correctly() -> MoraResult<()> {
         let mut tq = TemporalQueue::<i32>::default();
-        tq.enqueue(3, 3)?;
-        tq.enqueue(4, 4)?;
-        tq.enqueue(2, 2)?;
-        tq.enqueue(1, 1)?;
+        tq.enqueue(key(3), 3)?;
+        tq.enqueue(key(4), 4)?;
+        tq.enqueue(key(2), 2)?;
+        tq.enqueue(key(1), 1)?;
         assert_eq!(tq.inner.dequeue(4), vec![1, 2, 3, 4]);
         Ok(())
     }
@@ -98,13 +169,90 @@ mod tests {
     #[test]
     fn temporal_queue_dequeue_until_dequeues_until_given_timestamp() -> MoraResult<()> {
         let mut tq = TemporalQueue::<i32>::default();
-        tq.enqueue(1, 1)?;
-        tq.enqueue(2, 2)?;
-        tq.enqueue(3, 3)?;
-        tq.enqueue(4, 4)?;
-        let result = tq.dequeue_until(2, true);
-        assert_eq!(result, vec![(1, 1), (2, 2)]);
-        assert_eq!(tq.len, 2);
+        tq.enqueue(key(1), 1)?;
+        tq.enqueue(key(2), 2)?;
+        tq.enqueue(key(3), 3)?;
+        tq.enqueue(key(4), 4)?;
+        let result = tq.dequeue_until(2, true, usize::MAX);
+        assert_eq!(result, vec![(key(1), 1), (key(2), 2)]);
+        assert_eq!(tq.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn temporal_queue_keeps_events_scheduled_for_the_same_timestamp() -> MoraResult<()> {
+        let mut tq = TemporalQueue::<i32>::default();
+        tq.enqueue(EventKey::new(1, 2), 2)?;
+        tq.enqueue(EventKey::new(1, 1), 1)?;
+        tq.enqueue(EventKey::new(2, 0), 3)?;
+        let result = tq.dequeue_until(1, true, usize::MAX);
+        assert_eq!(
+            result,
+            vec![(EventKey::new(1, 1), 1), (EventKey::new(1, 2), 2)]
+        );
+        assert_eq!(tq.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn temporal_queue_removes_events_by_id() -> MoraResult<()> {
+        let mut tq = TemporalQueue::<i32>::default();
+        tq.enqueue(key(1), 1)?;
+        tq.enqueue(key(2), 2)?;
+        assert_eq!(tq.find(2), Some(key(2)));
+        assert_eq!(tq.remove(2), Some((key(2), 2)));
+        assert_eq!(tq.remove(2), None);
+        assert_eq!(tq.find(2), None);
+        assert_eq!(tq.len(), 1);
+        assert_eq!(tq.dequeue_until(10, true, usize::MAX), vec![(key(1), 1)]);
+        assert_eq!(tq.find(1), None);
+        Ok(())
+    }
+
+    #[test]
+    fn temporal_queue_dequeue_until_honors_limit() -> MoraResult<()> {
+        let mut tq = TemporalQueue::<i32>::default();
+        tq.enqueue(key(1), 1)?;
+        tq.enqueue(key(2), 2)?;
+        tq.enqueue(key(3), 3)?;
+        assert_eq!(tq.dequeue_until(3, true, 2), vec![(key(1), 1), (key(2), 2)]);
+        assert_eq!(tq.len(), 1);
+        assert_eq!(tq.find(3), Some(key(3)));
+        Ok(())
+    }
+
+    #[test]
+    fn temporal_queue_peek_until_resumes_after_key() -> MoraResult<()> {
+        let mut tq = TemporalQueue::<i32>::default();
+        tq.enqueue(key(1), 1)?;
+        tq.enqueue(key(2), 2)?;
+        tq.enqueue(key(3), 3)?;
+        tq.enqueue(key(4), 4)?;
+        assert_eq!(tq.peek_until(3, None, 2), vec![(key(1), 1), (key(2), 2)]);
+        assert_eq!(tq.peek_until(3, Some(key(2)), 2), vec![(key(3), 3)]);
+        assert_eq!(tq.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn temporal_queue_enqueues_each_event_once() -> MoraResult<()> {
+        let mut tq = TemporalQueue::<i32>::new(2);
+        tq.enqueue(key(1), 1)?;
+        tq.enqueue(key(2), 2)?;
+        // Replacing an event doesn't take room.
+        tq.enqueue(key(2), 20)?;
+        assert_eq!(tq.len(), 2);
+        assert!(matches!(tq.enqueue(key(3), 3), Err(MoraError::QueueFull)));
+
+        // Enqueueing a queued id moves the event.
+        tq.enqueue(EventKey::new(5, 1), 10)?;
+        assert_eq!(tq.len(), 2);
+        assert_eq!(tq.find(1), Some(EventKey::new(5, 1)));
+        assert_eq!(
+            tq.dequeue_until(10, true, usize::MAX),
+            vec![(key(2), 20), (EventKey::new(5, 1), 10)]
+        );
+        assert!(tq.is_empty());
         Ok(())
     }
 }
@@ -0,0 +1,236 @@
+use std::{collections::HashMap, hash::Hash};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+// 11 levels of 6 bits cover every `u64` tick, so no deadline ever overflows the wheel.
+const LEVELS: usize = 11;
+
+/// Hierarchical timing wheel firing items at their deadline, in nanoseconds.
+///
+/// Deadlines are rounded up to `resolution`. Level `n` has 64 slots spanning
+/// `64^n` ticks each: items are placed on the lowest level whose range covers their
+/// deadline and cascade down to finer levels as time advances, so inserting is
+/// `O(1)` and advancing costs the fired items plus at most one cascade per level,
+/// however many far-future items are parked. Removing an item looks up one slot
+/// per level.
+#[derive(Debug)]
+pub(crate) struct TimingWheel<T> {
+    resolution: u128,
+    /// Ticks elapsed since the epoch.
+    elapsed: u64,
+    levels: Vec<Level<T>>,
+}
+
+#[derive(Debug)]
+struct Level<T> {
+    /// Bit `n` is set when slot `n` holds items.
+    occupied: u64,
+    /// Items of each slot with their deadline tick.
+    slots: Vec<HashMap<T, u64>>,
+}
+
+impl<T> Level<T> {
+    fn new() -> Self {
+        Self {
+            occupied: 0,
+            slots: (0..SLOTS).map(|_| HashMap::new()).collect(),
+        }
+    }
+}
+
+impl<T: Eq + Hash> TimingWheel<T> {
+    pub fn new(resolution: u128, now: u128) -> Self {
+        Self {
+            resolution,
+            elapsed: u64::try_from(now / resolution).unwrap_or(u64::MAX),
+            levels: (0..LEVELS).map(|_| Level::new()).collect(),
+        }
+    }
+
+    /// Adds `item`, fired by the first `advance` reaching `deadline`. Items whose
+    /// deadline already passed are fired by the next `advance`.
+    pub fn insert(&mut self, deadline: u128, item: T) {
+        let tick = self.tick(deadline);
+        self.place(tick.max(self.elapsed), item);
+    }
+
+    /// Removes `item`, inserted with `deadline`, so it never fires. Items whose
+    /// deadline had already passed when inserted aren't found: they fire with the
+    /// next `advance`. Returns whether the item was removed.
+    pub fn remove(&mut self, deadline: u128, item: &T) -> bool {
+        let tick = self.tick(deadline);
+        if tick < self.elapsed {
+            return false;
+        }
+        // Items only cascade down, always to the slot covering their tick.
+        self.levels.iter_mut().enumerate().any(|(level, slots)| {
+            let slot = ((tick >> (level as u32 * SLOT_BITS)) as usize) & (SLOTS - 1);
+            if slots.slots[slot].remove(item).is_none() {
+                return false;
+            }
+            if slots.slots[slot].is_empty() {
+                slots.occupied &= !(1 << slot);
+            }
+            true
+        })
+    }
+
+    fn tick(&self, deadline: u128) -> u64 {
+        u64::try_from(deadline.div_ceil(self.resolution)).unwrap_or(u64::MAX)
+    }
+
+    /// Moves the wheel to `now`, returning the items whose deadline was reached,
+    /// in deadline order.
+    pub fn advance(&mut self, now: u128) -> Vec<T> {
+        let now = u64::try_from(now / self.resolution).unwrap_or(u64::MAX);
+        let mut fired = vec![];
+        while let Some((level, slot, deadline)) = self.next_slot() {
+            if deadline > now {
+                break;
+            }
+            self.elapsed = deadline;
+            self.levels[level].occupied &= !(1 << slot);
+            for (item, tick) in std::mem::take(&mut self.levels[level].slots[slot]) {
+                if tick <= self.elapsed {
+                    fired.push(item);
+                } else {
+                    self.place(tick, item);
+                }
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+        fired
+    }
+
+    /// Earliest time `advance` may have something to do: the deadline of the next
+    /// items to fire, or earlier when far-future items need to cascade first.
+    pub fn next_expiration(&self) -> Option<u128> {
+        self.next_slot()
+            .map(|(_, _, deadline)| u128::from(deadline) * self.resolution)
+    }
+
+    fn place(&mut self, tick: u64, item: T) {
+        let significant_bit = 63 - ((self.elapsed ^ tick) | (SLOTS as u64 - 1)).leading_zeros();
+        let level = (significant_bit / SLOT_BITS) as usize;
+        let slot = ((tick >> (level as u32 * SLOT_BITS)) as usize) & (SLOTS - 1);
+        self.levels[level].occupied |= 1 << slot;
+        self.levels[level].slots[slot].insert(item, tick);
+    }
+
+    /// Level, slot and starting tick of the next occupied slot. Lower levels always
+    /// expire before higher ones.
+    fn next_slot(&self) -> Option<(usize, usize, u64)> {
+        self.levels.iter().enumerate().find_map(|(level, slots)| {
+            if slots.occupied == 0 {
+                return None;
+            }
+            let shift = level as u32 * SLOT_BITS;
+            let current_slot = ((self.elapsed >> shift) as usize) & (SLOTS - 1);
+            let distance = slots
+                .occupied
+                .rotate_right(current_slot as u32)
+                .trailing_zeros();
+            let slot = (current_slot + distance as usize) & (SLOTS - 1);
+
+            let level_range = 1_u128 << (shift + SLOT_BITS);
+            let level_start = u128::from(self.elapsed) & !(level_range - 1);
+            let deadline = level_start + ((slot as u128) << shift);
+            Some((level, slot, u64::try_from(deadline).unwrap_or(u64::MAX)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSEC: u128 = 1_000_000;
+    const START: u128 = 1_700_000_000_000 * MSEC;
+
+    #[test]
+    fn empty_wheel_has_nothing_to_fire() {
+        let mut wheel = TimingWheel::<u32>::new(MSEC, START);
+        assert_eq!(wheel.next_expiration(), None);
+        assert!(wheel.advance(START + 1000 * MSEC).is_empty());
+    }
+
+    #[test]
+    fn items_fire_once_their_deadline_is_reached() {
+        let mut wheel = TimingWheel::new(MSEC, START);
+        wheel.insert(START + 10 * MSEC, 2);
+        wheel.insert(START + 5 * MSEC, 1);
+        wheel.insert(START + 70 * MSEC, 3);
+        assert_eq!(wheel.next_expiration(), Some(START + 5 * MSEC));
+
+        assert!(wheel.advance(START + 4 * MSEC).is_empty());
+        assert_eq!(wheel.advance(START + 10 * MSEC), vec![1, 2]);
+        assert_eq!(wheel.advance(START + 100 * MSEC), vec![3]);
+        assert_eq!(wheel.next_expiration(), None);
+    }
+
+    #[test]
+    fn deadlines_are_rounded_up_to_the_resolution() {
+        let mut wheel = TimingWheel::new(MSEC, START);
+        wheel.insert(START + MSEC + 1, 1);
+        assert!(wheel.advance(START + MSEC + 1).is_empty());
+        assert_eq!(wheel.advance(START + 2 * MSEC), vec![1]);
+    }
+
+    #[test]
+    fn past_deadlines_fire_on_next_advance() {
+        let mut wheel = TimingWheel::new(MSEC, START);
+        wheel.insert(START - 1000 * MSEC, 1);
+        assert_eq!(wheel.next_expiration(), Some(START));
+        assert_eq!(wheel.advance(START), vec![1]);
+    }
+
+    #[test]
+    fn far_future_items_cascade_down_and_fire_in_order() {
+        let mut wheel = TimingWheel::new(MSEC, START);
+        let day = 24 * 3600 * 1000 * MSEC;
+        let deadlines = [365 * day, day + 3 * MSEC, 7 * MSEC, day, 30 * day + 1];
+        for (n, deadline) in deadlines.iter().enumerate() {
+            wheel.insert(START + deadline, n);
+        }
+
+        let mut fired = vec![];
+        let mut now = START;
+        while let Some(next) = wheel.next_expiration() {
+            assert!(next >= now);
+            now = next;
+            fired.extend(wheel.advance(now));
+        }
+        assert_eq!(fired, vec![2, 3, 1, 4, 0]);
+    }
+
+    #[test]
+    fn furthest_deadline_is_reachable() {
+        let mut wheel = TimingWheel::new(MSEC, START);
+        wheel.insert(u128::MAX, 1);
+        assert!(wheel.advance(START + 1000 * MSEC).is_empty());
+        assert_eq!(wheel.advance(u128::MAX), vec![1]);
+    }
+
+    #[test]
+    fn removed_items_never_fire() {
+        let mut wheel = TimingWheel::new(MSEC, START);
+        let day = 24 * 3600 * 1000 * MSEC;
+        wheel.insert(START + 5 * MSEC, 1);
+        wheel.insert(START + day, 2);
+        wheel.insert(START + day, 3);
+        assert!(!wheel.remove(START + 5 * MSEC, &2));
+        assert!(wheel.remove(START + day, &2));
+        assert!(wheel.remove(START + 5 * MSEC, &1));
+        assert_eq!(
+            wheel.next_expiration().map(|next| next > START + 5 * MSEC),
+            Some(true)
+        );
+
+        // Cascaded items are found too.
+        assert!(wheel.advance(START + day - MSEC).is_empty());
+        wheel.insert(START + day, 4);
+        assert!(wheel.remove(START + day, &3));
+        assert_eq!(wheel.advance(START + day), vec![4]);
+        assert_eq!(wheel.next_expiration(), None);
+    }
+}
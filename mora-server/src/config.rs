@@ -1,16 +1,60 @@
+use std::{fmt::Display, str::FromStr};
+
 use log::{info, warn, Level};
-use mora_core::result::MoraResult;
+use mora_core::result::{MoraError, MoraResult};
 
 const DEFAULT_PORT: u16 = 2626;
 const DEFAULT_CHANNEL_TIMEOUT_IN_MSEC: usize = 3600 * 1000;
 const DEFAULT_QUEUE_POOL_CAPACITY: usize = usize::MAX;
+const DEFAULT_IDEMPOTENCY_WINDOW_IN_MSEC: u128 = 24 * 3600 * 1000;
+
+/// Where queues are stored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StorageEngine {
+    /// Write-ahead logs, configured by the `MORA_WAL_*` variables.
+    #[default]
+    Wal,
+    /// An embedded redb database at `MORA_REDB_PATH`.
+    Redb,
+    /// A SQLite database at `MORA_SQLITE_PATH`.
+    Sqlite,
+    /// In memory: queues are lost on restart.
+    Memory,
+}
+
+impl FromStr for StorageEngine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "wal" => Ok(Self::Wal),
+            "redb" => Ok(Self::Redb),
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            other => Err(format!("invalid storage engine: {other}")),
+        }
+    }
+}
+
+impl Display for StorageEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Wal => write!(f, "wal"),
+            Self::Redb => write!(f, "redb"),
+            Self::Sqlite => write!(f, "sqlite"),
+            Self::Memory => write!(f, "memory"),
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct MoraConfig {
     channel_timeout_in_msec: usize,
     port: u16,
     queue_pool_capacity: usize,
+    idempotency_window_in_msec: u128,
     log_level: Level,
+    storage_engine: StorageEngine,
 }
 
 impl MoraConfig {
@@ -48,6 +92,17 @@ impl MoraConfig {
             DEFAULT_QUEUE_POOL_CAPACITY
         };
 
+        let idempotency_window_in_msec = if let Ok(idempotency_window_in_msec_str) =
+            std::env::var("MORA_IDEMPOTENCY_WINDOW_IN_MSEC")
+        {
+            idempotency_window_in_msec_str.parse().unwrap_or_else(|_| {
+                warn!("{idempotency_window_in_msec_str} not a valid idempotency window number, reverting to default ({DEFAULT_IDEMPOTENCY_WINDOW_IN_MSEC})");
+                DEFAULT_IDEMPOTENCY_WINDOW_IN_MSEC
+            })
+        } else {
+            DEFAULT_IDEMPOTENCY_WINDOW_IN_MSEC
+        };
+
         let log_level = if let Ok(log_level_str) = std::env::var("MORA_LOG_LEVEL") {
             log_level_str.parse().unwrap_or_else(|_| {
                 warn!("{log_level_str} not a valid log level, reverting to default info level)");
@@ -58,11 +113,22 @@ impl MoraConfig {
             Level::Info
         };
 
+        let storage_engine = if let Ok(storage_engine_str) = std::env::var("MORA_STORAGE_ENGINE") {
+            // Falling back to another engine would serve the queues from the wrong place.
+            storage_engine_str
+                .parse()
+                .map_err(|e| MoraError::ConfigError(format!("MORA_STORAGE_ENGINE: {e}")))?
+        } else {
+            StorageEngine::default()
+        };
+
         Ok(Self {
             channel_timeout_in_msec,
             port,
             queue_pool_capacity,
+            idempotency_window_in_msec,
             log_level,
+            storage_engine,
         })
     }
 
@@ -78,9 +144,17 @@ impl MoraConfig {
         self.queue_pool_capacity
     }
 
+    pub fn idempotency_window_in_msec(&self) -> u128 {
+        self.idempotency_window_in_msec
+    }
+
     pub fn log_level(&self) -> Level {
         self.log_level
     }
+
+    pub fn storage_engine(&self) -> StorageEngine {
+        self.storage_engine
+    }
 }
 
 #[cfg(test)]
@@ -98,5 +172,25 @@ mod tests {
             }
         ));
         std::env::remove_var("MORA_CHANNEL_TIMEOUT_IN_MSEC");
+
+        std::env::set_var("MORA_STORAGE_ENGINE", "disk");
+        assert!(matches!(
+            MoraConfig::build(),
+            Err(MoraError::ConfigError(_))
+        ));
+        std::env::remove_var("MORA_STORAGE_ENGINE");
+    }
+
+    #[test]
+    fn storage_engines_round_trip_through_strings() {
+        for engine in [
+            StorageEngine::Wal,
+            StorageEngine::Redb,
+            StorageEngine::Sqlite,
+            StorageEngine::Memory,
+        ] {
+            assert_eq!(engine.to_string().parse(), Ok(engine));
+        }
+        assert!("disk".parse::<StorageEngine>().is_err());
     }
 }
@@ -1,15 +1,35 @@
-use crate::config::MoraConfig;
-use log::info;
-use mora_api::MoraApi;
+use crate::config::{MoraConfig, StorageEngine};
+use log::{error, info};
+use mora_api::{MoraApi, StorageBackend};
 use mora_core::result::MoraResult;
-use mora_queue::{channel_manager::ChannelManager, pool::QueuePool};
+use mora_queue::{
+    channel_manager::ChannelManager,
+    pool::{QueuePool, QueuePoolOptions},
+    scheduler::Scheduler,
+};
+use mora_storage::{
+    memory_storage::MemoryStorage, redb_storage::RedbStorage, sqlite_storage::SqliteStorage,
+    storage_thread::StorageThread, wal_file_storage::WalFileStorage,
+};
+use opentelemetry::global;
 
 use std::{sync::Arc, time::Duration};
-use tokio::{sync::Mutex, task::JoinSet, time::sleep};
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinSet,
+    time::interval,
+};
 
 pub mod config;
 pub mod otel;
 
+/// Channels are closed at most this late after their timeout expired.
+const CHANNEL_TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the storage is checked for logs that need compacting.
+const STORAGE_COMPACTION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the storage is checked for logs that grew enough to be snapshotted.
+const STORAGE_SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct Server {
     config: MoraConfig,
@@ -21,47 +41,104 @@ impl Server {
     }
 
     pub async fn run(self) -> MoraResult<()> {
+        info!("Using {} storage", self.config.storage_engine());
+        match self.config.storage_engine() {
+            StorageEngine::Wal => self.run_with::<StorageThread<WalFileStorage>>().await,
+            StorageEngine::Redb => self.run_with::<StorageThread<RedbStorage>>().await,
+            StorageEngine::Sqlite => self.run_with::<StorageThread<SqliteStorage>>().await,
+            StorageEngine::Memory => self.run_with::<StorageThread<MemoryStorage>>().await,
+        }
+    }
+
+    async fn run_with<S: StorageBackend>(self) -> MoraResult<()> {
         let mut tasks = JoinSet::new();
-        let queue_pool = QueuePool::new().await?;
+        let queue_pool = QueuePool::<S>::new(QueuePoolOptions {
+            idempotency_window_in_msec: self.config.idempotency_window_in_msec(),
+        })
+        .await?;
         let queue_pool = Arc::new(Mutex::new(queue_pool));
         let channel_manager = Arc::new(Mutex::new(ChannelManager::default()));
+        let event_notifier = Arc::new(Notify::new());
+
+        tasks.spawn(Scheduler::new(queue_pool.clone(), event_notifier.clone()).run());
 
         let api = MoraApi::new(self.config.port());
         let channel_manager_for_api = channel_manager.clone();
         let queue_pool_for_api = queue_pool.clone();
 
         tasks.spawn(async move {
-            api.start_grpc_server(channel_manager_for_api, queue_pool_for_api)
+            api.start_grpc_server(channel_manager_for_api, queue_pool_for_api, event_notifier)
                 .await
         });
 
         let channel_manager_for_checker = channel_manager.clone();
         tasks.spawn(async move {
+            let mut interval = interval(CHANNEL_TIMEOUT_CHECK_INTERVAL);
             loop {
-                sleep(Duration::from_millis(1)).await;
-                let mut binding = channel_manager_for_checker.lock().await;
+                interval.tick().await;
+                let closed = channel_manager_for_checker
+                    .lock()
+                    .await
+                    .close_inactive_channels(self.config.channel_timeout_in_msec());
+                if closed > 0 {
+                    info!("Closed {} inactive channels.", closed);
+                }
+            }
+        });
 
-                binding
-                    .get_mut_channels()?
-                    .into_iter()
-                    .for_each(|channel| channel.update_msec_from_last_op(1));
+        let queue_pool_for_compaction = queue_pool.clone();
+        tasks.spawn(async move {
+            let meter = global::meter("mora-server");
+            let compactions = meter.u64_counter("storage_compactions").build();
+            let reclaimed_bytes = meter
+                .u64_counter("storage_compaction_reclaimed_bytes")
+                .build();
+            let mut interval = interval(STORAGE_COMPACTION_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                // The pool is released while the storage compacts.
+                let compaction = queue_pool_for_compaction.lock().await.compact_storage();
+                match compaction.await {
+                    Ok(stats) if stats.containers > 0 => {
+                        compactions.add(stats.containers as u64, &[]);
+                        reclaimed_bytes.add(stats.reclaimed_bytes, &[]);
+                        info!(
+                            "Compacted {} containers, reclaimed {} bytes.",
+                            stats.containers, stats.reclaimed_bytes
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Storage compaction failed: {e}"),
+                }
+            }
+        });
 
-                let channels_to_delete: Vec<String> = binding
-                    .get_channels()?
-                    .into_iter()
-                    .filter(|c| c.msec_from_last_op() > self.config.channel_timeout_in_msec())
-                    .map(|c| c.id().to_owned())
-                    .collect();
-                if !channels_to_delete.is_empty() {
-                    info!("Will close {} inactive channels.", channels_to_delete.len());
-                    for channel_id in channels_to_delete {
-                        binding.close_channel(&channel_id.to_owned());
+        let queue_pool_for_snapshots = queue_pool.clone();
+        tasks.spawn(async move {
+            let meter = global::meter("mora-server");
+            let snapshots = meter.u64_counter("storage_snapshots").build();
+            let snapshot_items = meter.u64_counter("storage_snapshot_items").build();
+            let mut interval = interval(STORAGE_SNAPSHOT_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                // The pool is released while the storage snapshots.
+                let snapshot = queue_pool_for_snapshots.lock().await.snapshot_storage();
+                match snapshot.await {
+                    Ok(stats) if stats.containers > 0 => {
+                        snapshots.add(stats.containers as u64, &[]);
+                        snapshot_items.add(stats.items, &[]);
+                        info!(
+                            "Snapshotted {} containers, {} items.",
+                            stats.containers, stats.items
+                        );
                     }
+                    Ok(_) => {}
+                    Err(e) => error!("Storage snapshot failed: {e}"),
                 }
             }
         });
 
-        while let Some(_) = tasks.join_next().await {
+        while tasks.join_next().await.is_some() {
             info!("Tasks completed");
         }
         Ok(())
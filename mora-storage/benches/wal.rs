@@ -0,0 +1,78 @@
+//! WAL storage benchmarks, run with `cargo bench -p mora-storage`.
+//!
+//! Benchmarks are run for 10k, 1M and 10M events; set `MORA_BENCH_EVENTS` to a
+//! comma separated list of sizes to override them, e.g. `MORA_BENCH_EVENTS=10000`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mora_core::{models::events::EventKey, traits::storage::Storage};
+use mora_storage::wal_file_storage::WalFileStorage;
+
+const DEFAULT_SIZES: [usize; 3] = [10_000, 1_000_000, 10_000_000];
+const CONTAINER: &str = "bench";
+/// Roughly the size of a small encoded event.
+const ITEM_BYTES: usize = 64;
+
+fn sizes() -> Vec<usize> {
+    match std::env::var("MORA_BENCH_EVENTS") {
+        Ok(sizes) => sizes
+            .split(',')
+            .map(|size| size.trim().parse().expect("invalid MORA_BENCH_EVENTS"))
+            .collect(),
+        Err(_) => DEFAULT_SIZES.to_vec(),
+    }
+}
+
+fn store_item(c: &mut Criterion) {
+    let wal_dir = tempfile::tempdir().unwrap();
+    let mut storage = WalFileStorage::new(wal_dir.path().to_string_lossy().to_string());
+    let container = CONTAINER.to_string();
+    storage.create_container(&container).unwrap();
+    let item = vec![0_u8; ITEM_BYTES];
+
+    let mut group = c.benchmark_group("wal/store_item");
+    group.throughput(Throughput::Elements(1));
+    let mut id = 0;
+    group.bench_function("append", |b| {
+        b.iter(|| {
+            id += 1;
+            storage.store_item(&container, &EventKey::new(id, id), black_box(&item))
+        })
+    });
+    group.finish();
+}
+
+/// Loads the storage and reads back every item, as the server does on startup.
+fn replay(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wal/replay");
+    group.sample_size(10);
+    for size in sizes() {
+        let wal_dir = tempfile::tempdir().unwrap();
+        let wal_path = wal_dir.path().to_string_lossy().to_string();
+        let container = CONTAINER.to_string();
+        let mut storage = WalFileStorage::new(wal_path.clone());
+        storage.create_container(&container).unwrap();
+        let item = vec![0_u8; ITEM_BYTES];
+        for id in 0..size as u128 {
+            storage
+                .store_item(&container, &EventKey::new(id, id), &item)
+                .unwrap();
+        }
+        drop(storage);
+
+        std::env::set_var("MORA_WAL_PATH", &wal_path);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                let mut storage = WalFileStorage::load().unwrap();
+                let items = storage.get_all_items(&container).unwrap();
+                assert_eq!(items.len(), size);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, store_item, replay);
+criterion_main!(benches);
@@ -0,0 +1,251 @@
+//! Behaviour every `Storage` backend must share. The same tests run against each
+//! backend: add new ones to `conformance_suite!`.
+
+use std::path::Path;
+
+use mora_core::{
+    models::events::EventKey,
+    result::{MoraError, MoraResult, StorageError},
+    traits::storage::Storage,
+};
+
+use crate::{
+    memory_storage::MemoryStorage,
+    redb_storage::RedbStorage,
+    sqlite_storage::SqliteStorage,
+    wal_file_storage::{WalFileStorage, WalFileStorageConfig},
+};
+
+trait Backend: Storage<ContainerId = String, SortKey = EventKey, Item = Vec<u8>> + Sized {
+    /// Whether what was stored survives reopening the storage.
+    const PERSISTENT: bool = true;
+
+    /// Opens the storage kept in `dir`, an empty directory the first time.
+    fn open_in(dir: &Path) -> MoraResult<Self>;
+}
+
+impl Backend for WalFileStorage {
+    fn open_in(dir: &Path) -> MoraResult<Self> {
+        WalFileStorage::open(WalFileStorageConfig::new(dir.to_string_lossy().to_string()))
+    }
+}
+
+impl Backend for RedbStorage {
+    fn open_in(dir: &Path) -> MoraResult<Self> {
+        RedbStorage::open(&dir.join("mora.redb").to_string_lossy())
+    }
+}
+
+impl Backend for SqliteStorage {
+    fn open_in(dir: &Path) -> MoraResult<Self> {
+        SqliteStorage::open(&dir.join("mora.sqlite").to_string_lossy())
+    }
+}
+
+impl Backend for MemoryStorage {
+    const PERSISTENT: bool = false;
+
+    fn open_in(_dir: &Path) -> MoraResult<Self> {
+        Ok(MemoryStorage::new())
+    }
+}
+
+fn sorted_containers<B: Backend>(storage: &B) -> MoraResult<Vec<String>> {
+    let mut containers = storage.list_containers()?;
+    containers.sort_unstable();
+    Ok(containers)
+}
+
+fn containers_are_created_listed_and_deleted<B: Backend>(dir: &Path) -> MoraResult<()> {
+    let mut storage = B::open_in(dir)?;
+    let (first, second) = ("first".to_string(), "second".to_string());
+    storage.create_container(&first)?;
+    storage.create_container(&second)?;
+    assert!(matches!(
+        storage.create_container(&first),
+        Err(MoraError::StorageError(
+            StorageError::ContainerAlreadyExists(_)
+        ))
+    ));
+    assert_eq!(
+        sorted_containers(&storage)?,
+        vec![first.clone(), second.clone()]
+    );
+
+    storage.delete_container(&first)?;
+    assert_eq!(sorted_containers(&storage)?, vec![second]);
+    assert!(matches!(
+        storage.delete_container(&first),
+        Err(MoraError::StorageError(StorageError::ContainerNotFound(_)))
+    ));
+    Ok(())
+}
+
+fn missing_containers_are_reported<B: Backend>(dir: &Path) -> MoraResult<()> {
+    let mut storage = B::open_in(dir)?;
+    let missing = "missing".to_string();
+    let key = EventKey::new(1, 1);
+    let not_found = |result: MoraResult<_>| {
+        matches!(
+            result,
+            Err(MoraError::StorageError(StorageError::ContainerNotFound(_)))
+        )
+    };
+    assert!(not_found(storage.store_item(
+        &missing,
+        &key,
+        &b"item".to_vec()
+    )));
+    assert!(not_found(
+        storage.store_items(&missing, &[(key, b"item".to_vec())])
+    ));
+    assert!(not_found(storage.delete_item(&missing, &key)));
+    assert!(not_found(storage.delete_items(&missing, &[key])));
+    assert!(not_found(storage.get_all_items(&missing).map(|_| ())));
+    Ok(())
+}
+
+fn items_are_overwritten_and_deleted<B: Backend>(dir: &Path) -> MoraResult<()> {
+    let mut storage = B::open_in(dir)?;
+    let container = "queue".to_string();
+    storage.create_container(&container)?;
+    for id in 0..4 {
+        storage.store_item(&container, &EventKey::new(id, id), &b"first".to_vec())?;
+    }
+    storage.store_item(&container, &EventKey::new(1, 1), &b"later".to_vec())?;
+    storage.delete_item(&container, &EventKey::new(0, 0))?;
+    // Deleting unknown keys is not an error.
+    storage.delete_items(&container, &[EventKey::new(2, 2), EventKey::new(9, 9)])?;
+
+    let items = storage.get_all_items(&container)?;
+    assert_eq!(items.len(), 2);
+    assert_eq!(items.get(&EventKey::new(1, 1)), Some(&b"later".to_vec()));
+    assert_eq!(items.get(&EventKey::new(3, 3)), Some(&b"first".to_vec()));
+    Ok(())
+}
+
+fn items_are_stored_in_batches<B: Backend>(dir: &Path) -> MoraResult<()> {
+    let container = "queue".to_string();
+    {
+        let mut storage = B::open_in(dir)?;
+        storage.create_container(&container)?;
+        storage.store_item(&container, &EventKey::new(1, 1), &b"first".to_vec())?;
+        storage.store_items(
+            &container,
+            &[
+                (EventKey::new(1, 1), b"overwritten".to_vec()),
+                (EventKey::new(2, 2), b"second".to_vec()),
+                (EventKey::new(3, 3), b"third".to_vec()),
+            ],
+        )?;
+        storage.store_items(&container, &[])?;
+        storage.delete_item(&container, &EventKey::new(3, 3))?;
+        if !B::PERSISTENT {
+            return check_batch(&mut storage, &container);
+        }
+    }
+    check_batch(&mut B::open_in(dir)?, &container)
+}
+
+fn check_batch<B: Backend>(storage: &mut B, container: &String) -> MoraResult<()> {
+    let items = storage.get_all_items(container)?;
+    assert_eq!(items.len(), 2);
+    assert_eq!(
+        items.get(&EventKey::new(1, 1)),
+        Some(&b"overwritten".to_vec())
+    );
+    assert_eq!(items.get(&EventKey::new(2, 2)), Some(&b"second".to_vec()));
+    Ok(())
+}
+
+fn items_of_the_same_timestamp_are_distinct<B: Backend>(dir: &Path) -> MoraResult<()> {
+    let mut storage = B::open_in(dir)?;
+    let container = "queue".to_string();
+    storage.create_container(&container)?;
+    storage.store_item(&container, &EventKey::new(7, 1), &b"first".to_vec())?;
+    storage.store_item(&container, &EventKey::new(7, 2), &b"second".to_vec())?;
+    storage.store_item(&container, &EventKey::new(7, 3), &Vec::new())?;
+
+    let items = storage.get_all_items(&container)?;
+    assert_eq!(items.len(), 3);
+    assert_eq!(items.get(&EventKey::new(7, 3)), Some(&Vec::new()));
+    Ok(())
+}
+
+fn containers_are_isolated<B: Backend>(dir: &Path) -> MoraResult<()> {
+    let mut storage = B::open_in(dir)?;
+    let (first, second) = ("first".to_string(), "second".to_string());
+    storage.create_container(&first)?;
+    storage.create_container(&second)?;
+    storage.store_item(&first, &EventKey::new(1, 1), &b"first".to_vec())?;
+    storage.delete_item(&second, &EventKey::new(1, 1))?;
+
+    assert_eq!(storage.get_all_items(&first)?.len(), 1);
+    assert!(storage.get_all_items(&second)?.is_empty());
+
+    storage.delete_container(&first)?;
+    storage.create_container(&first)?;
+    assert!(storage.get_all_items(&first)?.is_empty());
+    Ok(())
+}
+
+fn items_survive_reopening<B: Backend>(dir: &Path) -> MoraResult<()> {
+    if !B::PERSISTENT {
+        return Ok(());
+    }
+    let (kept, deleted) = ("kept".to_string(), "deleted".to_string());
+    {
+        let mut storage = B::open_in(dir)?;
+        storage.create_container(&kept)?;
+        storage.create_container(&deleted)?;
+        storage.store_item(&kept, &EventKey::new(1, 1), &b"first".to_vec())?;
+        storage.store_item(&kept, &EventKey::new(2, 2), &b"second".to_vec())?;
+        storage.delete_item(&kept, &EventKey::new(1, 1))?;
+        storage.delete_container(&deleted)?;
+    }
+
+    let mut storage = B::open_in(dir)?;
+    assert_eq!(sorted_containers(&storage)?, vec![kept.clone()]);
+    let items = storage.get_all_items(&kept)?;
+    assert_eq!(items.len(), 1);
+    assert_eq!(items.get(&EventKey::new(2, 2)), Some(&b"second".to_vec()));
+    Ok(())
+}
+
+/// Runs every conformance test against each `backend: Type`, in a module named
+/// after the backend.
+macro_rules! conformance_suite {
+    ($($backend:ident: $storage:ty),* $(,)?) => {
+        $(
+            mod $backend {
+                use super::*;
+
+                conformance_suite!(@tests $storage;
+                    containers_are_created_listed_and_deleted,
+                    missing_containers_are_reported,
+                    items_are_overwritten_and_deleted,
+                    items_are_stored_in_batches,
+                    items_of_the_same_timestamp_are_distinct,
+                    containers_are_isolated,
+                    items_survive_reopening,
+                );
+            }
+        )*
+    };
+    (@tests $storage:ty; $($test:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $test() -> MoraResult<()> {
+                let dir = tempfile::tempdir().unwrap();
+                super::$test::<$storage>(dir.path())
+            }
+        )*
+    };
+}
+
+conformance_suite!(
+    wal_file_storage: WalFileStorage,
+    redb_storage: RedbStorage,
+    sqlite_storage: SqliteStorage,
+    memory_storage: MemoryStorage,
+);
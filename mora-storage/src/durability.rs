@@ -0,0 +1,392 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs::File,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::error;
+use mora_core::{
+    result::{MoraError, MoraResult, StorageError},
+    traits::storage::DurabilityTicket,
+};
+use tokio::{sync::watch, time::Instant};
+
+/// When writes to the WAL are fsynced.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes are fsynced before being acknowledged. Concurrent writers share the
+    /// same fsync.
+    #[default]
+    Always,
+    /// Writes are fsynced at most once per interval and acknowledged once synced.
+    Interval(Duration),
+    /// Writes are left to the OS: acknowledged writes may be lost on power failure.
+    Never,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    /// Parses `always`, `never` or an interval in milliseconds such as `100ms`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            other => other
+                .strip_suffix("ms")
+                .and_then(|msec| msec.trim().parse().ok())
+                .map(|msec| Self::Interval(Duration::from_millis(msec)))
+                .ok_or_else(|| format!("invalid durability: {other}")),
+        }
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Always => write!(f, "always"),
+            Self::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+            Self::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// Group commit of the WAL files.
+///
+/// Every write gets a position. A ticket waits for its position to be synced: the
+/// first waiter finding no sync in progress leads the next one, fsyncing every file
+/// and directory written since the previous sync on a blocking thread, while the
+/// others wait for its outcome. Writes nobody waits for, e.g. deletes on fetch, are
+/// synced along with the next ticket.
+pub(crate) struct WalSync {
+    durability: Durability,
+    state: Mutex<SyncState>,
+    synced: watch::Sender<Synced>,
+}
+
+struct SyncState {
+    /// Position of the last write.
+    written: u64,
+    /// File written to by each container.
+    files: HashMap<String, Arc<File>>,
+    dirty: HashSet<String>,
+    /// Files replaced since the last sync, e.g. by a segment rollover.
+    retired: Vec<Arc<File>>,
+    /// Directories where files were created or deleted since the last sync.
+    dirty_directories: HashSet<String>,
+    syncing: bool,
+    last_sync: Instant,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Synced {
+    position: u64,
+    /// The last failed sync, with the position it was meant to reach.
+    failed: Option<(u64, String)>,
+}
+
+/// What a sync has to write to disk.
+#[derive(Default)]
+struct PendingSync {
+    containers: Vec<(String, Arc<File>)>,
+    retired: Vec<Arc<File>>,
+    directories: Vec<String>,
+}
+
+/// What a ticket does next.
+enum Step {
+    Done(MoraResult<()>),
+    Wait,
+    WaitUntil(Instant),
+    /// Sync up to the given position.
+    Lead(u64, PendingSync),
+}
+
+impl WalSync {
+    pub fn new(durability: Durability) -> Self {
+        Self {
+            durability,
+            state: Mutex::new(SyncState {
+                written: 0,
+                files: HashMap::new(),
+                dirty: HashSet::new(),
+                retired: Vec::new(),
+                dirty_directories: HashSet::new(),
+                syncing: false,
+                last_sync: Instant::now(),
+            }),
+            synced: watch::Sender::new(Synced::default()),
+        }
+    }
+
+    /// Tracks the file a container writes to. The file it replaces is still synced
+    /// by the next sync if it was written to.
+    pub fn register(&self, container_id: &str, file: &File) -> MoraResult<()> {
+        let file = file
+            .try_clone()
+            .map_err(|e| MoraError::StorageError(StorageError::SyncFailed(e.to_string())))?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(previous) = state.files.insert(container_id.to_string(), Arc::new(file)) {
+            if state.dirty.contains(container_id) {
+                state.retired.push(previous);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn deleted(&self, container_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.files.remove(container_id);
+        state.dirty.remove(container_id);
+    }
+
+    pub fn wrote(&self, container_id: &str) {
+        if self.durability == Durability::Never {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if !state.dirty.contains(container_id) {
+            state.dirty.insert(container_id.to_string());
+        }
+        state.written += 1;
+    }
+
+    /// Records that files were created or deleted in `directory`.
+    pub fn wrote_directory(&self, directory: &str) {
+        if self.durability == Durability::Never {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.dirty_directories.insert(directory.to_string());
+        state.written += 1;
+    }
+
+    pub fn ticket(self: &Arc<Self>) -> DurabilityTicket {
+        if self.durability == Durability::Never {
+            return Box::pin(std::future::ready(Ok(())));
+        }
+        let position = self.state.lock().unwrap().written;
+        Box::pin(self.clone().wait(position))
+    }
+
+    async fn wait(self: Arc<Self>, position: u64) -> MoraResult<()> {
+        let mut synced = self.synced.subscribe();
+        loop {
+            // Cloned so the watch isn't borrowed while locking the state.
+            let current = synced.borrow_and_update().clone();
+            match self.step(&current, position) {
+                Step::Done(result) => return result,
+                Step::Wait => {}
+                Step::WaitUntil(deadline) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => continue,
+                        _ = synced.changed() => continue,
+                    }
+                }
+                Step::Lead(target, pending) => {
+                    // Detached from the ticket, so waiters aren't left hanging when
+                    // the leader is dropped.
+                    let sync = self.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let result = pending.sync();
+                        sync.publish(target, pending, result);
+                    });
+                }
+            }
+            // The sender lives as long as `self`.
+            let _ = synced.changed().await;
+        }
+    }
+
+    fn step(&self, synced: &Synced, position: u64) -> Step {
+        if synced.position >= position {
+            return Step::Done(Ok(()));
+        }
+        if let Some((failed, error)) = &synced.failed {
+            if *failed >= position {
+                return Step::Done(Err(MoraError::StorageError(StorageError::SyncFailed(
+                    error.to_owned(),
+                ))));
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.syncing {
+            return Step::Wait;
+        }
+        if let Durability::Interval(interval) = self.durability {
+            let next_sync = state.last_sync + interval;
+            if next_sync > Instant::now() {
+                return Step::WaitUntil(next_sync);
+            }
+        }
+        state.syncing = true;
+        let containers = std::mem::take(&mut state.dirty)
+            .into_iter()
+            .filter_map(|container_id| {
+                let file = state.files.get(&container_id)?.clone();
+                Some((container_id, file))
+            })
+            .collect();
+        let pending = PendingSync {
+            containers,
+            retired: std::mem::take(&mut state.retired),
+            directories: std::mem::take(&mut state.dirty_directories)
+                .into_iter()
+                .collect(),
+        };
+        Step::Lead(state.written, pending)
+    }
+
+    fn publish(&self, target: u64, pending: PendingSync, result: std::io::Result<()>) {
+        let mut state = self.state.lock().unwrap();
+        state.syncing = false;
+        state.last_sync = Instant::now();
+        match result {
+            Ok(()) => self.synced.send_modify(|synced| {
+                synced.position = synced.position.max(target);
+            }),
+            Err(e) => {
+                error!("error syncing the wal: {e}");
+                // Left for the next sync, the writes it was meant for can't be
+                // acknowledged anymore.
+                state.dirty.extend(
+                    pending
+                        .containers
+                        .into_iter()
+                        .map(|(container_id, _)| container_id),
+                );
+                state.retired.extend(pending.retired);
+                state.dirty_directories.extend(pending.directories);
+                self.synced.send_modify(|synced| {
+                    synced.failed = Some((target, e.to_string()));
+                });
+            }
+        }
+    }
+}
+
+impl PendingSync {
+    /// Files are synced before the directories, so new files are never listed
+    /// before their content is durable.
+    fn sync(&self) -> std::io::Result<()> {
+        let files = self.containers.iter().map(|(_, file)| file);
+        for file in self.retired.iter().chain(files) {
+            file.sync_data()?;
+        }
+        for directory in &self.directories {
+            match File::open(directory) {
+                Ok(directory) => directory.sync_all()?,
+                // Deleted since, along with its container.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durability_round_trips_through_strings() {
+        for durability in [
+            Durability::Always,
+            Durability::Never,
+            Durability::Interval(Duration::from_millis(50)),
+        ] {
+            assert_eq!(durability.to_string().parse(), Ok(durability));
+        }
+        assert_eq!(
+            "10s".parse::<Durability>(),
+            Err("invalid durability: 10s".to_string())
+        );
+        assert!("sometimes".parse::<Durability>().is_err());
+    }
+
+    fn temp_sync(name: &str, durability: Durability) -> (Arc<WalSync>, File) {
+        let path = std::env::temp_dir().join(format!("mora-sync-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let file = File::create(path.join("queue")).unwrap();
+        let sync = Arc::new(WalSync::new(durability));
+        sync.register("queue", &file).unwrap();
+        sync.wrote("queue");
+        sync.wrote_directory(&path.to_string_lossy());
+        (sync, file)
+    }
+
+    fn synced_position(sync: &WalSync) -> u64 {
+        sync.synced.borrow().position
+    }
+
+    #[tokio::test]
+    async fn concurrent_tickets_share_a_sync() -> MoraResult<()> {
+        let (sync, _file) = temp_sync("always", Durability::Always);
+        sync.wrote("queue");
+        let first = sync.ticket();
+        sync.wrote("queue");
+        let second = sync.ticket();
+
+        let (first, second) = tokio::join!(first, second);
+        first?;
+        second?;
+        assert_eq!(synced_position(&sync), 4);
+        let state = sync.state.lock().unwrap();
+        assert!(state.dirty.is_empty() && state.dirty_directories.is_empty() && !state.syncing);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn synced_writes_get_ready_tickets() -> MoraResult<()> {
+        let (sync, _file) = temp_sync("synced", Durability::Always);
+        sync.ticket().await?;
+        sync.ticket().await?;
+        assert_eq!(synced_position(&sync), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replaced_files_are_synced_with_the_next_ticket() -> MoraResult<()> {
+        let (sync, file) = temp_sync("replaced", Durability::Always);
+        sync.register("queue", &file.try_clone().unwrap())?;
+        assert_eq!(sync.state.lock().unwrap().retired.len(), 1);
+
+        sync.ticket().await?;
+        assert!(sync.state.lock().unwrap().retired.is_empty());
+        // Files that weren't written to since the last sync don't need it.
+        sync.register("queue", &file)?;
+        assert!(sync.state.lock().unwrap().retired.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interval_tickets_wait_for_the_next_sync() -> MoraResult<()> {
+        let interval = Duration::from_millis(50);
+        let (sync, _file) = temp_sync("interval", Durability::Interval(interval));
+        let start = Instant::now();
+        sync.ticket().await?;
+        assert!(start.elapsed() >= interval);
+
+        sync.wrote("queue");
+        let start = Instant::now();
+        sync.ticket().await?;
+        assert!(start.elapsed() >= interval / 2);
+        assert_eq!(synced_position(&sync), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_are_not_synced_when_durability_is_never() -> MoraResult<()> {
+        let (sync, _file) = temp_sync("never", Durability::Never);
+        sync.wrote("queue");
+        sync.ticket().await?;
+        assert_eq!(synced_position(&sync), 0);
+        Ok(())
+    }
+}
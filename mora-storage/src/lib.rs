@@ -1 +1,9 @@
+#[cfg(test)]
+mod conformance;
+pub mod durability;
+pub mod memory_storage;
+pub mod redb_storage;
+pub(crate) mod snapshot;
+pub mod sqlite_storage;
+pub mod storage_thread;
 pub mod wal_file_storage;
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use mora_core::{
+    models::events::EventKey,
+    result::{MoraError, MoraResult, StorageError},
+    traits::storage::Storage,
+};
+
+/// Keeps every container in memory: nothing survives a restart. Meant for tests and
+/// ephemeral deployments, where touching the disk is not wanted.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    containers: HashMap<String, HashMap<EventKey, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn container_mut(&mut self, container_id: &str) -> MoraResult<&mut HashMap<EventKey, Vec<u8>>> {
+        self.containers
+            .get_mut(container_id)
+            .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
+                container_id.to_string(),
+            )))
+    }
+}
+
+impl Storage for MemoryStorage {
+    type ContainerId = String;
+
+    type SortKey = EventKey;
+
+    type Item = Vec<u8>;
+
+    fn load() -> MoraResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self::new())
+    }
+
+    fn create_container(&mut self, container_id: &Self::ContainerId) -> MoraResult<()> {
+        if self.containers.contains_key(container_id) {
+            return Err(MoraError::StorageError(
+                StorageError::ContainerAlreadyExists(container_id.to_string()),
+            ));
+        }
+        self.containers.insert(container_id.clone(), HashMap::new());
+        Ok(())
+    }
+
+    fn delete_container(&mut self, container_id: &Self::ContainerId) -> MoraResult<()> {
+        self.containers
+            .remove(container_id)
+            .map(|_| ())
+            .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
+                container_id.to_string(),
+            )))
+    }
+
+    fn list_containers(&self) -> MoraResult<Vec<Self::ContainerId>> {
+        Ok(self.containers.keys().cloned().collect())
+    }
+
+    fn delete_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+    ) -> MoraResult<()> {
+        self.container_mut(container_id)?.remove(item_sort_key);
+        Ok(())
+    }
+
+    fn store_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+        item: &Self::Item,
+    ) -> MoraResult<()> {
+        self.container_mut(container_id)?
+            .insert(*item_sort_key, item.clone());
+        Ok(())
+    }
+
+    fn store_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        items: &[(Self::SortKey, Self::Item)],
+    ) -> MoraResult<()> {
+        self.container_mut(container_id)?
+            .extend(items.iter().cloned());
+        Ok(())
+    }
+
+    fn get_all_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+    ) -> MoraResult<HashMap<Self::SortKey, Self::Item>> {
+        Ok(self.container_mut(container_id)?.clone())
+    }
+
+    fn delete_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_keys: &[Self::SortKey],
+    ) -> MoraResult<()> {
+        let container = self.container_mut(container_id)?;
+        for key in item_sort_keys {
+            container.remove(key);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use mora_core::{
+    models::events::EventKey,
+    result::{MoraError, MoraResult, StorageError},
+    traits::storage::Storage,
+};
+use redb::{Database, ReadableTable, TableDefinition, TableHandle};
+
+const DEFAULT_REDB_PATH: &str = "/tmp/mora.redb";
+
+type ContainerTable<'a> = TableDefinition<'a, &'static [u8], &'static [u8]>;
+
+/// Storage backed by redb, an embedded ordered key-value store. Each container is a
+/// table keyed by the sort keys, encoded by `table_key` so that tables are ordered
+/// like `EventKey`.
+///
+/// Every write is a transaction committed before returning: redb fsyncs it, so the
+/// default durability ticket is always ready. Space held by deleted items is reused
+/// by redb itself, there is nothing to compact.
+pub struct RedbStorage {
+    database: Database,
+    containers: HashSet<String>,
+}
+
+impl RedbStorage {
+    /// Opens the database at `path`, creating it along with its parent directories
+    /// if needed.
+    pub fn open(path: &str) -> MoraResult<Self> {
+        if let Some(parent) = Path::new(path).parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                MoraError::StorageError(StorageError::DirectoryCreationFailed(
+                    parent.to_string_lossy().to_string(),
+                    e.to_string(),
+                ))
+            })?;
+        }
+        let open_error: fn(String) -> StorageError = StorageError::DatabaseOpenFailed;
+        let database = Database::create(path).map_err(storage_error(open_error))?;
+        let transaction = database.begin_read().map_err(storage_error(open_error))?;
+        let containers = transaction
+            .list_tables()
+            .map_err(storage_error(open_error))?
+            .map(|table| table.name().to_string())
+            .collect();
+        Ok(Self {
+            database,
+            containers,
+        })
+    }
+
+    fn check_container(&self, container_id: &str) -> MoraResult<()> {
+        if self.containers.contains(container_id) {
+            Ok(())
+        } else {
+            Err(MoraError::StorageError(StorageError::ContainerNotFound(
+                container_id.to_string(),
+            )))
+        }
+    }
+
+    /// Applies `write` to the table of a container in a single transaction.
+    fn write(
+        &mut self,
+        container_id: &str,
+        write: impl FnOnce(
+            &mut redb::Table<&'static [u8], &'static [u8]>,
+        ) -> Result<(), redb::StorageError>,
+    ) -> MoraResult<()> {
+        self.check_container(container_id)?;
+        let write_error: fn(String) -> StorageError = StorageError::ItemWriteFailed;
+        let transaction = self
+            .database
+            .begin_write()
+            .map_err(storage_error(write_error))?;
+        {
+            let mut table = transaction
+                .open_table(ContainerTable::new(container_id))
+                .map_err(storage_error(write_error))?;
+            write(&mut table).map_err(storage_error(write_error))?;
+        }
+        transaction.commit().map_err(storage_error(write_error))
+    }
+}
+
+/// Maps redb errors to the `StorageError` built by `variant`.
+fn storage_error<E: Into<redb::Error>>(
+    variant: fn(String) -> StorageError,
+) -> impl Fn(E) -> MoraError {
+    move |e| MoraError::StorageError(variant(e.into().to_string()))
+}
+
+/// Table key of `key`: its timestamp then its id, both big-endian, so that redb
+/// orders the bytes like `EventKey`.
+fn table_key(key: &EventKey) -> [u8; EventKey::BYTES] {
+    let mut bytes = [0_u8; EventKey::BYTES];
+    bytes[..16].copy_from_slice(&key.timestamp.to_be_bytes());
+    bytes[16..].copy_from_slice(&key.id.to_be_bytes());
+    bytes
+}
+
+fn parse_table_key(bytes: &[u8]) -> Option<EventKey> {
+    if bytes.len() != EventKey::BYTES {
+        return None;
+    }
+    Some(EventKey::new(
+        u128::from_be_bytes(bytes[..16].try_into().ok()?),
+        u128::from_be_bytes(bytes[16..].try_into().ok()?),
+    ))
+}
+
+impl Storage for RedbStorage {
+    type ContainerId = String;
+
+    type SortKey = EventKey;
+
+    type Item = Vec<u8>;
+
+    /// Opens the database at `MORA_REDB_PATH`.
+    fn load() -> MoraResult<Self>
+    where
+        Self: Sized,
+    {
+        let path =
+            std::env::var("MORA_REDB_PATH").unwrap_or_else(|_| DEFAULT_REDB_PATH.to_string());
+        Self::open(&path)
+    }
+
+    fn create_container(&mut self, container_id: &Self::ContainerId) -> MoraResult<()> {
+        if self.containers.contains(container_id) {
+            return Err(MoraError::StorageError(
+                StorageError::ContainerAlreadyExists(container_id.to_string()),
+            ));
+        }
+        let creation_error: fn(String) -> StorageError = StorageError::ContainerCreationFailed;
+        let transaction = self
+            .database
+            .begin_write()
+            .map_err(storage_error(creation_error))?;
+        transaction
+            .open_table(ContainerTable::new(container_id))
+            .map_err(storage_error(creation_error))?;
+        transaction
+            .commit()
+            .map_err(storage_error(creation_error))?;
+        self.containers.insert(container_id.clone());
+        Ok(())
+    }
+
+    fn delete_container(&mut self, container_id: &Self::ContainerId) -> MoraResult<()> {
+        self.check_container(container_id)?;
+        let deletion_error: fn(String) -> StorageError = StorageError::ContainerDeletionFailed;
+        let transaction = self
+            .database
+            .begin_write()
+            .map_err(storage_error(deletion_error))?;
+        transaction
+            .delete_table(ContainerTable::new(container_id))
+            .map_err(storage_error(deletion_error))?;
+        transaction
+            .commit()
+            .map_err(storage_error(deletion_error))?;
+        self.containers.remove(container_id);
+        Ok(())
+    }
+
+    fn list_containers(&self) -> MoraResult<Vec<Self::ContainerId>> {
+        Ok(self.containers.iter().cloned().collect())
+    }
+
+    fn delete_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+    ) -> MoraResult<()> {
+        self.delete_items(container_id, std::slice::from_ref(item_sort_key))
+    }
+
+    fn store_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+        item: &Self::Item,
+    ) -> MoraResult<()> {
+        self.write(container_id, |table| {
+            table.insert(table_key(item_sort_key).as_slice(), item.as_slice())?;
+            Ok(())
+        })
+    }
+
+    /// Stores the items in a single transaction.
+    fn store_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        items: &[(Self::SortKey, Self::Item)],
+    ) -> MoraResult<()> {
+        self.write(container_id, |table| {
+            for (key, item) in items {
+                table.insert(table_key(key).as_slice(), item.as_slice())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_all_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+    ) -> MoraResult<HashMap<Self::SortKey, Self::Item>> {
+        self.check_container(container_id)?;
+        let read_error: fn(String) -> StorageError = StorageError::ItemReadFailed;
+        let transaction = self
+            .database
+            .begin_read()
+            .map_err(storage_error(read_error))?;
+        let table = transaction
+            .open_table(ContainerTable::new(container_id))
+            .map_err(storage_error(read_error))?;
+        let mut items = HashMap::new();
+        for entry in table.iter().map_err(storage_error(read_error))? {
+            let (key, item) = entry.map_err(storage_error(read_error))?;
+            let key = parse_table_key(key.value()).ok_or_else(|| {
+                MoraError::StorageError(StorageError::ItemReadFailed(format!(
+                    "invalid sort key in {container_id}"
+                )))
+            })?;
+            items.insert(key, item.value().to_vec());
+        }
+        Ok(items)
+    }
+
+    fn delete_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_keys: &[Self::SortKey],
+    ) -> MoraResult<()> {
+        self.write(container_id, |table| {
+            for key in item_sort_keys {
+                table.remove(table_key(key).as_slice())?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_are_ordered_like_event_keys() -> MoraResult<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RedbStorage::open(&dir.path().join("mora.redb").to_string_lossy())?;
+        let container = "queue".to_string();
+        storage.create_container(&container)?;
+        let keys = [
+            EventKey::new(256, 1),
+            EventKey::new(1, 256),
+            EventKey::new(1, 2),
+            EventKey::new(u128::MAX, 0),
+        ];
+        for key in &keys {
+            storage.store_item(&container, key, &vec![])?;
+        }
+
+        let transaction = storage.database.begin_read().unwrap();
+        let table = transaction
+            .open_table(ContainerTable::new(&container))
+            .unwrap();
+        let stored = table
+            .iter()
+            .unwrap()
+            .map(|entry| parse_table_key(entry.unwrap().0.value()))
+            .collect::<Option<Vec<_>>>();
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        assert_eq!(stored, Some(sorted));
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+};
+
+use mora_core::models::events::EventKey;
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"MSNP";
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Segment of a container log, as recorded by a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SnapshotSegment {
+    pub id: u64,
+    /// Records of the segment covered by the snapshot.
+    pub records: u64,
+    pub shadows: Vec<u64>,
+}
+
+/// Live items of a container log up to some position.
+///
+/// File layout, integers being little endian:
+///        ┌──────────────┬──────────────┬───────────────┬───────────────┐
+///        │ magic "MSNP" │ version (4B) │ segment (8B)  │ offset (8B)   │
+///        ├──────────────┴──────────────┴───────────────┴───────────────┤
+///        │ segments count (8B) │ id (8B) │ records (8B) │ shadows count │
+///        │ (8B) │ shadows (8B each) │ ...                              │
+///        ├─────────────────────────────────────────────────────────────┤
+///        │ items count (8B) │ key (32B) │ segment (8B) │ item length    │
+///        │ (8B) │ item (variable) │ ...                                │
+///        ├─────────────────────────────────────────────────────────────┤
+///        │ crc32c of all the preceding bytes (4B)                      │
+///        └─────────────────────────────────────────────────────────────┘
+#[derive(Debug, Default)]
+pub(crate) struct Snapshot {
+    /// The snapshot covers every record of the segments before `segment`, and the
+    /// ones of `segment` before `offset`.
+    pub segment: u64,
+    pub offset: u64,
+    pub segments: Vec<SnapshotSegment>,
+    /// Every live item, with the segment holding it.
+    pub items: HashMap<EventKey, (u64, Vec<u8>)>,
+}
+
+/// Writes a snapshot to `path` and fsyncs it, returning its size.
+pub(crate) fn write<'a>(
+    path: &str,
+    segment: u64,
+    offset: u64,
+    segments: &[SnapshotSegment],
+    items: impl ExactSizeIterator<Item = (&'a EventKey, u64, &'a [u8])>,
+) -> std::io::Result<u64> {
+    let mut writer = ChecksumWriter {
+        inner: BufWriter::new(File::create(path)?),
+        checksum: 0,
+    };
+    writer.write_all(&SNAPSHOT_MAGIC)?;
+    writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    writer.write_u64(segment)?;
+    writer.write_u64(offset)?;
+
+    writer.write_u64(segments.len() as u64)?;
+    for segment in segments {
+        writer.write_u64(segment.id)?;
+        writer.write_u64(segment.records)?;
+        writer.write_u64(segment.shadows.len() as u64)?;
+        for shadowed in &segment.shadows {
+            writer.write_u64(*shadowed)?;
+        }
+    }
+
+    writer.write_u64(items.len() as u64)?;
+    for (key, segment, item) in items {
+        writer.write_all(&key.to_bytes())?;
+        writer.write_u64(segment)?;
+        writer.write_u64(item.len() as u64)?;
+        writer.write_all(item)?;
+    }
+
+    let checksum = writer.checksum;
+    let mut file = writer.inner.into_inner().map_err(|e| e.into_error())?;
+    file.write_all(&checksum.to_le_bytes())?;
+    file.sync_all()?;
+    Ok(file.metadata()?.len())
+}
+
+/// Reads the snapshot at `path`, if any. Snapshots that can't be read whole or
+/// whose checksum doesn't match are reported as `ErrorKind::InvalidData`.
+pub(crate) fn read(path: &str) -> std::io::Result<Option<Snapshot>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let file_bytes = file.metadata()?.len();
+    let mut reader = ChecksumReader {
+        inner: BufReader::new(file),
+        checksum: 0,
+        remaining: file_bytes,
+    };
+
+    let mut magic = [0_u8; 4];
+    reader.read_exact(&mut magic)?;
+    let mut version = [0_u8; 4];
+    reader.read_exact(&mut version)?;
+    if magic != SNAPSHOT_MAGIC || u32::from_le_bytes(version) != SNAPSHOT_VERSION {
+        return Err(invalid("not a snapshot of a supported version"));
+    }
+    let mut snapshot = Snapshot {
+        segment: reader.read_u64()?,
+        offset: reader.read_u64()?,
+        ..Default::default()
+    };
+
+    for _ in 0..reader.read_u64()? {
+        let id = reader.read_u64()?;
+        let records = reader.read_u64()?;
+        let shadows = (0..reader.read_u64()?)
+            .map(|_| reader.read_u64())
+            .collect::<std::io::Result<_>>()?;
+        snapshot.segments.push(SnapshotSegment {
+            id,
+            records,
+            shadows,
+        });
+    }
+
+    for _ in 0..reader.read_u64()? {
+        let mut key = [0_u8; EventKey::BYTES];
+        reader.read_exact(&mut key)?;
+        let segment = reader.read_u64()?;
+        let item_length = reader.read_u64()?;
+        // A corrupted length could be anything, never allocate past the file.
+        if item_length > reader.remaining {
+            return Err(invalid("item past the end of the snapshot"));
+        }
+        let mut item = vec![0_u8; item_length as usize];
+        reader.read_exact(&mut item)?;
+        snapshot
+            .items
+            .insert(EventKey::from_bytes(key), (segment, item));
+    }
+
+    let checksum = reader.checksum;
+    let mut expected = [0_u8; 4];
+    reader.read_exact(&mut expected)?;
+    if checksum != u32::from_le_bytes(expected) || reader.inner.read(&mut [0_u8; 1])? != 0 {
+        return Err(invalid("checksum mismatch"));
+    }
+    Ok(Some(snapshot))
+}
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid snapshot: {reason}"),
+    )
+}
+
+struct ChecksumWriter<W> {
+    inner: W,
+    checksum: u32,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn write_all(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.checksum = crc32c::crc32c_append(self.checksum, bytes);
+        self.inner.write_all(bytes)
+    }
+
+    fn write_u64(&mut self, value: u64) -> std::io::Result<()> {
+        self.write_all(&value.to_le_bytes())
+    }
+}
+
+struct ChecksumReader<R> {
+    inner: R,
+    checksum: u32,
+    remaining: u64,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn read_exact(&mut self, buffer: &mut [u8]) -> std::io::Result<()> {
+        self.inner.read_exact(buffer).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => invalid("truncated"),
+            _ => e,
+        })?;
+        self.checksum = crc32c::crc32c_append(self.checksum, buffer);
+        self.remaining = self.remaining.saturating_sub(buffer.len() as u64);
+        Ok(())
+    }
+
+    fn read_u64(&mut self) -> std::io::Result<u64> {
+        let mut value = [0_u8; 8];
+        self.read_exact(&mut value)?;
+        Ok(u64::from_le_bytes(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("mora-snapshot-{name}-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn write_sample(path: &str) -> std::io::Result<u64> {
+        let segments = vec![
+            SnapshotSegment {
+                id: 3,
+                records: 10,
+                shadows: vec![],
+            },
+            SnapshotSegment {
+                id: 4,
+                records: 2,
+                shadows: vec![1, 3],
+            },
+        ];
+        let items = [(EventKey::new(1, 1), 3, b"first".to_vec())];
+        write(
+            path,
+            4,
+            120,
+            &segments,
+            items
+                .iter()
+                .map(|(key, segment, item)| (key, *segment, item.as_slice())),
+        )
+    }
+
+    #[test]
+    fn snapshots_round_trip() -> std::io::Result<()> {
+        let path = temp_path("round-trip");
+        write_sample(&path)?;
+
+        let snapshot = read(&path)?.unwrap();
+        assert_eq!((snapshot.segment, snapshot.offset), (4, 120));
+        assert_eq!(snapshot.segments.len(), 2);
+        assert_eq!(snapshot.segments[1].shadows, vec![1, 3]);
+        assert_eq!(
+            snapshot.items.get(&EventKey::new(1, 1)),
+            Some(&(3, b"first".to_vec()))
+        );
+        assert!(read(&temp_path("missing"))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_snapshots_are_invalid() -> std::io::Result<()> {
+        let path = temp_path("damaged");
+        let bytes = write_sample(&path)?;
+
+        let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.set_len(bytes - 1)?;
+        assert_eq!(read(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        write_sample(&path)?;
+        let mut content = std::fs::read(&path)?;
+        content[30] ^= 1;
+        std::fs::write(&path, content)?;
+        assert_eq!(read(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+use std::{collections::HashMap, path::Path};
+
+use mora_core::{
+    models::events::EventKey,
+    result::{MoraError, MoraResult, StorageError},
+    traits::storage::Storage,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+
+const DEFAULT_SQLITE_PATH: &str = "/tmp/mora.sqlite";
+
+/// Schema migrations, applied in order. The database `user_version` counts the ones
+/// already applied: never edit a migration once released, append a new one.
+///
+/// Sort keys are stored so that SQLite orders them like `EventKey`: timestamps as
+/// nanosecond integers and event ids as 16-byte big-endian blobs. Events can be
+/// looked up with plain SQL, e.g.
+/// `SELECT * FROM items WHERE container = 'queue' AND timestamp < 1700000000000000000`.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE containers (
+        name TEXT PRIMARY KEY
+    ) WITHOUT ROWID;
+    CREATE TABLE items (
+        container TEXT NOT NULL REFERENCES containers (name) ON DELETE CASCADE,
+        timestamp INTEGER NOT NULL,
+        event_id BLOB NOT NULL CHECK (length(event_id) = 16),
+        item BLOB NOT NULL,
+        PRIMARY KEY (container, timestamp, event_id)
+    ) WITHOUT ROWID;
+"];
+
+const STORE_ITEM: &str = "
+    INSERT INTO items (container, timestamp, event_id, item) VALUES (?1, ?2, ?3, ?4)
+    ON CONFLICT (container, timestamp, event_id) DO UPDATE SET item = excluded.item
+";
+
+/// Storage backed by a SQLite database, holding every container in a single table
+/// keyed by container and sort key.
+///
+/// The database is in WAL mode with full synchronous commits: every write is durable
+/// once it returns, and the database can be read or backed up by other processes
+/// while the server runs.
+pub struct SqliteStorage {
+    connection: Connection,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it along with its parent directories
+    /// if needed, and migrates its schema.
+    pub fn open(path: &str) -> MoraResult<Self> {
+        if let Some(parent) = Path::new(path).parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                MoraError::StorageError(StorageError::DirectoryCreationFailed(
+                    parent.to_string_lossy().to_string(),
+                    e.to_string(),
+                ))
+            })?;
+        }
+        let open_error: fn(String) -> StorageError = StorageError::DatabaseOpenFailed;
+        let connection = Connection::open(path).map_err(storage_error(open_error))?;
+        connection
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+            .map_err(storage_error(open_error))?;
+        connection
+            .pragma_update(None, "synchronous", "FULL")
+            .and_then(|_| connection.pragma_update(None, "foreign_keys", true))
+            .map_err(storage_error(open_error))?;
+
+        let mut storage = Self { connection };
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    /// Applies the migrations the database is missing, each in its own transaction.
+    fn migrate(&mut self) -> MoraResult<()> {
+        let migration_error: fn(String) -> StorageError = StorageError::MigrationFailed;
+        let version: usize = self
+            .connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(storage_error(migration_error))?;
+        if version > MIGRATIONS.len() {
+            return Err(MoraError::StorageError(StorageError::MigrationFailed(
+                format!(
+                    "schema version {version} is newer than the latest known one ({})",
+                    MIGRATIONS.len()
+                ),
+            )));
+        }
+
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = self
+                .connection
+                .transaction()
+                .map_err(storage_error(migration_error))?;
+            transaction
+                .execute_batch(migration)
+                .and_then(|_| transaction.pragma_update(None, "user_version", applied + 1))
+                .and_then(|_| transaction.commit())
+                .map_err(storage_error(migration_error))?;
+        }
+        Ok(())
+    }
+
+    fn check_container(&self, container_id: &str) -> MoraResult<()> {
+        let exists = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM containers WHERE name = ?1",
+                [container_id],
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(storage_error(StorageError::ItemReadFailed))?;
+        match exists {
+            Some(()) => Ok(()),
+            None => Err(MoraError::StorageError(StorageError::ContainerNotFound(
+                container_id.to_string(),
+            ))),
+        }
+    }
+}
+
+/// Maps SQLite errors to the `StorageError` built by `variant`.
+fn storage_error(variant: fn(String) -> StorageError) -> impl Fn(rusqlite::Error) -> MoraError {
+    move |e| MoraError::StorageError(variant(e.to_string()))
+}
+
+/// Columns of `key`. Timestamps past the range of SQLite integers, in year 2262,
+/// can't be stored.
+fn sort_key_columns(key: &EventKey) -> MoraResult<(i64, [u8; 16])> {
+    let timestamp = i64::try_from(key.timestamp).map_err(|_| {
+        MoraError::StorageError(StorageError::ItemWriteFailed(format!(
+            "timestamp {} is out of range",
+            key.timestamp
+        )))
+    })?;
+    Ok((timestamp, key.id.to_be_bytes()))
+}
+
+fn parse_sort_key(timestamp: i64, event_id: &[u8]) -> Option<EventKey> {
+    Some(EventKey::new(
+        u128::try_from(timestamp).ok()?,
+        u128::from_be_bytes(event_id.try_into().ok()?),
+    ))
+}
+
+impl Storage for SqliteStorage {
+    type ContainerId = String;
+
+    type SortKey = EventKey;
+
+    type Item = Vec<u8>;
+
+    /// Opens the database at `MORA_SQLITE_PATH`.
+    fn load() -> MoraResult<Self>
+    where
+        Self: Sized,
+    {
+        let path =
+            std::env::var("MORA_SQLITE_PATH").unwrap_or_else(|_| DEFAULT_SQLITE_PATH.to_string());
+        Self::open(&path)
+    }
+
+    fn create_container(&mut self, container_id: &Self::ContainerId) -> MoraResult<()> {
+        let created = self
+            .connection
+            .execute(
+                "INSERT INTO containers (name) VALUES (?1) ON CONFLICT DO NOTHING",
+                [container_id],
+            )
+            .map_err(storage_error(StorageError::ContainerCreationFailed))?;
+        if created == 0 {
+            return Err(MoraError::StorageError(
+                StorageError::ContainerAlreadyExists(container_id.to_string()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Deleting the container deletes its items along, see `MIGRATIONS`.
+    fn delete_container(&mut self, container_id: &Self::ContainerId) -> MoraResult<()> {
+        let deleted = self
+            .connection
+            .execute("DELETE FROM containers WHERE name = ?1", [container_id])
+            .map_err(storage_error(StorageError::ContainerDeletionFailed))?;
+        if deleted == 0 {
+            return Err(MoraError::StorageError(StorageError::ContainerNotFound(
+                container_id.to_string(),
+            )));
+        }
+        Ok(())
+    }
+
+    fn list_containers(&self) -> MoraResult<Vec<Self::ContainerId>> {
+        let read_error: fn(String) -> StorageError = StorageError::ItemReadFailed;
+        let mut statement = self
+            .connection
+            .prepare("SELECT name FROM containers")
+            .map_err(storage_error(read_error))?;
+        let containers = statement
+            .query_map([], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(storage_error(read_error))?;
+        Ok(containers)
+    }
+
+    fn delete_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+    ) -> MoraResult<()> {
+        self.delete_items(container_id, std::slice::from_ref(item_sort_key))
+    }
+
+    fn store_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+        item: &Self::Item,
+    ) -> MoraResult<()> {
+        self.check_container(container_id)?;
+        let (timestamp, event_id) = sort_key_columns(item_sort_key)?;
+        self.connection
+            .execute(STORE_ITEM, params![container_id, timestamp, event_id, item])
+            .map_err(storage_error(StorageError::ItemWriteFailed))?;
+        Ok(())
+    }
+
+    /// Stores the items in a single transaction.
+    fn store_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        items: &[(Self::SortKey, Self::Item)],
+    ) -> MoraResult<()> {
+        self.check_container(container_id)?;
+        let write_error: fn(String) -> StorageError = StorageError::ItemWriteFailed;
+        let transaction = self
+            .connection
+            .transaction()
+            .map_err(storage_error(write_error))?;
+        {
+            let mut statement = transaction
+                .prepare_cached(STORE_ITEM)
+                .map_err(storage_error(write_error))?;
+            for (key, item) in items {
+                let (timestamp, event_id) = sort_key_columns(key)?;
+                statement
+                    .execute(params![container_id, timestamp, event_id, item])
+                    .map_err(storage_error(write_error))?;
+            }
+        }
+        transaction.commit().map_err(storage_error(write_error))
+    }
+
+    fn get_all_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+    ) -> MoraResult<HashMap<Self::SortKey, Self::Item>> {
+        self.check_container(container_id)?;
+        let read_error: fn(String) -> StorageError = StorageError::ItemReadFailed;
+        let mut statement = self
+            .connection
+            .prepare("SELECT timestamp, event_id, item FROM items WHERE container = ?1")
+            .map_err(storage_error(read_error))?;
+        let rows = statement
+            .query_map([container_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Vec<u8>>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })
+            .map_err(storage_error(read_error))?;
+
+        let mut items = HashMap::new();
+        for row in rows {
+            let (timestamp, event_id, item) = row.map_err(storage_error(read_error))?;
+            let key = parse_sort_key(timestamp, &event_id).ok_or_else(|| {
+                MoraError::StorageError(StorageError::ItemReadFailed(format!(
+                    "invalid sort key in {container_id}: {timestamp}, {event_id:x?}"
+                )))
+            })?;
+            items.insert(key, item);
+        }
+        Ok(items)
+    }
+
+    /// Deletes the items in a single transaction.
+    fn delete_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_keys: &[Self::SortKey],
+    ) -> MoraResult<()> {
+        self.check_container(container_id)?;
+        let write_error: fn(String) -> StorageError = StorageError::ItemWriteFailed;
+        let transaction = self
+            .connection
+            .transaction()
+            .map_err(storage_error(write_error))?;
+        {
+            let mut statement = transaction
+                .prepare_cached(
+                    "DELETE FROM items WHERE container = ?1 AND timestamp = ?2 AND event_id = ?3",
+                )
+                .map_err(storage_error(write_error))?;
+            for key in item_sort_keys {
+                let (timestamp, event_id) = sort_key_columns(key)?;
+                statement
+                    .execute(params![container_id, timestamp, event_id])
+                    .map_err(storage_error(write_error))?;
+            }
+        }
+        transaction.commit().map_err(storage_error(write_error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database_path(dir: &tempfile::TempDir) -> String {
+        dir.path().join("mora.sqlite").to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn migrations_are_applied_once() -> MoraResult<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = SqliteStorage::open(&database_path(&dir))?;
+        storage.create_container(&"queue".to_string())?;
+        drop(storage);
+
+        let storage = SqliteStorage::open(&database_path(&dir))?;
+        let version: usize = storage
+            .connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        assert_eq!(storage.list_containers()?, vec!["queue".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn newer_schemas_are_rejected() -> MoraResult<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::open(&database_path(&dir))?;
+        storage
+            .connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        drop(storage);
+
+        assert!(matches!(
+            SqliteStorage::open(&database_path(&dir)),
+            Err(MoraError::StorageError(StorageError::MigrationFailed(_)))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn items_can_be_queried_with_sql() -> MoraResult<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = SqliteStorage::open(&database_path(&dir))?;
+        let container = "queue".to_string();
+        storage.create_container(&container)?;
+        let timestamp = i64::MAX as u128;
+        storage.store_item(&container, &EventKey::new(timestamp, 7), &b"late".to_vec())?;
+        storage.store_item(&container, &EventKey::new(10, 1 << 64), &b"second".to_vec())?;
+        storage.store_item(&container, &EventKey::new(10, 2), &b"first".to_vec())?;
+
+        // SQLite orders the keys like `EventKey`.
+        let items: Vec<(i64, Vec<u8>, Vec<u8>)> = storage
+            .connection
+            .prepare("SELECT timestamp, event_id, item FROM items ORDER BY timestamp, event_id")
+            .and_then(|mut statement| {
+                statement
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                    .and_then(|rows| rows.collect())
+            })
+            .unwrap();
+        assert_eq!(
+            items,
+            vec![
+                (10, 2_u128.to_be_bytes().to_vec(), b"first".to_vec()),
+                (
+                    10,
+                    (1_u128 << 64).to_be_bytes().to_vec(),
+                    b"second".to_vec()
+                ),
+                (i64::MAX, 7_u128.to_be_bytes().to_vec(), b"late".to_vec()),
+            ]
+        );
+        assert_eq!(storage.get_all_items(&container)?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn timestamps_out_of_range_are_rejected() -> MoraResult<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = SqliteStorage::open(&database_path(&dir))?;
+        let container = "queue".to_string();
+        storage.create_container(&container)?;
+
+        let key = EventKey::new(i64::MAX as u128 + 1, 1);
+        assert!(matches!(
+            storage.store_item(&container, &key, &b"item".to_vec()),
+            Err(MoraError::StorageError(StorageError::ItemWriteFailed(_)))
+        ));
+        assert!(storage.get_all_items(&container)?.is_empty());
+        Ok(())
+    }
+}
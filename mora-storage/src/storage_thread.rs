@@ -0,0 +1,281 @@
+use std::{collections::HashMap, future::Future, hash::Hash, sync::mpsc, thread};
+
+use mora_core::{
+    result::{MoraError, MoraResult, StorageError},
+    traits::storage::{
+        AsyncStorage, CompactionStats, DurabilityTicket, SnapshotStats, Storage, StorageWrite,
+    },
+};
+use tokio::sync::oneshot;
+
+type Command<S> = Box<dyn FnOnce(&mut S) + Send>;
+
+/// Runs a blocking `Storage` on a dedicated thread, exposing it as an `AsyncStorage`.
+///
+/// Calls are sent to the thread over a channel and run in the order they were made,
+/// so storage I/O never blocks the async runtime. The thread stops once the handle
+/// is dropped.
+///
+/// Calls and replies:
+///         ┌────────────────┐  command  ┌─────────────────┐
+///         │ StorageThread  │ ────────▶ │ storage thread  │
+///         │ (async caller) │ ◀──────── │ (owns Storage)  │
+///         └────────────────┘   reply   └─────────────────┘
+pub struct StorageThread<S> {
+    commands: mpsc::Sender<Command<S>>,
+}
+
+impl<S: Storage + 'static> StorageThread<S> {
+    /// Starts the thread, opening the storage on it with `open`.
+    pub async fn spawn(open: impl FnOnce() -> MoraResult<S> + Send + 'static) -> MoraResult<Self> {
+        let (commands, receiver) = mpsc::channel::<Command<S>>();
+        let (opened, open_result) = oneshot::channel();
+        thread::Builder::new()
+            .name("mora-storage".to_string())
+            .spawn(move || {
+                let mut storage = match open() {
+                    Ok(storage) => {
+                        let _ = opened.send(Ok(()));
+                        storage
+                    }
+                    Err(e) => {
+                        let _ = opened.send(Err(e));
+                        return;
+                    }
+                };
+                while let Ok(command) = receiver.recv() {
+                    command(&mut storage);
+                }
+            })
+            .map_err(|e| thread_error(e.to_string()))?;
+
+        open_result
+            .await
+            .map_err(|_| thread_error("stopped while opening the storage"))??;
+        Ok(Self { commands })
+    }
+
+    /// Runs `call` on the storage thread. The call is queued right away, not when
+    /// the returned future is first polled, so calls keep the order they were made in.
+    fn call<R: Send + 'static>(
+        &self,
+        call: impl FnOnce(&mut S) -> MoraResult<R> + Send + 'static,
+    ) -> impl Future<Output = MoraResult<R>> + Send + 'static {
+        let (reply, response) = oneshot::channel();
+        let sent = self
+            .commands
+            .send(Box::new(move |storage| {
+                let _ = reply.send(call(storage));
+            }))
+            .is_ok();
+        async move {
+            if !sent {
+                return Err(thread_error("stopped"));
+            }
+            // The reply is only dropped unsent when the call panicked.
+            response.await.map_err(|_| thread_error("stopped"))?
+        }
+    }
+}
+
+fn thread_error(reason: impl Into<String>) -> MoraError {
+    MoraError::StorageError(StorageError::StorageThreadFailed(reason.into()))
+}
+
+impl<S> AsyncStorage for StorageThread<S>
+where
+    S: Storage + 'static,
+    S::ContainerId: Clone + Send + 'static,
+    S::SortKey: Clone + Eq + Hash + Send + 'static,
+    S::Item: Clone + Send + 'static,
+{
+    type ContainerId = S::ContainerId;
+
+    type SortKey = S::SortKey;
+
+    type Item = S::Item;
+
+    /// Loads the storage on a new thread, see `Storage::load`.
+    fn load() -> impl Future<Output = MoraResult<Self>> + Send
+    where
+        Self: Sized,
+    {
+        Self::spawn(S::load)
+    }
+
+    fn create_container(
+        &mut self,
+        container_id: &Self::ContainerId,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static {
+        let container_id = container_id.clone();
+        self.call(move |storage| storage.create_container(&container_id))
+    }
+
+    fn delete_container(
+        &mut self,
+        container_id: &Self::ContainerId,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static {
+        let container_id = container_id.clone();
+        self.call(move |storage| storage.delete_container(&container_id))
+    }
+
+    fn list_containers(
+        &self,
+    ) -> impl Future<Output = MoraResult<Vec<Self::ContainerId>>> + Send + 'static {
+        self.call(|storage| storage.list_containers())
+    }
+
+    fn delete_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static {
+        let (container_id, item_sort_key) = (container_id.clone(), item_sort_key.clone());
+        self.call(move |storage| storage.delete_item(&container_id, &item_sort_key))
+    }
+
+    fn store_item(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_key: &Self::SortKey,
+        item: &Self::Item,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static {
+        let (container_id, item_sort_key, item) =
+            (container_id.clone(), item_sort_key.clone(), item.clone());
+        self.call(move |storage| storage.store_item(&container_id, &item_sort_key, &item))
+    }
+
+    fn store_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        items: &[(Self::SortKey, Self::Item)],
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static {
+        let (container_id, items) = (container_id.clone(), items.to_vec());
+        self.call(move |storage| storage.store_items(&container_id, &items))
+    }
+
+    fn get_all_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+    ) -> impl Future<Output = MoraResult<HashMap<Self::SortKey, Self::Item>>> + Send + 'static {
+        let container_id = container_id.clone();
+        self.call(move |storage| storage.get_all_items(&container_id))
+    }
+
+    fn delete_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        item_sort_keys: &[Self::SortKey],
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static {
+        let (container_id, item_sort_keys) = (container_id.clone(), item_sort_keys.to_vec());
+        self.call(move |storage| storage.delete_items(&container_id, &item_sort_keys))
+    }
+
+    fn write(
+        &mut self,
+        writes: Vec<StorageWrite<Self::ContainerId, Self::SortKey, Self::Item>>,
+    ) -> impl Future<Output = MoraResult<()>> + Send + 'static {
+        self.call(move |storage| storage.write(&writes))
+    }
+
+    fn compact(&mut self) -> impl Future<Output = MoraResult<CompactionStats>> + Send + 'static {
+        self.call(|storage| storage.compact())
+    }
+
+    fn snapshot(&mut self) -> impl Future<Output = MoraResult<SnapshotStats>> + Send + 'static {
+        self.call(|storage| storage.snapshot())
+    }
+
+    /// The ticket is taken on the storage thread, after every call made before.
+    fn durability_ticket(&self) -> DurabilityTicket {
+        let ticket = self.call(|storage| Ok(storage.durability_ticket()));
+        Box::pin(async move { ticket.await?.await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mora_core::models::events::EventKey;
+
+    use super::*;
+    use crate::{
+        memory_storage::MemoryStorage,
+        wal_file_storage::{WalFileStorage, WalFileStorageConfig},
+    };
+
+    #[tokio::test]
+    async fn calls_run_in_order() -> MoraResult<()> {
+        let mut storage = StorageThread::spawn(|| Ok(MemoryStorage::new())).await?;
+        let container = "queue".to_string();
+        // Only the last call is awaited: the others were queued before it.
+        let created = storage.call(|storage| storage.create_container(&"queue".to_string()));
+        let stored = storage.call(|storage| {
+            storage.store_item(
+                &"queue".to_string(),
+                &EventKey::new(1, 1),
+                &b"first".to_vec(),
+            )
+        });
+        let items = storage.get_all_items(&container).await?;
+        created.await?;
+        stored.await?;
+        assert_eq!(items.get(&EventKey::new(1, 1)), Some(&b"first".to_vec()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wal_file_storage_runs_on_the_thread() -> MoraResult<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let config = WalFileStorageConfig::new(dir.path().to_string_lossy().to_string());
+        let mut storage = StorageThread::spawn(move || WalFileStorage::open(config)).await?;
+        let container = "queue".to_string();
+        storage.create_container(&container).await?;
+        storage
+            .store_item(&container, &EventKey::new(1, 1), &b"first".to_vec())
+            .await?;
+        storage.durability_ticket().await?;
+
+        assert!(matches!(
+            storage.create_container(&container).await,
+            Err(MoraError::StorageError(
+                StorageError::ContainerAlreadyExists(_)
+            ))
+        ));
+        assert_eq!(storage.list_containers().await?, vec![container.clone()]);
+        assert_eq!(storage.get_all_items(&container).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_stop_at_the_first_failure() -> MoraResult<()> {
+        let mut storage = StorageThread::spawn(|| Ok(MemoryStorage::new())).await?;
+        let container = "queue".to_string();
+        let item = (EventKey::new(1, 1), b"first".to_vec());
+        let written = storage.write(vec![
+            StorageWrite::CreateContainer(container.clone()),
+            StorageWrite::StoreItems("missing".to_string(), vec![item.clone()]),
+            StorageWrite::StoreItems(container.clone(), vec![item]),
+        ]);
+
+        assert!(matches!(
+            written.await,
+            Err(MoraError::StorageError(StorageError::ContainerNotFound(_)))
+        ));
+        assert!(storage.get_all_items(&container).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_errors_are_returned() {
+        let opened = StorageThread::<MemoryStorage>::spawn(|| {
+            Err(MoraError::StorageError(StorageError::DatabaseOpenFailed(
+                "locked".to_string(),
+            )))
+        })
+        .await;
+        assert!(matches!(
+            opened,
+            Err(MoraError::StorageError(StorageError::DatabaseOpenFailed(_)))
+        ));
+    }
+}
@@ -1,48 +1,315 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
     fs::{File, OpenOptions},
-    io::{BufWriter, Read, Seek, Write},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     path::Path,
+    sync::Arc,
 };
 
+use log::{debug, warn};
 use mora_core::{
+    models::events::EventKey,
     result::{MoraError, MoraResult, StorageError},
-    traits::storage::Storage,
+    traits::storage::{CompactionStats, DurabilityTicket, SnapshotStats, Storage},
 };
 
+use crate::{
+    durability::{Durability, WalSync},
+    snapshot::{self, Snapshot, SnapshotSegment},
+};
+
+/// Left over by compactions of the single-file layout, which wrote compacted logs to
+/// `<wal_path>/<prefix><container_id>`. Names starting with `__mora_` are reserved
+/// to the server, so these never clash with a container.
+const COMPACTION_FILE_PREFIX: &str = "__mora_compaction_";
+/// Single-file logs are moved to `<wal_path>/<prefix><container_id>` while they are
+/// turned into the first segment of their container.
+const MIGRATION_FILE_PREFIX: &str = "__mora_migration_";
+const SEGMENT_EXTENSION: &str = ".wal";
+/// Snapshots are written to the staging file of the container directory, then
+/// renamed over the previous one once complete.
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_STAGING_FILE: &str = "snapshot.tmp";
+
 pub struct WalFileStorage {
-    wals: HashMap<String, BufWriter<File>>,
+    containers: HashMap<String, Container>,
     wal_path: String,
+    compaction_thresholds: CompactionThresholds,
+    segment_max_bytes: u64,
+    snapshot_tail_bytes: u64,
+    sync: Arc<WalSync>,
 }
 
 pub struct WalFileStorageConfig {
     wal_path: String,
+    compaction_thresholds: CompactionThresholds,
+    durability: Durability,
+    segment_max_bytes: u64,
+    snapshot_tail_bytes: u64,
 }
 
 impl WalFileStorageConfig {
+    /// Keeps the logs in `wal_path`, every other setting at its default.
+    pub fn new(wal_path: String) -> Self {
+        Self {
+            wal_path,
+            compaction_thresholds: CompactionThresholds::default(),
+            durability: Durability::default(),
+            segment_max_bytes: DEFAULT_SEGMENT_MAX_BYTES,
+            snapshot_tail_bytes: DEFAULT_SNAPSHOT_TAIL_BYTES,
+        }
+    }
+
     pub fn load() -> MoraResult<Self> {
         let wal_path = std::env::var("MORA_WAL_PATH").unwrap_or_else(|_| "/tmp/wals".to_string());
-        Ok(Self { wal_path })
+        let defaults = CompactionThresholds::default();
+        let compaction_thresholds = CompactionThresholds {
+            dead_records_ratio: env_or(
+                "MORA_WAL_COMPACTION_DEAD_RECORDS_RATIO",
+                defaults.dead_records_ratio,
+            ),
+            min_bytes: env_or("MORA_WAL_COMPACTION_MIN_BYTES", defaults.min_bytes),
+            growth_bytes: env_or("MORA_WAL_COMPACTION_GROWTH_BYTES", defaults.growth_bytes),
+        };
+        let durability = env_or("MORA_WAL_DURABILITY", Durability::default());
+        let segment_max_bytes = env_or("MORA_WAL_SEGMENT_MAX_BYTES", DEFAULT_SEGMENT_MAX_BYTES);
+        let snapshot_tail_bytes =
+            env_or("MORA_WAL_SNAPSHOT_TAIL_BYTES", DEFAULT_SNAPSHOT_TAIL_BYTES);
+        Ok(Self {
+            wal_path,
+            compaction_thresholds,
+            durability,
+            segment_max_bytes,
+            snapshot_tail_bytes,
+        })
+    }
+}
+
+fn env_or<T: std::str::FromStr + std::fmt::Display>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("{value} not a valid {name}, reverting to default ({default})");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Segments are rolled over once they reach this size.
+pub const DEFAULT_SEGMENT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// Containers are snapshotted once this many bytes were appended to their log since
+/// their last snapshot.
+pub const DEFAULT_SNAPSHOT_TAIL_BYTES: u64 = 32 * 1024 * 1024;
+
+/// When a container log is worth compacting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionThresholds {
+    /// Share of dead records, i.e. deleted or overwritten items and tombstones,
+    /// over all the records of the log.
+    pub dead_records_ratio: f64,
+    /// Logs smaller than this are never compacted because of their dead records ratio.
+    pub min_bytes: u64,
+    /// Bytes appended since the log was last compacted or loaded that trigger a
+    /// compaction, whatever the ratio.
+    pub growth_bytes: u64,
+}
+
+impl Default for CompactionThresholds {
+    fn default() -> Self {
+        Self {
+            dead_records_ratio: 0.5,
+            min_bytes: 1024 * 1024,
+            growth_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Records of a container log. Dead records are counted exactly once the log was
+/// replayed and estimated before, assuming every tombstone deletes a live item.
+#[derive(Debug, Default, Clone, Copy)]
+struct WalStats {
+    records: u64,
+    dead_records: u64,
+    bytes: u64,
+    /// Size of the log when it was last compacted or loaded.
+    base_bytes: u64,
+}
+
+impl WalStats {
+    fn needs_compaction(&self, thresholds: &CompactionThresholds) -> bool {
+        if self.dead_records == 0 {
+            return false;
+        }
+        let dead_records_ratio = self.dead_records as f64 / self.records as f64;
+        (self.bytes >= thresholds.min_bytes && dead_records_ratio >= thresholds.dead_records_ratio)
+            || self.bytes.saturating_sub(self.base_bytes) >= thresholds.growth_bytes
+    }
+}
+
+/// The log of a container, split into segments. Only the last segment is appended
+/// to, the others are sealed.
+struct Container {
+    /// The last segment.
+    active: File,
+    index: SegmentIndex,
+    stats: WalStats,
+    /// Bytes of the log that the last snapshot doesn't cover.
+    tail_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct Segment {
+    records: u64,
+    /// Live items, only counted once the log was replayed.
+    live: u64,
+    bytes: u64,
+    /// Earlier segments holding items that this one overwrites or deletes: they must
+    /// be deleted first, or their items would come back on replay.
+    shadows: BTreeSet<u64>,
+}
+
+/// Where the records of a container log are, to tell when sealed segments only hold
+/// dead records.
+#[derive(Debug, Default)]
+struct SegmentIndex {
+    segments: BTreeMap<u64, Segment>,
+    /// Segment holding the live copy of each key, only known once the log was
+    /// replayed. Segments are never deleted before.
+    keys: Option<HashMap<EventKey, u64>>,
+    /// Some sealed segment may only hold dead records.
+    reclaimable: bool,
+    /// Segment covered in part by the snapshot of the container. Loading the snapshot
+    /// replays the log from there, so only the segments before it may be deleted.
+    snapshot_segment: Option<u64>,
+}
+
+impl SegmentIndex {
+    fn active_segment(&self) -> u64 {
+        self.segments
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Accounts for a record of `key` appended to the active segment, an item when
+    /// `stored` or a tombstone otherwise. Returns the records it made dead.
+    fn record(&mut self, key: &EventKey, stored: bool) -> u64 {
+        let active = self.active_segment();
+        let segment = self.segments.entry(active).or_default();
+        segment.records += 1;
+        let Some(keys) = &mut self.keys else {
+            // The tombstone and the item it deletes are both dead.
+            return if stored { 0 } else { 2 };
+        };
+
+        let previous = if stored {
+            segment.live += 1;
+            keys.insert(*key, active)
+        } else {
+            keys.remove(key)
+        };
+        let Some(previous) = previous else {
+            return if stored { 0 } else { 1 };
+        };
+        if previous != active {
+            segment.shadows.insert(previous);
+        }
+        if let Some(previous_segment) = self.segments.get_mut(&previous) {
+            previous_segment.live = previous_segment.live.saturating_sub(1);
+            self.reclaimable |= previous_segment.live == 0 && previous != active;
+        }
+        if stored {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Sealed segments holding dead records only and shadowing no remaining segment,
+    /// oldest first.
+    fn reclaimable_segments(&self) -> Vec<u64> {
+        if self.keys.is_none() {
+            return vec![];
+        }
+        let end = self
+            .snapshot_segment
+            .unwrap_or(u64::MAX)
+            .min(self.active_segment());
+        let mut reclaimable = BTreeSet::new();
+        for (id, segment) in self.segments.range(..end) {
+            let shadows_nothing = segment.shadows.iter().all(|shadowed| {
+                !self.segments.contains_key(shadowed) || reclaimable.contains(shadowed)
+            });
+            if segment.live == 0 && shadows_nothing {
+                reclaimable.insert(*id);
+            }
+        }
+        reclaimable.into_iter().collect()
+    }
+
+    /// Indexes the items of `snapshot`, adding them to `items`. `on_disk` lists the
+    /// segments of the log.
+    fn restore(
+        &mut self,
+        snapshot: Snapshot,
+        on_disk: &SegmentIndex,
+        items: &mut HashMap<EventKey, Vec<u8>>,
+    ) {
+        for segment in snapshot.segments {
+            if let Some(on_disk) = on_disk.segments.get(&segment.id) {
+                self.segments.insert(
+                    segment.id,
+                    Segment {
+                        records: segment.records,
+                        live: 0,
+                        bytes: on_disk.bytes,
+                        shadows: segment.shadows.into_iter().collect(),
+                    },
+                );
+            }
+        }
+        // Segments the snapshot doesn't know about hold dead records only.
+        for (id, on_disk) in on_disk.segments.range(..snapshot.segment) {
+            self.segments.entry(*id).or_insert_with(|| Segment {
+                bytes: on_disk.bytes,
+                ..Default::default()
+            });
+        }
+
+        let keys = self.keys.get_or_insert_with(HashMap::new);
+        for (key, (segment, item)) in snapshot.items {
+            keys.insert(key, segment);
+            if let Some(segment) = self.segments.get_mut(&segment) {
+                segment.live += 1;
+            }
+            items.insert(key, item);
+        }
+        self.snapshot_segment = Some(snapshot.segment);
     }
 }
 
 enum ItemDescriptor {
     Tombstone = 0,
     Item = 1,
+    Batch = 2,
 }
 
-impl Into<u8> for ItemDescriptor {
-    fn into(self) -> u8 {
-        self as u8
+impl From<ItemDescriptor> for u8 {
+    fn from(val: ItemDescriptor) -> Self {
+        val as u8
     }
 }
 
-impl From<&[u8]> for ItemDescriptor {
-    fn from(value: &[u8]) -> Self {
-        match value[0] {
-            0 => Self::Tombstone,
-            1 => Self::Item,
-            _ => panic!("invalid item descriptor"),
+impl TryFrom<u8> for ItemDescriptor {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Tombstone),
+            1 => Ok(Self::Item),
+            2 => Ok(Self::Batch),
+            other => Err(other),
         }
     }
 }
@@ -50,157 +317,670 @@ impl From<&[u8]> for ItemDescriptor {
 impl WalFileStorage {
     pub fn new(wal_path: String) -> Self {
         Self {
-            wals: HashMap::new(),
+            containers: HashMap::new(),
             wal_path,
+            compaction_thresholds: CompactionThresholds::default(),
+            segment_max_bytes: DEFAULT_SEGMENT_MAX_BYTES,
+            snapshot_tail_bytes: DEFAULT_SNAPSHOT_TAIL_BYTES,
+            sync: Arc::new(WalSync::new(Durability::default())),
+        }
+    }
+
+    pub fn with_compaction_thresholds(mut self, thresholds: CompactionThresholds) -> Self {
+        self.compaction_thresholds = thresholds;
+        self
+    }
+
+    /// Sets when writes are fsynced, before any container is opened.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.sync = Arc::new(WalSync::new(durability));
+        self
+    }
+
+    /// Sets the size past which segments are rolled over. A record is never split,
+    /// so segments holding a single record may be bigger.
+    pub fn with_segment_max_bytes(mut self, segment_max_bytes: u64) -> Self {
+        self.segment_max_bytes = segment_max_bytes;
+        self
+    }
+
+    /// Sets how many bytes appended to a container log since its last snapshot
+    /// make `snapshot` write a new one.
+    pub fn with_snapshot_tail_bytes(mut self, snapshot_tail_bytes: u64) -> Self {
+        self.snapshot_tail_bytes = snapshot_tail_bytes;
+        self
+    }
+
+    fn container_path(&self, container_id: &str) -> String {
+        container_path(&self.wal_path, container_id)
+    }
+
+    // open(config)
+    // Initialize storage from disk.
+    //
+    //   open(config)
+    //        │
+    //        ▼
+    //   scan wal_path for single-file logs -> move each into its container directory
+    //        │
+    //        ▼
+    //   scan wal_path for container directories -> open_container(id) -> Ok(self)
+    pub fn open(config: WalFileStorageConfig) -> MoraResult<Self> {
+        let mut storage = Self::new(config.wal_path.to_owned())
+            .with_compaction_thresholds(config.compaction_thresholds)
+            .with_durability(config.durability)
+            .with_segment_max_bytes(config.segment_max_bytes)
+            .with_snapshot_tail_bytes(config.snapshot_tail_bytes);
+
+        if !Path::new(&config.wal_path).exists() {
+            std::fs::create_dir_all(&config.wal_path).map_err(|e| {
+                MoraError::StorageError(StorageError::DirectoryCreationFailed(
+                    config.wal_path.to_string(),
+                    e.to_string(),
+                ))
+            })?;
+            return Ok(storage);
+        }
+
+        for entry in storage.list_wal_path()? {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() {
+                continue;
+            }
+            if file_name.starts_with(COMPACTION_FILE_PREFIX) {
+                // Left over by a compaction interrupted before completing.
+                std::fs::remove_file(entry.path()).map_err(|e| {
+                    MoraError::StorageError(StorageError::CompactionFailed(e.to_string()))
+                })?;
+                continue;
+            }
+            let container_id = file_name
+                .strip_prefix(MIGRATION_FILE_PREFIX)
+                .unwrap_or(&file_name);
+            storage.migrate_single_file_log(container_id)?;
+        }
+
+        for entry in storage.list_wal_path()? {
+            if entry.path().is_dir() {
+                let container_id = entry.file_name().to_string_lossy().to_string();
+                storage.open_container(container_id)?;
+            }
+        }
+
+        Ok(storage)
+    }
+
+    fn list_wal_path(&self) -> MoraResult<Vec<std::fs::DirEntry>> {
+        std::fs::read_dir(&self.wal_path)
+            .and_then(|entries| entries.collect())
+            .map_err(|e| MoraError::StorageError(StorageError::DirectoryReadFailed(e.to_string())))
+    }
+
+    /// Turns the log of a container written by previous versions, a single file at
+    /// the root of `wal_path`, into the first segment of the container. Logs written
+    /// before segments had a header are rewritten in the current format, see
+    /// `upgrade_unversioned_log`.
+    fn migrate_single_file_log(&self, container_id: &str) -> MoraResult<()> {
+        let path = self.container_path(container_id);
+        let staging_path = self.container_path(&format!("{MIGRATION_FILE_PREFIX}{container_id}"));
+        let segment = segment_path(&self.wal_path, container_id, 0);
+        let read_error = |e: std::io::Error| {
+            MoraError::StorageError(StorageError::FileReadFailed(e.to_string()))
+        };
+
+        // The container directory takes the name of the file, so the file is moved
+        // out of the way first. A crash leaves it there for the next load.
+        if Path::new(&path).is_file() {
+            std::fs::rename(&path, &staging_path).map_err(read_error)?;
+        }
+        let log = std::fs::read(&staging_path).map_err(read_error)?;
+        let versioned = header().starts_with(&log[..log.len().min(HEADER_BYTES)]);
+        let upgraded = if versioned {
+            None
+        } else {
+            Some(upgrade_unversioned_log(&log).ok_or_else(|| {
+                MoraError::StorageError(StorageError::InvalidWalFile(container_id.to_string()))
+            })?)
+        };
+
+        (|| {
+            std::fs::create_dir_all(&path)?;
+            match &upgraded {
+                None => std::fs::rename(&staging_path, &segment)?,
+                Some(upgraded) => {
+                    // Rewritten whole on every attempt until the staging file is gone.
+                    let mut file = File::create(&segment)?;
+                    file.write_all(upgraded)?;
+                    file.sync_all()?;
+                    std::fs::remove_file(&staging_path)?;
+                }
+            }
+            sync_directory(&path)?;
+            sync_directory(&self.wal_path)
+        })()
+        .map_err(read_error)?;
+        debug!("moved the log of {container_id} to its first segment");
+        Ok(())
+    }
+
+    fn open_container(&mut self, container_id: String) -> MoraResult<()> {
+        let read_error = |e: std::io::Error| {
+            MoraError::StorageError(StorageError::FileReadFailed(e.to_string()))
+        };
+        let path = self.container_path(&container_id);
+
+        let mut index = SegmentIndex::default();
+        for entry in std::fs::read_dir(&path)
+            .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+            .map_err(read_error)?
+        {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name == SNAPSHOT_FILE {
+                continue;
+            }
+            if file_name == SNAPSHOT_STAGING_FILE {
+                // Left over by a snapshot interrupted before completing.
+                std::fs::remove_file(entry.path()).map_err(|e| {
+                    MoraError::StorageError(StorageError::SnapshotFailed(e.to_string()))
+                })?;
+                continue;
+            }
+            let Some(segment) = file_name
+                .strip_suffix(SEGMENT_EXTENSION)
+                .and_then(|segment| segment.parse().ok())
+            else {
+                warn!("ignoring {file_name}, not a segment of {container_id}");
+                continue;
+            };
+            let bytes = entry.metadata().map_err(read_error)?.len();
+            index.segments.insert(
+                segment,
+                Segment {
+                    bytes,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let active = if index.segments.is_empty() {
+            // The container was being created.
+            let file = create_segment(&segment_path(&self.wal_path, &container_id, 0))
+                .map_err(read_error)?;
+            index.segments.insert(
+                0,
+                Segment {
+                    bytes: HEADER_BYTES as u64,
+                    ..Default::default()
+                },
+            );
+            file
+        } else {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(segment_path(
+                    &self.wal_path,
+                    &container_id,
+                    index.active_segment(),
+                ))
+                .map_err(read_error)?
+        };
+
+        let bytes = index.segments.values().map(|segment| segment.bytes).sum();
+        self.sync.register(&container_id, &active)?;
+        self.containers.insert(
+            container_id,
+            Container {
+                active,
+                index,
+                stats: WalStats {
+                    bytes,
+                    base_bytes: bytes,
+                    ..Default::default()
+                },
+                tail_bytes: bytes,
+            },
+        );
+        Ok(())
+    }
+
+    // append(&container_id, records, &keys, stored)
+    // Append framed records to the active segment, rolling it over first if full.
+    //
+    //   append(id, records)
+    //        │
+    //        ▼
+    //   active segment would exceed segment_max_bytes? ── no ─▶ write at EOF
+    //        │ yes
+    //        ▼
+    //   create segment n + 1 -> make it the active one -> write at EOF
+    //        │
+    //        ▼
+    //   account for the records -> delete the segments holding dead records only
+    fn append(
+        &mut self,
+        container_id: &str,
+        records: &[u8],
+        keys: &[EventKey],
+        stored: bool,
+    ) -> MoraResult<()> {
+        let write_error = |e: std::io::Error| {
+            MoraError::StorageError(StorageError::ItemWriteFailed(e.to_string()))
+        };
+        let container = self
+            .containers
+            .get_mut(container_id)
+            .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
+                container_id.to_string(),
+            )))?;
+
+        let active = container.index.active_segment();
+        let active_bytes = container
+            .index
+            .segments
+            .get(&active)
+            .map_or(0, |segment| segment.bytes);
+        if active_bytes > HEADER_BYTES as u64
+            && active_bytes + records.len() as u64 > self.segment_max_bytes
+        {
+            let file = create_segment(&segment_path(&self.wal_path, container_id, active + 1))
+                .map_err(write_error)?;
+            self.sync.register(container_id, &file)?;
+            self.sync
+                .wrote_directory(&container_path(&self.wal_path, container_id));
+            container.active = file;
+            container.index.segments.insert(
+                active + 1,
+                Segment {
+                    bytes: HEADER_BYTES as u64,
+                    ..Default::default()
+                },
+            );
+            container.index.reclaimable = true;
+            container.stats.bytes += HEADER_BYTES as u64;
+            container.tail_bytes += HEADER_BYTES as u64;
+        }
+
+        container
+            .active
+            .seek(SeekFrom::End(0))
+            .and_then(|_| container.active.write_all(records))
+            .and_then(|_| container.active.flush())
+            .map_err(write_error)?;
+        self.sync.wrote(container_id);
+
+        let active = container.index.active_segment();
+        if let Some(segment) = container.index.segments.get_mut(&active) {
+            segment.bytes += records.len() as u64;
+        }
+        for key in keys {
+            let dead_records = container.index.record(key, stored);
+            container.stats.dead_records += dead_records;
+        }
+        container.stats.records += keys.len() as u64;
+        container.stats.dead_records = container.stats.dead_records.min(container.stats.records);
+        container.stats.bytes += records.len() as u64;
+        container.tail_bytes += records.len() as u64;
+
+        if container.index.reclaimable {
+            self.reclaim_segments(container_id);
+        }
+        Ok(())
+    }
+
+    /// Deletes the sealed segments of a container holding dead records only. Failing
+    /// to is not an error for the write that made them dead: they are retried with
+    /// the next ones.
+    fn reclaim_segments(&mut self, container_id: &str) {
+        let Some(container) = self.containers.get_mut(container_id) else {
+            return;
+        };
+        container.index.reclaimable = false;
+        for segment in container.index.reclaimable_segments() {
+            if let Err(e) = remove_segment(&self.wal_path, container_id, segment) {
+                warn!("error deleting segment {segment} of {container_id}: {e}");
+                return;
+            }
+            if let Some(removed) = container.index.segments.remove(&segment) {
+                let stats = &mut container.stats;
+                stats.records = stats.records.saturating_sub(removed.records);
+                stats.dead_records = stats.dead_records.saturating_sub(removed.records);
+                stats.bytes = stats.bytes.saturating_sub(removed.bytes);
+            }
+            debug!("deleted segment {segment} of {container_id}");
+        }
+    }
+
+    // compact_container(&container_id)
+    // Rewrite the container log with its live items only.
+    //
+    //   compact_container(id)
+    //        │
+    //        ▼
+    //   replay log -> write live items to a new segment -> fsync segment and directory
+    //        │
+    //        ▼
+    //   make it the active segment -> delete the snapshot -> delete the previous
+    //   segments, oldest first
+    //
+    // A crash before the previous segments are all deleted leaves them to be replayed
+    // before the new one, which holds the same live items.
+    fn compact_container(&mut self, container_id: &String) -> MoraResult<u64> {
+        let compaction_error = |e: std::io::Error| {
+            MoraError::StorageError(StorageError::CompactionFailed(e.to_string()))
+        };
+
+        let mut items = self
+            .get_all_items(container_id)?
+            .into_iter()
+            .collect::<Vec<_>>();
+        items.sort_unstable_by_key(|(key, _)| *key);
+        let container = self
+            .containers
+            .get_mut(container_id)
+            .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
+                container_id.to_string(),
+            )))?;
+        let previous_bytes = container.stats.bytes;
+        let compacted_segment = container.index.active_segment() + 1;
+
+        let mut compacted = BufWriter::new(
+            create_segment(&segment_path(
+                &self.wal_path,
+                container_id,
+                compacted_segment,
+            ))
+            .map_err(compaction_error)?,
+        );
+        let mut buffer = Vec::new();
+        for (key, item) in &items {
+            buffer.clear();
+            insert_add_item_op_to_buffer(&mut buffer, key, item);
+            compacted.write_all(&buffer).map_err(compaction_error)?;
+        }
+        let compacted = compacted
+            .into_inner()
+            .map_err(|e| compaction_error(e.into_error()))?;
+        compacted.sync_all().map_err(compaction_error)?;
+        sync_directory(&container_path(&self.wal_path, container_id)).map_err(compaction_error)?;
+        let bytes = compacted.metadata().map_err(compaction_error)?.len();
+        // The snapshot covers segments about to be deleted.
+        remove_snapshot(&self.wal_path, container_id).map_err(compaction_error)?;
+
+        self.sync.register(container_id, &compacted)?;
+        container.active = compacted;
+        let previous_segments = std::mem::replace(
+            &mut container.index,
+            SegmentIndex {
+                segments: BTreeMap::from([(
+                    compacted_segment,
+                    Segment {
+                        records: items.len() as u64,
+                        live: items.len() as u64,
+                        bytes,
+                        shadows: BTreeSet::new(),
+                    },
+                )]),
+                keys: Some(
+                    items
+                        .iter()
+                        .map(|(key, _)| (*key, compacted_segment))
+                        .collect(),
+                ),
+                reclaimable: false,
+                snapshot_segment: None,
+            },
+        )
+        .segments;
+        container.stats = WalStats {
+            records: items.len() as u64,
+            dead_records: 0,
+            bytes,
+            base_bytes: bytes,
+        };
+        container.tail_bytes = bytes;
+        for segment in previous_segments.into_keys() {
+            remove_segment(&self.wal_path, container_id, segment).map_err(compaction_error)?;
+        }
+
+        Ok(previous_bytes.saturating_sub(bytes))
+    }
+
+    // snapshot_container(&container_id)
+    // Write the live items of the container along with the log position they cover.
+    //
+    //   snapshot_container(id)
+    //        │
+    //        ▼
+    //   replay log -> fsync the segments written since the previous snapshot
+    //        │
+    //        ▼
+    //   write items to wal_path/id/snapshot.tmp -> fsync -> rename over wal_path/id/snapshot
+    //        │
+    //        ▼
+    //   fsync wal_path/id -> delete the segments now covered, if dead
+    //
+    // The log is synced first: a crash losing records the snapshot covers would have
+    // later records written where the snapshot expects them.
+    fn snapshot_container(&mut self, container_id: &String) -> MoraResult<u64> {
+        let snapshot_error = |e: std::io::Error| {
+            MoraError::StorageError(StorageError::SnapshotFailed(e.to_string()))
+        };
+
+        let items = self.get_all_items(container_id)?;
+        let container = self
+            .containers
+            .get_mut(container_id)
+            .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
+                container_id.to_string(),
+            )))?;
+        let active = container.index.active_segment();
+        let previous_snapshot = container.index.snapshot_segment.unwrap_or_default();
+        for (segment, _) in container.index.segments.range(previous_snapshot..active) {
+            File::open(segment_path(&self.wal_path, container_id, *segment))
+                .and_then(|file| file.sync_data())
+                .map_err(snapshot_error)?;
         }
+        container.active.sync_data().map_err(snapshot_error)?;
+
+        let offset = container
+            .index
+            .segments
+            .get(&active)
+            .map_or(HEADER_BYTES as u64, |segment| segment.bytes);
+        let segments = container
+            .index
+            .segments
+            .iter()
+            .map(|(id, segment)| SnapshotSegment {
+                id: *id,
+                records: segment.records,
+                shadows: segment.shadows.iter().copied().collect(),
+            })
+            .collect::<Vec<_>>();
+        let keys = container.index.keys.get_or_insert_with(HashMap::new);
+        let path = container_path(&self.wal_path, container_id);
+        let staging_path = format!("{path}/{SNAPSHOT_STAGING_FILE}");
+        snapshot::write(
+            &staging_path,
+            active,
+            offset,
+            &segments,
+            items.iter().map(|(key, item)| {
+                let segment = keys.get(key).copied().unwrap_or(active);
+                (key, segment, item.as_slice())
+            }),
+        )
+        .and_then(|_| std::fs::rename(&staging_path, snapshot_path(&self.wal_path, container_id)))
+        .and_then(|_| sync_directory(&path))
+        .map_err(snapshot_error)?;
+
+        container.index.snapshot_segment = Some(active);
+        container.index.reclaimable = true;
+        container.tail_bytes = 0;
+        self.reclaim_segments(container_id);
+        Ok(items.len() as u64)
     }
 }
 
-const SORT_KEY_BYTES: usize = 16;
+const WAL_MAGIC: [u8; 4] = *b"MWAL";
+const WAL_VERSION: u32 = 1;
+const HEADER_BYTES: usize = 8;
+const SORT_KEY_BYTES: usize = EventKey::BYTES;
 const ITEM_DESCRIPTOR_BYTES: usize = 1;
 const ITEM_LENGTH_BYTES: usize = 8;
+const CHECKSUM_BYTES: usize = 4;
 
 /// WAL file storage design notes
 ///
-/// File layout per container:
+/// Directory layout per container, segments being numbered in write order:
+///
+///   wal_path/<container_id>/00000000000000000000.wal
+///   wal_path/<container_id>/00000000000000000001.wal
+///   ...
+///
+/// Records are appended to the last segment, which is rolled over once it reaches
+/// `segment_max_bytes`. Replay reads the segments in order.
 ///
-///   wal_path/<container_id>.wal
+/// File layout per segment:
 ///        ┌──────────────────────────────────────────────────────────────┐
-///        │ Record 1 │ Record 2 │ ... │ Record N                         │
+///        │ Header │ Record 1 │ Record 2 │ ... │ Record N                │
 ///        └──────────────────────────────────────────────────────────────┘
 ///
-/// Each record is a framed entry containing the sort key and payload:
+/// The header identifies the file and its format:
+///        ┌─────────────────────┬────────────────┐
+///        │ magic "MWAL" (4B)   │ version (4B)   │
+///        └─────────────────────┴────────────────┘
+///
+/// Each record is a framed entry containing the sort key and payload.
+/// The key is the event timestamp (16B) followed by the event id (16B), so
+/// events scheduled for the same instant never share a key. Every record ends
+/// with the CRC32C of its preceding bytes:
 /// Item:
-///        ┌────────────┬───────────────────────┬──────────────────┬─────────────────┐
-///        │ key (16B)  │ item_descriptor (1B)  │ item_length (4B) │ item (variable) │
-///        └────────────┴───────────────────────┴──────────────────┴─────────────────┘
+///        ┌────────────┬───────────────────────┬──────────────────┬─────────────────┬────────────┐
+///        │ key (32B)  │ item_descriptor (1B)  │ item_length (8B) │ item (variable) │ crc32c (4B)│
+///        └────────────┴───────────────────────┴──────────────────┴─────────────────┴────────────┘
 ///
 /// Tombstone:
-///        ┌────────────┬──────────────────────┐
-///        │ key (16B)  │ item_descriptor (1B) │
-///        └────────────┴──────────────────────┘
+///        ┌────────────┬──────────────────────┬────────────┐
+///        │ key (32B)  │ item_descriptor (1B) │ crc32c (4B)│
+///        └────────────┴──────────────────────┴────────────┘
+///
+/// Batch, holding item records stored atomically, the key being the one of the
+/// first item. A batch is replayed whole or not at all:
+///        ┌────────────┬──────────────────────┬───────────────────┬───────────────────────────┬────────────┐
+///        │ key (32B)  │ item_descriptor (1B) │ batch_length (8B) │ item records (variable)   │ crc32c (4B)│
+///        └────────────┴──────────────────────┴───────────────────┴───────────────────────────┴────────────┘
+///
+/// A crash can leave the last record of a segment partially written: replay
+/// truncates it. Invalid records followed by valid data are reported as corruption
+/// instead.
+///
+/// Once replayed, the segment of every live item is known: sealed segments whose
+/// items were all overwritten or deleted are deleted whole, as long as the segments
+/// holding the items their records shadow are already gone.
+///
+/// Containers whose log grew enough are snapshotted to wal_path/<container_id>/snapshot,
+/// see `snapshot::Snapshot`: loading a container reads its snapshot and only replays
+/// the records written after it. Segments the snapshot doesn't cover whole are kept.
 impl Storage for WalFileStorage {
     type ContainerId = String;
 
-    type SortKey = u128;
+    type SortKey = EventKey;
 
     type Item = Vec<u8>;
 
     // load()
-    // Initialize storage from disk.
+    // Initialize storage from disk, configured from the environment.
     //
     //   Startup -> load()
     //                │
     //                ▼
-    //        scan wal_path for files
-    //                │
-    //                ▼
-    //      open file handles -> populate self.wals -> Ok(self)
+    //        WalFileStorageConfig::load() -> open(config)
     fn load() -> MoraResult<Self>
     where
         Self: Sized,
     {
-        let config = WalFileStorageConfig::load()?;
-        let mut storage = Self::new(config.wal_path.to_owned());
-
-        if !Path::new(&config.wal_path).exists() {
-            std::fs::create_dir_all(&config.wal_path).map_err(|e| {
-                MoraError::StorageError(StorageError::DirectoryCreationFailed(
-                    config.wal_path.to_string(),
-                    e.to_string(),
-                ))
-            })?;
-        } else {
-            // List all wal files in the wal_path directory
-            let mut wal_files = std::fs::read_dir(config.wal_path).map_err(|e| {
-                MoraError::StorageError(StorageError::DirectoryReadFailed(e.to_string()))
-            })?;
-
-            while let Some(Ok(wal_file)) = wal_files.next() {
-                let file_handle = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .open(wal_file.path())
-                    .map_err(|e| {
-                        MoraError::StorageError(StorageError::FileReadFailed(e.to_string()))
-                    })?;
-                storage.wals.insert(
-                    wal_file
-                        .file_name()
-                        .to_string_lossy()
-                        .to_string()
-                        .replace(".wal", ""),
-                    BufWriter::new(file_handle),
-                );
-            }
-        }
-
-        Ok(storage)
+        Self::open(WalFileStorageConfig::load()?)
     }
 
     // create_container(&container_id)
-    // Create an empty WAL file for the container if it doesn't exist.
+    // Create the container directory with an empty first segment.
     //
     //   create_container(id)
     //        │
     //        ▼
-    //   wal_path directory exists? ── yes ─▶ go through the flow
-    //        │ no
-    //        ▼
-    //   create directory -> create file -> insert handle in self.wals -> Ok(())
-    //        │
-    //        ▼
-    //   wal_path/id.wal exists? ── yes ─▶ Err(MoraError::StorageError(ContainerAlreadyExists(id)))
+    //   wal_path/id exists? ── yes ─▶ Err(MoraError::StorageError(ContainerAlreadyExists(id)))
     //        │ no
     //        ▼
-    //   create file -> insert handle in self.wals -> Ok(())
+    //   create directory -> create segment 0 -> insert container -> Ok(())
     fn create_container(&mut self, container_id: &Self::ContainerId) -> MoraResult<()> {
-        if self.wals.contains_key(container_id) {
-            return Err(MoraError::StorageError(
-                StorageError::ContainerAlreadyExists(container_id.to_string()),
-            ));
-        }
-
-        if Path::new(&format!("{}/{}", self.wal_path, container_id)).exists() {
+        let path = self.container_path(container_id);
+        if self.containers.contains_key(container_id) || Path::new(&path).exists() {
             return Err(MoraError::StorageError(
                 StorageError::ContainerAlreadyExists(container_id.to_string()),
             ));
         }
 
-        let file = File::create(format!("{}/{}", self.wal_path, container_id)).map_err(|e| {
+        let creation_error = |e: std::io::Error| {
             MoraError::StorageError(StorageError::ContainerCreationFailed(e.to_string()))
-        })?;
+        };
+        std::fs::create_dir(&path).map_err(creation_error)?;
+        let file = create_segment(&segment_path(&self.wal_path, container_id, 0))
+            .map_err(creation_error)?;
 
-        self.wals.insert(container_id.clone(), BufWriter::new(file));
+        self.sync.register(container_id, &file)?;
+        self.sync.wrote(container_id);
+        self.sync.wrote_directory(&path);
+        self.sync.wrote_directory(&self.wal_path);
+        self.containers.insert(
+            container_id.clone(),
+            Container {
+                active: file,
+                index: SegmentIndex {
+                    segments: BTreeMap::from([(
+                        0,
+                        Segment {
+                            bytes: HEADER_BYTES as u64,
+                            ..Default::default()
+                        },
+                    )]),
+                    keys: Some(HashMap::new()),
+                    reclaimable: false,
+                    snapshot_segment: None,
+                },
+                stats: WalStats {
+                    bytes: HEADER_BYTES as u64,
+                    base_bytes: HEADER_BYTES as u64,
+                    ..Default::default()
+                },
+                tail_bytes: HEADER_BYTES as u64,
+            },
+        );
         Ok(())
     }
 
     // delete_container(&container_id)
-    // Remove container WAL (logically: close handle, delete file).
+    // Remove the container directory along with its segments.
     //
     //   delete_container(id)
     //        │
     //        ▼
-    //   close handle (if open)
-    //        │
-    //        ▼
-    //   fs remove wal_path/id.wal -> Ok(())
+    //   fs remove wal_path/id -> close handle -> Ok(())
     fn delete_container(&mut self, container_id: &Self::ContainerId) -> MoraResult<()> {
-        let file = self
-            .wals
-            .get_mut(container_id)
-            .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
+        if !self.containers.contains_key(container_id) {
+            return Err(MoraError::StorageError(StorageError::ContainerNotFound(
                 container_id.to_string(),
-            )))?;
-        file.flush().map_err(|e| {
-            MoraError::StorageError(StorageError::ContainerDeletionFailed(e.to_string()))
-        })?;
+            )));
+        }
 
-        std::fs::remove_file(format!("{}/{}", self.wal_path, container_id)).map_err(|e| {
+        std::fs::remove_dir_all(self.container_path(container_id)).map_err(|e| {
             MoraError::StorageError(StorageError::ContainerDeletionFailed(e.to_string()))
         })?;
 
-        self.wals.remove(container_id);
+        self.containers.remove(container_id);
+        self.sync.deleted(container_id);
+        self.sync.wrote_directory(&self.wal_path);
         Ok(())
     }
 
@@ -212,7 +992,7 @@ impl Storage for WalFileStorage {
     //        ▼
     //   scan wal_path/*.wal -> collect ids -> return slice/collection
     fn list_containers(&self) -> MoraResult<Vec<Self::ContainerId>> {
-        Ok(self.wals.keys().cloned().collect())
+        Ok(self.containers.keys().cloned().collect())
     }
 
     // delete_item(&container_id, &sort_key)
@@ -221,7 +1001,7 @@ impl Storage for WalFileStorage {
     //   delete_item(id, k)
     //        │
     //        ▼
-    //   seek end -> write [key_len|key|item_len=0|<tombstone-flag>]
+    //   append [key|<tombstone-flag>|crc32c] to the active segment
     //        │
     //        ▼
     //   flush -> Ok(())
@@ -230,25 +1010,15 @@ impl Storage for WalFileStorage {
         container_id: &Self::ContainerId,
         item_sort_key: &Self::SortKey,
     ) -> MoraResult<()> {
-        let file_buffer = self
-            .wals
-            .get_mut(container_id)
-            .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
-                container_id.to_string(),
-            )))?
-            .get_mut();
-
-        let mut buffer = Vec::with_capacity(SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES);
-        insert_delete_item_op_to_buffer(&mut buffer, *item_sort_key);
-        file_buffer
-            .write_all(&buffer)
-            .map_err(|e| MoraError::StorageError(StorageError::ItemWriteFailed(e.to_string())))?;
-
-        file_buffer
-            .flush()
-            .map_err(|e| MoraError::StorageError(StorageError::ItemWriteFailed(e.to_string())))?;
-
-        Ok(())
+        let mut buffer =
+            Vec::with_capacity(SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES + CHECKSUM_BYTES);
+        insert_delete_item_op_to_buffer(&mut buffer, item_sort_key);
+        self.append(
+            container_id,
+            &buffer,
+            std::slice::from_ref(item_sort_key),
+            false,
+        )
     }
 
     // store_item(&container_id, &sort_key, &item)
@@ -257,111 +1027,149 @@ impl Storage for WalFileStorage {
     //   store_item(id, k, v)
     //        │
     //        ▼
-    //   ensure wal file exists -> open handle
+    //   append [key|<item-flag>|item_len|item|crc32c] to the active segment
     //        │
     //        ▼
-    //   append [key_len|key|item_len|item]
-    //        │
-    //        ▼
-    //   flush/fsync -> Ok(())
+    //   flush -> Ok(()), fsynced as configured by durability_ticket()
     fn store_item(
         &mut self,
         container_id: &Self::ContainerId,
         item_sort_key: &Self::SortKey,
         item: &Self::Item,
     ) -> MoraResult<()> {
-        let file_buffer = self
-            .wals
-            .get_mut(container_id)
-            .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
-                container_id.to_string(),
-            )))?
-            .get_mut();
-
         let mut buffer = Vec::new();
-        insert_add_item_op_to_buffer(&mut buffer, *item_sort_key, item);
-
-        file_buffer
-            .seek(std::io::SeekFrom::End(0))
-            .map_err(|e| MoraError::StorageError(StorageError::ItemWriteFailed(e.to_string())))?;
-
-        file_buffer
-            .write_all(&buffer)
-            .map_err(|e| MoraError::StorageError(StorageError::ItemWriteFailed(e.to_string())))?;
+        insert_add_item_op_to_buffer(&mut buffer, item_sort_key, item);
+        self.append(
+            container_id,
+            &buffer,
+            std::slice::from_ref(item_sort_key),
+            true,
+        )
+    }
 
-        file_buffer
-            .flush()
-            .map_err(|e| MoraError::StorageError(StorageError::ItemWriteFailed(e.to_string())))?;
-
-        Ok(())
+    // store_items(&container_id, &items)
+    // Store items atomically by appending a single batch record at EOF.
+    //
+    //   store_items(id, [(k, v), ...])
+    //        │
+    //        ▼
+    //   append [first key|<batch-flag>|batch_len|item records|crc32c] to the active segment
+    //        │
+    //        ▼
+    //   flush -> Ok(()), fsynced as configured by durability_ticket()
+    //
+    // A torn batch fails its checksum, so replay truncates it whole.
+    fn store_items(
+        &mut self,
+        container_id: &Self::ContainerId,
+        items: &[(Self::SortKey, Self::Item)],
+    ) -> MoraResult<()> {
+        let Some(((first_key, _), _)) = items.split_first() else {
+            if self.containers.contains_key(container_id) {
+                return Ok(());
+            }
+            return Err(MoraError::StorageError(StorageError::ContainerNotFound(
+                container_id.to_string(),
+            )));
+        };
+        let mut buffer = Vec::new();
+        insert_add_items_op_to_buffer(&mut buffer, first_key, items);
+        let keys = items.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        self.append(container_id, &buffer, &keys, true)
     }
 
+    // get_all_items(&container_id)
+    // Load the container snapshot and replay the log it doesn't cover, segment by segment.
+    //
+    //   get_all_items(id)
+    //        │
+    //        ▼
+    //   valid snapshot? ── yes ─▶ load its items, replay from the position it covers
+    //        │ no
+    //        ▼
+    //   for each segment: check header -> read records in order, applying items and tombstones
+    //        │
+    //        ▼
+    //   invalid record? ── at the end of the segment ─▶ truncate it
+    //        │ followed by more data
+    //        ▼
+    //   Err(MoraError::StorageError(CorruptedRecord(id, offset)))
     fn get_all_items(
         &mut self,
         container_id: &Self::ContainerId,
     ) -> MoraResult<HashMap<Self::SortKey, Self::Item>> {
-        let file_buffer = self
-            .wals
+        let container = self
+            .containers
             .get_mut(container_id)
             .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
                 container_id.to_string(),
-            )))?
-            .get_mut();
+            )))?;
 
         let mut items = HashMap::new();
-        let mut buffer = [0_u8; SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES];
-        let mut offset = 0;
-        while let Ok(_) = file_buffer
-            .seek(std::io::SeekFrom::Start(offset))
-            .and_then(|_| file_buffer.read_exact(&mut buffer))
-        {
-            // read header frame into separate variables
-            let sort_key = &buffer[..SORT_KEY_BYTES];
-            let mut sort_key_bytes_buf = [0; SORT_KEY_BYTES];
-            sort_key_bytes_buf.copy_from_slice(&sort_key);
-            let sort_key_u128 = u128::from_le_bytes(sort_key_bytes_buf);
-            dbg!("sort_key_u128: {:?}", sort_key_u128);
-
-            let item_descriptor_bytes =
-                &buffer[SORT_KEY_BYTES..SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES];
-            dbg!("item_descriptor_bytes: {:?}", item_descriptor_bytes);
-            offset += buffer.len() as u64;
-
-            match Into::<ItemDescriptor>::into(item_descriptor_bytes) {
-                ItemDescriptor::Item => {
-                    let mut item_length_buffer = [0_u8; ITEM_LENGTH_BYTES];
-                    file_buffer
-                        .seek(std::io::SeekFrom::Start(offset))
-                        .and_then(|_| file_buffer.read_exact(&mut item_length_buffer))
-                        .map_err(|e| {
-                            MoraError::StorageError(StorageError::ItemReadFailed(e.to_string()))
-                        })?;
-                    offset += item_length_buffer.len() as u64;
-                    let item_length =
-                        u64::from_le_bytes(item_length_buffer.try_into().map_err(|_| {
-                            MoraError::StorageError(StorageError::ItemReadFailed(
-                                "invalid item length".to_string(),
-                            ))
-                        })?);
-                    dbg!("item_length: {:?}", item_length);
-                    // read item into separate buffer
-                    let mut item_buffer = vec![0_u8; item_length as usize];
-                    file_buffer
-                        .seek(std::io::SeekFrom::Start(offset))
-                        .and_then(|_| file_buffer.read_exact(&mut item_buffer))
-                        .map_err(|e| {
-                            MoraError::StorageError(StorageError::ItemReadFailed(e.to_string()))
-                        })?;
-                    offset += item_buffer.len() as u64;
-                    dbg!("item_buffer: {:?}", &item_buffer);
-                    items.insert(sort_key_u128, item_buffer);
-                }
-                ItemDescriptor::Tombstone => {
-                    items.remove(&sort_key_u128);
+        let mut index = SegmentIndex {
+            keys: Some(HashMap::new()),
+            ..Default::default()
+        };
+        let (start_segment, start_offset) =
+            match load_snapshot(&self.wal_path, container_id, &container.index) {
+                Some(snapshot) => {
+                    let start = (snapshot.segment, snapshot.offset);
+                    index.restore(snapshot, &container.index, &mut items);
+                    start
                 }
+                None => (0, 0),
+            };
+
+        let active = container.index.active_segment();
+        let mut tail_bytes = 0;
+        let tail = container
+            .index
+            .segments
+            .range(start_segment..)
+            .map(|(segment, _)| *segment)
+            .collect::<Vec<_>>();
+        for segment in tail {
+            let start = if segment == start_segment {
+                start_offset
+            } else {
+                0
+            };
+            index.segments.entry(segment).or_default();
+            let mut apply = |key: EventKey, item: Option<Vec<u8>>| {
+                index.record(&key, item.is_some());
+                match item {
+                    Some(item) => items.insert(key, item),
+                    None => items.remove(&key),
+                };
+            };
+            let bytes = if segment == active {
+                replay(container_id, &mut container.active, start, &mut apply)?
+            } else {
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(segment_path(&self.wal_path, container_id, segment))
+                    .map_err(|e| {
+                        MoraError::StorageError(StorageError::FileReadFailed(e.to_string()))
+                    })?;
+                replay(container_id, &mut file, start, &mut apply)?
+            };
+            if let Some(segment) = index.segments.get_mut(&segment) {
+                segment.bytes = bytes;
             }
+            tail_bytes += bytes.saturating_sub(start);
         }
 
+        let records = index.segments.values().map(|segment| segment.records).sum();
+        container.stats.records = records;
+        container.stats.dead_records = records - items.len() as u64;
+        container.stats.bytes = index.segments.values().map(|segment| segment.bytes).sum();
+        // Sealed segments may hold dead records only.
+        index.reclaimable = true;
+        container.index = index;
+        container.tail_bytes = tail_bytes;
+
+        self.reclaim_segments(container_id);
         Ok(items)
     }
 
@@ -370,39 +1178,949 @@ impl Storage for WalFileStorage {
         container_id: &Self::ContainerId,
         item_sort_keys: &[Self::SortKey],
     ) -> MoraResult<()> {
-        let file_buffer = self
-            .wals
-            .get_mut(container_id)
-            .ok_or(MoraError::StorageError(StorageError::ContainerNotFound(
-                container_id.to_string(),
-            )))?
-            .get_mut();
-
         let mut buffer = Vec::new();
         item_sort_keys
             .iter()
-            .for_each(|key| insert_delete_item_op_to_buffer(&mut buffer, *key));
+            .for_each(|key| insert_delete_item_op_to_buffer(&mut buffer, key));
+        self.append(container_id, &buffer, item_sort_keys, false)
+    }
 
-        file_buffer
-            .write_all(&buffer)
-            .map_err(|e| MoraError::StorageError(StorageError::ItemWriteFailed(e.to_string())))?;
+    // compact()
+    // Compact the containers whose log crossed the compaction thresholds.
+    //
+    //   compact()
+    //        │
+    //        ▼
+    //   for each container: too many dead records or grown too much? ── no ─▶ skip
+    //        │ yes
+    //        ▼
+    //   compact_container(id) -> sum reclaimed bytes -> Ok(stats)
+    //
+    // Compaction is synchronous: writes to the storage wait for it to complete.
+    fn compact(&mut self) -> MoraResult<CompactionStats> {
+        let containers = self
+            .containers
+            .iter()
+            .filter(|(_, container)| {
+                container
+                    .stats
+                    .needs_compaction(&self.compaction_thresholds)
+            })
+            .map(|(container_id, _)| container_id.clone())
+            .collect::<Vec<_>>();
 
-        file_buffer
-            .flush()
-            .map_err(|e| MoraError::StorageError(StorageError::ItemWriteFailed(e.to_string())))?;
+        let mut compaction_stats = CompactionStats::default();
+        for container_id in containers {
+            compaction_stats.reclaimed_bytes += self.compact_container(&container_id)?;
+            compaction_stats.containers += 1;
+        }
+        Ok(compaction_stats)
+    }
 
-        Ok(())
+    // snapshot()
+    // Snapshot the containers whose log grew enough since their last snapshot.
+    //
+    //   snapshot()
+    //        │
+    //        ▼
+    //   for each container: tail_bytes >= snapshot_tail_bytes? ── no ─▶ skip
+    //        │ yes
+    //        ▼
+    //   snapshot_container(id) -> sum items -> Ok(stats)
+    fn snapshot(&mut self) -> MoraResult<SnapshotStats> {
+        let containers = self
+            .containers
+            .iter()
+            .filter(|(_, container)| container.tail_bytes >= self.snapshot_tail_bytes)
+            .map(|(container_id, _)| container_id.clone())
+            .collect::<Vec<_>>();
+
+        let mut snapshot_stats = SnapshotStats::default();
+        for container_id in containers {
+            snapshot_stats.items += self.snapshot_container(&container_id)?;
+            snapshot_stats.containers += 1;
+        }
+        Ok(snapshot_stats)
+    }
+
+    // durability_ticket()
+    // Wait for the writes made so far to be synced, as configured.
+    //
+    //   durability_ticket() -> await
+    //        │
+    //        ▼
+    //   never? ── yes ─▶ Ok(())
+    //        │ no
+    //        ▼
+    //   already synced? ── yes ─▶ Ok(())
+    //        │ no
+    //        ▼
+    //   sync in progress or interval not elapsed? ── yes ─▶ wait for it, retry
+    //        │ no
+    //        ▼
+    //   fsync every written file -> wake up all waiters
+    fn durability_ticket(&self) -> DurabilityTicket {
+        self.sync.ticket()
     }
 }
 
-fn insert_delete_item_op_to_buffer(buffer: &mut Vec<u8>, key: u128) {
-    buffer.extend_from_slice(&key.to_le_bytes());
+fn container_path(wal_path: &str, container_id: &str) -> String {
+    format!("{wal_path}/{container_id}")
+}
+
+fn segment_path(wal_path: &str, container_id: &str, segment: u64) -> String {
+    format!("{wal_path}/{container_id}/{segment:020}{SEGMENT_EXTENSION}")
+}
+
+fn snapshot_path(wal_path: &str, container_id: &str) -> String {
+    format!("{wal_path}/{container_id}/{SNAPSHOT_FILE}")
+}
+
+/// Reads the snapshot of a container, deleting it if it can't be used with the
+/// segments of `on_disk`.
+fn load_snapshot(wal_path: &str, container_id: &str, on_disk: &SegmentIndex) -> Option<Snapshot> {
+    match snapshot::read(&snapshot_path(wal_path, container_id)) {
+        Ok(None) => return None,
+        Ok(Some(snapshot))
+            if on_disk
+                .segments
+                .get(&snapshot.segment)
+                .is_some_and(|segment| segment.bytes >= snapshot.offset) =>
+        {
+            return Some(snapshot)
+        }
+        Ok(Some(_)) => warn!("discarding the snapshot of {container_id}, its log was rewritten"),
+        Err(e) => warn!("discarding the snapshot of {container_id}: {e}"),
+    }
+    if let Err(e) = remove_snapshot(wal_path, container_id) {
+        warn!("error deleting the snapshot of {container_id}: {e}");
+    }
+    None
+}
+
+fn remove_snapshot(wal_path: &str, container_id: &str) -> std::io::Result<()> {
+    match std::fs::remove_file(snapshot_path(wal_path, container_id)) {
+        Ok(()) => sync_directory(&container_path(wal_path, container_id)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn create_segment(path: &str) -> std::io::Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    file.write_all(&header())?;
+    Ok(file)
+}
+
+/// Deletes a segment for good: a segment coming back after a crash could bring back
+/// the items that a later deleted segment was shadowing.
+fn remove_segment(wal_path: &str, container_id: &str, segment: u64) -> std::io::Result<()> {
+    std::fs::remove_file(segment_path(wal_path, container_id, segment))?;
+    sync_directory(&container_path(wal_path, container_id))
+}
+
+fn sync_directory(path: &str) -> std::io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+fn header() -> [u8; HEADER_BYTES] {
+    let mut header = [0_u8; HEADER_BYTES];
+    header[..4].copy_from_slice(&WAL_MAGIC);
+    header[4..].copy_from_slice(&WAL_VERSION.to_le_bytes());
+    header
+}
+
+/// Key size of the logs written before events had an id, the key being the event
+/// timestamp alone.
+const TIMESTAMP_SORT_KEY_BYTES: usize = 16;
+
+/// Rewrites a log written before segments had a header: its records had no checksum
+/// and, before events had an id, a timestamp as key. Events keyed by a timestamp
+/// alone get id 0. Returns `None` if the log can't be read whole with either key.
+fn upgrade_unversioned_log(log: &[u8]) -> Option<Vec<u8>> {
+    [SORT_KEY_BYTES, TIMESTAMP_SORT_KEY_BYTES]
+        .into_iter()
+        .find_map(|key_bytes| {
+            let mut upgraded = header().to_vec();
+            let mut records = log;
+            while !records.is_empty() {
+                let (key, rest) = records.split_at_checked(key_bytes)?;
+                let key = match key_bytes {
+                    SORT_KEY_BYTES => EventKey::from_bytes(key.try_into().ok()?),
+                    _ => EventKey::new(u128::from_le_bytes(key.try_into().ok()?), 0),
+                };
+                let (descriptor, rest) = rest.split_first()?;
+                records = match ItemDescriptor::try_from(*descriptor).ok()? {
+                    ItemDescriptor::Tombstone => {
+                        insert_delete_item_op_to_buffer(&mut upgraded, &key);
+                        rest
+                    }
+                    ItemDescriptor::Item => {
+                        let (length, rest) = rest.split_at_checked(ITEM_LENGTH_BYTES)?;
+                        let length = u64::from_le_bytes(length.try_into().ok()?);
+                        let (item, rest) = rest.split_at_checked(usize::try_from(length).ok()?)?;
+                        insert_add_item_op_to_buffer(&mut upgraded, &key, item);
+                        rest
+                    }
+                    ItemDescriptor::Batch => return None,
+                };
+            }
+            Some(upgraded)
+        })
+}
+
+fn insert_delete_item_op_to_buffer(buffer: &mut Vec<u8>, key: &EventKey) {
+    let start = buffer.len();
+    buffer.extend_from_slice(&key.to_bytes());
     buffer.push(ItemDescriptor::Tombstone as u8);
+    append_checksum(buffer, start);
 }
 
-fn insert_add_item_op_to_buffer(buffer: &mut Vec<u8>, key: u128, item: &[u8]) {
-    buffer.extend_from_slice(&key.to_le_bytes());
+fn insert_add_item_op_to_buffer(buffer: &mut Vec<u8>, key: &EventKey, item: &[u8]) {
+    let start = buffer.len();
+    buffer.extend_from_slice(&key.to_bytes());
     buffer.push(ItemDescriptor::Item.into());
-    buffer.extend_from_slice(&item.len().to_le_bytes());
+    buffer.extend_from_slice(&(item.len() as u64).to_le_bytes());
     buffer.extend_from_slice(item);
+    append_checksum(buffer, start);
+}
+
+fn insert_add_items_op_to_buffer(
+    buffer: &mut Vec<u8>,
+    first_key: &EventKey,
+    items: &[(EventKey, Vec<u8>)],
+) {
+    let start = buffer.len();
+    buffer.extend_from_slice(&first_key.to_bytes());
+    buffer.push(ItemDescriptor::Batch.into());
+    let length_start = buffer.len();
+    buffer.extend_from_slice(&[0_u8; ITEM_LENGTH_BYTES]);
+    for (key, item) in items {
+        insert_add_item_op_to_buffer(buffer, key, item);
+    }
+    let batch_length = (buffer.len() - length_start - ITEM_LENGTH_BYTES) as u64;
+    buffer[length_start..length_start + ITEM_LENGTH_BYTES]
+        .copy_from_slice(&batch_length.to_le_bytes());
+    append_checksum(buffer, start);
+}
+
+fn append_checksum(buffer: &mut Vec<u8>, record_start: usize) {
+    let checksum = crc32c::crc32c(&buffer[record_start..]);
+    buffer.extend_from_slice(&checksum.to_le_bytes());
+}
+
+/// Why a record could not be replayed.
+enum InvalidRecord {
+    /// The file ends before the record does.
+    Truncated,
+    /// The record length goes past the end of the file. The record is torn unless a
+    /// valid record follows its payload, starting at `payload`: the length is
+    /// corrupted then. `batch` is set for batches, whose item records are skipped.
+    LengthPastEnd { payload: u64, batch: bool },
+    /// The record is complete, up to `end`, but its checksum doesn't match.
+    ChecksumMismatch { end: u64 },
+    /// The record can't even be framed.
+    UnknownDescriptor,
+    /// The checksum of a batch matches, but it doesn't hold item records only.
+    MalformedBatch,
+}
+
+/// Outcome of reading the record at some offset of a log.
+enum ReadRecord {
+    Record {
+        key: EventKey,
+        /// `None` for tombstones.
+        item: Option<Vec<u8>>,
+        bytes: u64,
+    },
+    Batch {
+        items: Vec<(EventKey, Vec<u8>)>,
+        bytes: u64,
+    },
+    End,
+    Invalid(InvalidRecord),
+}
+
+/// Replays a segment from `start`, applying its items and tombstones (`None`) in
+/// order. Returns the offset replay stopped at, i.e. the size of the segment.
+fn replay(
+    container_id: &str,
+    file: &mut File,
+    start: u64,
+    apply: &mut impl FnMut(EventKey, Option<Vec<u8>>),
+) -> MoraResult<u64> {
+    let read_error =
+        |e: std::io::Error| MoraError::StorageError(StorageError::ItemReadFailed(e.to_string()));
+    let file_bytes = file.metadata().map_err(read_error)?.len();
+    file.seek(SeekFrom::Start(0)).map_err(read_error)?;
+
+    let mut reader = BufReader::new(&mut *file);
+    let mut header_buffer = [0_u8; HEADER_BYTES];
+    let header_bytes = read_up_to(&mut reader, &mut header_buffer).map_err(read_error)?;
+    if header_bytes < HEADER_BYTES {
+        drop(reader);
+        if header_buffer[..header_bytes] != header()[..header_bytes] {
+            return Err(MoraError::StorageError(StorageError::InvalidWalFile(
+                container_id.to_string(),
+            )));
+        }
+        // The container was being created: finish writing the header.
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(&header()))
+            .and_then(|_| file.sync_all())
+            .map_err(read_error)?;
+        return Ok(HEADER_BYTES as u64);
+    }
+    if header_buffer[..4] != WAL_MAGIC {
+        return Err(MoraError::StorageError(StorageError::InvalidWalFile(
+            container_id.to_string(),
+        )));
+    }
+    let mut version = [0_u8; 4];
+    version.copy_from_slice(&header_buffer[4..]);
+    let version = u32::from_le_bytes(version);
+    if version != WAL_VERSION {
+        return Err(MoraError::StorageError(
+            StorageError::UnsupportedWalVersion(container_id.to_string(), version),
+        ));
+    }
+
+    let mut offset = start.max(HEADER_BYTES as u64);
+    if offset > HEADER_BYTES as u64 {
+        reader.seek(SeekFrom::Start(offset)).map_err(read_error)?;
+    }
+    let invalid = loop {
+        match read_record(&mut reader, offset, file_bytes).map_err(read_error)? {
+            ReadRecord::End => break None,
+            ReadRecord::Record { key, item, bytes } => {
+                apply(key, item);
+                offset += bytes;
+            }
+            ReadRecord::Batch { items, bytes } => {
+                for (key, item) in items {
+                    apply(key, Some(item));
+                }
+                offset += bytes;
+            }
+            ReadRecord::Invalid(invalid) => break Some(invalid),
+        }
+    };
+    drop(reader);
+
+    if let Some(invalid) = invalid {
+        let torn = match invalid {
+            InvalidRecord::Truncated => true,
+            InvalidRecord::LengthPastEnd { payload, batch } => {
+                !has_record_from(file, payload, batch).map_err(read_error)?
+            }
+            InvalidRecord::ChecksumMismatch { end } => {
+                end == file_bytes || is_zeroed_from(file, offset).map_err(read_error)?
+            }
+            InvalidRecord::UnknownDescriptor => is_zeroed_from(file, offset).map_err(read_error)?,
+            InvalidRecord::MalformedBatch => false,
+        };
+        if !torn {
+            return Err(MoraError::StorageError(StorageError::CorruptedRecord(
+                container_id.to_string(),
+                offset,
+            )));
+        }
+
+        warn!(
+            "truncating partially written record of {} at offset {}",
+            container_id, offset
+        );
+        file.set_len(offset)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| MoraError::StorageError(StorageError::ItemWriteFailed(e.to_string())))?;
+    }
+
+    Ok(offset)
+}
+
+/// Reads the record at `offset`, `file_bytes` being the size of the whole file.
+fn read_record(
+    reader: &mut impl Read,
+    offset: u64,
+    file_bytes: u64,
+) -> std::io::Result<ReadRecord> {
+    let mut record = vec![0_u8; SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES];
+    match read_up_to(reader, &mut record)? {
+        0 => return Ok(ReadRecord::End),
+        n if n < record.len() => return Ok(ReadRecord::Invalid(InvalidRecord::Truncated)),
+        _ => {}
+    }
+
+    let descriptor = ItemDescriptor::try_from(record[SORT_KEY_BYTES]);
+    // Batches are framed as items, their length being the one of their records.
+    let item_length = match descriptor {
+        Ok(ItemDescriptor::Tombstone) => None,
+        Ok(ItemDescriptor::Item | ItemDescriptor::Batch) => {
+            let mut length = [0_u8; ITEM_LENGTH_BYTES];
+            if read_up_to(reader, &mut length)? < ITEM_LENGTH_BYTES {
+                return Ok(ReadRecord::Invalid(InvalidRecord::Truncated));
+            }
+            record.extend_from_slice(&length);
+            Some(u64::from_le_bytes(length))
+        }
+        Err(_) => return Ok(ReadRecord::Invalid(InvalidRecord::UnknownDescriptor)),
+    };
+
+    if let Some(item_length) = item_length {
+        // Never allocate for a length past the end of the file.
+        let payload = offset + record.len() as u64;
+        if item_length > file_bytes.saturating_sub(payload) {
+            return Ok(ReadRecord::Invalid(InvalidRecord::LengthPastEnd {
+                payload,
+                batch: matches!(descriptor, Ok(ItemDescriptor::Batch)),
+            }));
+        }
+        let item_start = record.len();
+        record.resize(item_start + item_length as usize, 0);
+        if read_up_to(reader, &mut record[item_start..])? < item_length as usize {
+            return Ok(ReadRecord::Invalid(InvalidRecord::Truncated));
+        }
+    }
+
+    let mut checksum = [0_u8; CHECKSUM_BYTES];
+    if read_up_to(reader, &mut checksum)? < CHECKSUM_BYTES {
+        return Ok(ReadRecord::Invalid(InvalidRecord::Truncated));
+    }
+    let bytes = (record.len() + CHECKSUM_BYTES) as u64;
+    if crc32c::crc32c(&record) != u32::from_le_bytes(checksum) {
+        return Ok(ReadRecord::Invalid(InvalidRecord::ChecksumMismatch {
+            end: offset + bytes,
+        }));
+    }
+
+    if let Ok(ItemDescriptor::Batch) = descriptor {
+        let records = &record[SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES + ITEM_LENGTH_BYTES..];
+        return read_batch(records, bytes);
+    }
+
+    let mut key = [0_u8; SORT_KEY_BYTES];
+    key.copy_from_slice(&record[..SORT_KEY_BYTES]);
+    let item = item_length
+        .map(|_| record.split_off(SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES + ITEM_LENGTH_BYTES));
+    Ok(ReadRecord::Record {
+        key: EventKey::from_bytes(key),
+        item,
+        bytes,
+    })
+}
+
+/// Reads the item records of a batch, `bytes` being the size of the whole batch.
+fn read_batch(records: &[u8], bytes: u64) -> std::io::Result<ReadRecord> {
+    let mut reader = records;
+    let mut offset = 0;
+    let mut items = Vec::new();
+    loop {
+        match read_record(&mut reader, offset, records.len() as u64)? {
+            ReadRecord::End => return Ok(ReadRecord::Batch { items, bytes }),
+            ReadRecord::Record {
+                key,
+                item: Some(item),
+                bytes: record_bytes,
+            } => {
+                items.push((key, item));
+                offset += record_bytes;
+            }
+            _ => return Ok(ReadRecord::Invalid(InvalidRecord::MalformedBatch)),
+        }
+    }
+}
+
+/// Fills `buffer` as much as the reader allows, returning the bytes read.
+fn read_up_to(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Whether a valid record starts anywhere in the file from `offset`, after the item
+/// records found right at `offset` when `batch` is set.
+fn has_record_from(file: &mut File, offset: u64, batch: bool) -> std::io::Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut rest = Vec::new();
+    file.read_to_end(&mut rest)?;
+    let read_from = |start: usize| read_record(&mut &rest[start..], 0, (rest.len() - start) as u64);
+
+    let mut start = 0;
+    if batch {
+        while let ReadRecord::Record {
+            item: Some(_),
+            bytes,
+            ..
+        } = read_from(start)?
+        {
+            start += bytes as usize;
+        }
+    }
+    for start in start..rest.len() {
+        if let ReadRecord::Record { .. } | ReadRecord::Batch { .. } = read_from(start)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether the file holds only zeros from `offset`, as filesystems may leave after
+/// a crash when the file size was updated but the data wasn't written.
+fn is_zeroed_from(file: &mut File, offset: u64) -> std::io::Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file);
+    let mut buffer = [0_u8; 8192];
+    loop {
+        match read_up_to(&mut reader, &mut buffer)? {
+            0 => return Ok(true),
+            n if buffer[..n].iter().any(|byte| *byte != 0) => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_storage(name: &str) -> WalFileStorage {
+        let path = std::env::temp_dir().join(format!("mora-wal-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        WalFileStorage::new(path.to_string_lossy().to_string())
+    }
+
+    #[test]
+    fn items_scheduled_for_the_same_timestamp_survive_replay() -> MoraResult<()> {
+        let mut storage = temp_storage("same-timestamp");
+        let container = "queue".to_string();
+        storage.create_container(&container)?;
+
+        let first = EventKey::new(42, 1);
+        let second = EventKey::new(42, 2);
+        storage.store_item(&container, &first, &b"first".to_vec())?;
+        storage.store_item(&container, &second, &b"second".to_vec())?;
+        storage.delete_item(&container, &first)?;
+        storage.store_item(&container, &EventKey::new(43, 1), &b"third".to_vec())?;
+
+        let items = storage.get_all_items(&container)?;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items.get(&second), Some(&b"second".to_vec()));
+        assert_eq!(items.get(&EventKey::new(43, 1)), Some(&b"third".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_keeps_live_items_and_reclaims_dead_ones() -> MoraResult<()> {
+        let mut storage =
+            temp_storage("compaction").with_compaction_thresholds(CompactionThresholds {
+                dead_records_ratio: 0.5,
+                min_bytes: 0,
+                growth_bytes: u64::MAX,
+            });
+        let container = "queue".to_string();
+        storage.create_container(&container)?;
+        assert_eq!(storage.compact()?, CompactionStats::default());
+
+        let keys = (0..10).map(|id| EventKey::new(id, id)).collect::<Vec<_>>();
+        for key in &keys {
+            storage.store_item(&container, key, &key.id.to_le_bytes().to_vec())?;
+        }
+        storage.delete_items(&container, &keys[..3])?;
+        // 13 records, 6 of them dead: not enough to compact yet.
+        assert_eq!(storage.compact()?, CompactionStats::default());
+
+        storage.delete_item(&container, &keys[3])?;
+        let stats = storage.compact()?;
+        assert_eq!(stats.containers, 1);
+        assert_eq!(
+            stats.reclaimed_bytes,
+            (4 * (SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES + CHECKSUM_BYTES) * 2
+                + 4 * (ITEM_LENGTH_BYTES + 16)) as u64
+        );
+
+        let items = storage.get_all_items(&container)?;
+        assert_eq!(items.len(), 6);
+        for key in &keys[4..] {
+            assert_eq!(items.get(key), Some(&key.id.to_le_bytes().to_vec()));
+        }
+
+        // The compacted log keeps accepting writes.
+        storage.delete_item(&container, &keys[4])?;
+        assert_eq!(storage.get_all_items(&container)?.len(), 5);
+        Ok(())
+    }
+
+    fn wal_file(storage: &WalFileStorage, container: &str) -> File {
+        let segment = storage.containers[container].index.active_segment();
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(segment_path(&storage.wal_path, container, segment))
+            .unwrap()
+    }
+
+    fn log_with_two_items(name: &str) -> MoraResult<(WalFileStorage, String)> {
+        let mut storage = temp_storage(name);
+        let container = "queue".to_string();
+        storage.create_container(&container)?;
+        storage.store_item(&container, &EventKey::new(1, 1), &b"first".to_vec())?;
+        storage.store_item(&container, &EventKey::new(2, 2), &b"second".to_vec())?;
+        Ok((storage, container))
+    }
+
+    #[test]
+    fn torn_last_record_is_truncated() -> MoraResult<()> {
+        let (mut storage, container) = log_with_two_items("torn")?;
+        let file = wal_file(&storage, &container);
+        let complete = file.metadata().unwrap().len();
+        file.set_len(complete - 3).unwrap();
+
+        let items = storage.get_all_items(&container)?;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items.get(&EventKey::new(1, 1)), Some(&b"first".to_vec()));
+        let truncated = file.metadata().unwrap().len();
+        assert!(truncated < complete - 3);
+
+        // Writes resume after the last complete record.
+        storage.store_item(&container, &EventKey::new(3, 3), &b"third".to_vec())?;
+        assert_eq!(storage.get_all_items(&container)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn torn_batch_is_dropped_whole() -> MoraResult<()> {
+        let (mut storage, container) = log_with_two_items("torn-batch")?;
+        let batch = vec![
+            (EventKey::new(3, 3), b"third".to_vec()),
+            (EventKey::new(4, 4), b"fourth".to_vec()),
+        ];
+        storage.store_items(&container, &batch)?;
+        let file = wal_file(&storage, &container);
+        // Only the last item of the batch is torn.
+        let complete = file.metadata().unwrap().len();
+        file.set_len(complete - 3).unwrap();
+
+        let items = storage.get_all_items(&container)?;
+        assert_eq!(items.len(), 2);
+        assert!(!items.contains_key(&EventKey::new(3, 3)));
+        Ok(())
+    }
+
+    #[test]
+    fn zeroed_tail_is_truncated() -> MoraResult<()> {
+        let (mut storage, container) = log_with_two_items("zeroed")?;
+        let file = wal_file(&storage, &container);
+        let complete = file.metadata().unwrap().len();
+        file.set_len(complete + 100).unwrap();
+
+        assert_eq!(storage.get_all_items(&container)?.len(), 2);
+        assert_eq!(file.metadata().unwrap().len(), complete);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_record_followed_by_data_is_reported() -> MoraResult<()> {
+        let (mut storage, container) = log_with_two_items("corrupted")?;
+        let mut file = wal_file(&storage, &container);
+        // Flip a byte of the first item payload.
+        let offset =
+            (HEADER_BYTES + SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES + ITEM_LENGTH_BYTES) as u64;
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(b"F").unwrap();
+
+        assert!(matches!(
+            storage.get_all_items(&container),
+            Err(MoraError::StorageError(StorageError::CorruptedRecord(_, at))) if at == HEADER_BYTES as u64
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_length_followed_by_data_is_reported() -> MoraResult<()> {
+        let (mut storage, container) = log_with_two_items("corrupted-length")?;
+        let mut file = wal_file(&storage, &container);
+        // The length of the first item goes past the end of the file.
+        let offset = (HEADER_BYTES + SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES) as u64;
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&1000_u64.to_le_bytes()).unwrap();
+        let complete = file.metadata().unwrap().len();
+
+        assert!(matches!(
+            storage.get_all_items(&container),
+            Err(MoraError::StorageError(StorageError::CorruptedRecord(_, at))) if at == HEADER_BYTES as u64
+        ));
+        assert_eq!(file.metadata().unwrap().len(), complete);
+        Ok(())
+    }
+
+    #[test]
+    fn torn_payload_of_last_record_is_truncated() -> MoraResult<()> {
+        let (mut storage, container) = log_with_two_items("torn-payload")?;
+        let file = wal_file(&storage, &container);
+        // Only the first bytes of the last item were written.
+        let complete = file.metadata().unwrap().len();
+        file.set_len(complete - CHECKSUM_BYTES as u64 - 4).unwrap();
+
+        let items = storage.get_all_items(&container)?;
+        assert_eq!(items.len(), 1);
+        assert!(items.contains_key(&EventKey::new(1, 1)));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() -> MoraResult<()> {
+        let (mut storage, container) = log_with_two_items("version")?;
+        let mut file = wal_file(&storage, &container);
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write_all(&2_u32.to_le_bytes()).unwrap();
+
+        assert!(matches!(
+            storage.get_all_items(&container),
+            Err(MoraError::StorageError(
+                StorageError::UnsupportedWalVersion(_, 2)
+            ))
+        ));
+        Ok(())
+    }
+
+    /// Opens the storage again, as the server does on restart.
+    fn reopen(storage: WalFileStorage) -> MoraResult<WalFileStorage> {
+        WalFileStorage::open(WalFileStorageConfig {
+            wal_path: storage.wal_path.clone(),
+            compaction_thresholds: storage.compaction_thresholds,
+            durability: Durability::default(),
+            segment_max_bytes: storage.segment_max_bytes,
+            snapshot_tail_bytes: storage.snapshot_tail_bytes,
+        })
+    }
+
+    fn segments_on_disk(storage: &WalFileStorage, container: &str) -> Vec<u64> {
+        let mut segments = std::fs::read_dir(storage.container_path(container))
+            .unwrap()
+            .filter_map(|entry| {
+                let file_name = entry.unwrap().file_name().to_string_lossy().to_string();
+                file_name.strip_suffix(SEGMENT_EXTENSION)?.parse().ok()
+            })
+            .collect::<Vec<u64>>();
+        segments.sort_unstable();
+        segments
+    }
+
+    /// Segments fit two records of 5 bytes items, and a new segment is rolled over
+    /// every two items or tombstones stored one at a time.
+    fn segmented_storage(name: &str) -> MoraResult<(WalFileStorage, String)> {
+        let item_record_bytes =
+            SORT_KEY_BYTES + ITEM_DESCRIPTOR_BYTES + ITEM_LENGTH_BYTES + 5 + CHECKSUM_BYTES;
+        let mut storage = temp_storage(name)
+            .with_segment_max_bytes((HEADER_BYTES + 2 * item_record_bytes) as u64);
+        let container = "queue".to_string();
+        storage.create_container(&container)?;
+        Ok((storage, container))
+    }
+
+    #[test]
+    fn segments_roll_over_and_replay_in_order() -> MoraResult<()> {
+        let (mut storage, container) = segmented_storage("rollover")?;
+        for id in 0..10 {
+            storage.store_item(&container, &EventKey::new(id, id), &b"first".to_vec())?;
+        }
+        storage.store_item(&container, &EventKey::new(3, 3), &b"later".to_vec())?;
+        storage.delete_items(&container, &[EventKey::new(5, 5), EventKey::new(6, 6)])?;
+        assert_eq!(
+            segments_on_disk(&storage, &container),
+            vec![0, 1, 2, 3, 4, 5, 6]
+        );
+
+        let mut storage = reopen(storage)?;
+        let items = storage.get_all_items(&container)?;
+        assert_eq!(items.len(), 8);
+        assert_eq!(items.get(&EventKey::new(3, 3)), Some(&b"later".to_vec()));
+        assert!(!items.contains_key(&EventKey::new(5, 5)));
+        assert!(!items.contains_key(&EventKey::new(6, 6)));
+        Ok(())
+    }
+
+    #[test]
+    fn segments_holding_dead_records_only_are_deleted() -> MoraResult<()> {
+        let (mut storage, container) = segmented_storage("reclaim")?;
+        for id in 0..4 {
+            storage.store_item(&container, &EventKey::new(id, id), &b"first".to_vec())?;
+        }
+        storage.delete_item(&container, &EventKey::new(0, 0))?;
+        storage.delete_items(&container, &[EventKey::new(2, 2), EventKey::new(3, 3)])?;
+        // The second segment is dead, but the third one deletes an item of the first
+        // one, which would come back if it was deleted.
+        assert_eq!(segments_on_disk(&storage, &container), vec![0, 2, 3]);
+
+        let mut storage = reopen(storage)?;
+        let items = storage.get_all_items(&container)?;
+        assert_eq!(items.keys().collect::<Vec<_>>(), vec![&EventKey::new(1, 1)]);
+        assert_eq!(segments_on_disk(&storage, &container), vec![0, 2, 3]);
+
+        storage.delete_item(&container, &EventKey::new(1, 1))?;
+        assert_eq!(segments_on_disk(&storage, &container), vec![4]);
+        assert!(reopen(storage)?.get_all_items(&container)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn single_file_logs_become_the_first_segment() -> MoraResult<()> {
+        let (storage, container) = log_with_two_items("migration")?;
+        let path = storage.container_path(&container);
+        let single_file = format!("{path}.log");
+        std::fs::rename(segment_path(&storage.wal_path, &container, 0), &single_file).unwrap();
+        std::fs::remove_dir(&path).unwrap();
+        std::fs::rename(&single_file, &path).unwrap();
+
+        let mut storage = reopen(storage)?;
+        assert_eq!(segments_on_disk(&storage, &container), vec![0]);
+        assert_eq!(storage.get_all_items(&container)?.len(), 2);
+        storage.store_item(&container, &EventKey::new(3, 3), &b"third".to_vec())?;
+        assert_eq!(storage.get_all_items(&container)?.len(), 3);
+        Ok(())
+    }
+
+    fn unversioned_log(key_bytes: usize) -> Vec<u8> {
+        let mut log = vec![];
+        for (timestamp, item) in [(1_u128, b"first".as_slice()), (2, b"second")] {
+            log.extend_from_slice(&EventKey::new(timestamp, timestamp).to_bytes()[..key_bytes]);
+            log.push(ItemDescriptor::Item.into());
+            log.extend_from_slice(&(item.len() as u64).to_le_bytes());
+            log.extend_from_slice(item);
+        }
+        log.extend_from_slice(&EventKey::new(1, 1).to_bytes()[..key_bytes]);
+        log.push(ItemDescriptor::Tombstone.into());
+        log
+    }
+
+    #[test]
+    fn unversioned_logs_are_upgraded() -> MoraResult<()> {
+        for (key_bytes, id) in [(SORT_KEY_BYTES, 2), (TIMESTAMP_SORT_KEY_BYTES, 0)] {
+            let storage = temp_storage(&format!("unversioned-{key_bytes}"));
+            let container = "queue".to_string();
+            std::fs::write(
+                storage.container_path(&container),
+                unversioned_log(key_bytes),
+            )
+            .unwrap();
+
+            let mut storage = reopen(storage)?;
+            let items = storage.get_all_items(&container)?;
+            assert_eq!(items.len(), 1);
+            assert_eq!(items.get(&EventKey::new(2, id)), Some(&b"second".to_vec()));
+            storage.store_item(&container, &EventKey::new(3, 3), &b"third".to_vec())?;
+            assert_eq!(reopen(storage)?.get_all_items(&container)?.len(), 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_unversioned_logs_are_rejected() {
+        let storage = temp_storage("unversioned-unreadable");
+        let mut log = unversioned_log(SORT_KEY_BYTES);
+        log.truncate(log.len() - 3);
+        std::fs::write(storage.container_path("queue"), log).unwrap();
+        assert!(matches!(
+            reopen(storage),
+            Err(MoraError::StorageError(StorageError::InvalidWalFile(_)))
+        ));
+    }
+
+    fn snapshotted_storage(name: &str) -> MoraResult<(WalFileStorage, String)> {
+        let (storage, container) = segmented_storage(name)?;
+        Ok((storage.with_snapshot_tail_bytes(0), container))
+    }
+
+    #[test]
+    fn snapshots_leave_only_the_tail_to_replay() -> MoraResult<()> {
+        let (mut storage, container) = snapshotted_storage("snapshot-tail")?;
+        for id in 0..4 {
+            storage.store_item(&container, &EventKey::new(id, id), &b"first".to_vec())?;
+        }
+        assert_eq!(
+            storage.snapshot()?,
+            SnapshotStats {
+                containers: 1,
+                items: 4
+            }
+        );
+        storage.store_item(&container, &EventKey::new(9, 9), &b"tail!".to_vec())?;
+        storage.delete_item(&container, &EventKey::new(0, 0))?;
+
+        let mut storage = reopen(storage)?;
+        let items = storage.get_all_items(&container)?;
+        let mut keys = items.keys().map(|key| key.id).collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![1, 2, 3, 9]);
+        assert_eq!(items.get(&EventKey::new(9, 9)), Some(&b"tail!".to_vec()));
+        let container = &storage.containers[&container];
+        assert!(container.tail_bytes < container.stats.bytes);
+        assert_eq!(container.stats.records - container.stats.dead_records, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn unusable_snapshots_fall_back_to_a_full_replay() -> MoraResult<()> {
+        let (mut storage, container) = log_with_two_items("snapshot-unusable")?;
+        storage = storage.with_snapshot_tail_bytes(0);
+        storage.snapshot()?;
+        let snapshot_file = snapshot_path(&storage.wal_path, &container);
+        std::fs::write(&snapshot_file, b"not a snapshot").unwrap();
+        let mut storage = reopen(storage)?;
+        assert_eq!(storage.get_all_items(&container)?.len(), 2);
+        assert!(!Path::new(&snapshot_file).exists());
+
+        // A snapshot covering more than the log holds is stale.
+        storage.snapshot()?;
+        wal_file(&storage, &container)
+            .set_len(HEADER_BYTES as u64)
+            .unwrap();
+        let mut storage = reopen(storage)?;
+        assert!(storage.get_all_items(&container)?.is_empty());
+        assert!(!Path::new(&snapshot_file).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn segments_not_covered_by_the_snapshot_are_kept() -> MoraResult<()> {
+        let (mut storage, container) = snapshotted_storage("snapshot-reclaim")?;
+        for id in 0..4 {
+            storage.store_item(&container, &EventKey::new(id, id), &b"first".to_vec())?;
+        }
+        storage.snapshot()?;
+        storage.delete_items(&container, &[EventKey::new(2, 2), EventKey::new(3, 3)])?;
+        // The second segment is dead, but the snapshot only covers part of it.
+        assert_eq!(segments_on_disk(&storage, &container), vec![0, 1, 2]);
+
+        storage.snapshot()?;
+        assert_eq!(segments_on_disk(&storage, &container), vec![0, 2]);
+        let mut storage = reopen(storage)?;
+        assert_eq!(storage.get_all_items(&container)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_deletes_the_snapshot() -> MoraResult<()> {
+        let (mut storage, container) = log_with_two_items("snapshot-compaction")?;
+        storage = storage.with_snapshot_tail_bytes(0);
+        storage.snapshot()?;
+        storage.delete_item(&container, &EventKey::new(1, 1))?;
+        storage.compact_container(&container)?;
+        assert!(!Path::new(&snapshot_path(&storage.wal_path, &container)).exists());
+        assert_eq!(reopen(storage)?.get_all_items(&container)?.len(), 1);
+        Ok(())
+    }
 }